@@ -0,0 +1,639 @@
+use crate::dmg::apu::noise::NoiseChannel;
+use crate::dmg::apu::square::SquareChannel;
+use crate::dmg::apu::wave::WaveChannel;
+use crate::dmg::audio::rate_control::RateControl;
+use crate::dmg::audio::sink::{AudioSink, NullSink};
+use crate::dmg::save_state::{SaveState, StateReader, StateWriter};
+#[cfg(test)]
+use std::cell::RefCell;
+use std::collections::VecDeque;
+#[cfg(test)]
+use std::rc::Rc;
+
+// The DMG's master clock, 4194304 Hz
+const CPU_CLOCK: f64 = 4194304.0;
+
+// The frame sequencer is clocked at 512 Hz
+const FRAME_SEQUENCER_PERIOD: u16 = 8192;
+
+// The number of stereo frames we hold before handing them to the sink
+const SAMPLE_BUFFER_FRAMES: usize = 512;
+
+// How many of each channel's most recent samples we keep for visualizers
+const SCOPE_SAMPLES: usize = 1024;
+
+// How much of the DC offset the high pass filter keeps each cpu cycle.
+// See: https://gbdev.io/pandocs/Audio_details.html#obscure-behavior
+const HIGH_PASS_CHARGE: f64 = 0.999958;
+
+// The bits of 0xFF10 - 0xFF2F that always read back as 1, either because they're
+// unused or write only. See: https://gbdev.io/pandocs/Audio_Registers.html
+const READ_MASKS: [u8; 0x20] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, // NR10 - NR14
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF, // unused, NR21 - NR24
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF, // NR30 - NR34
+    0xFF, 0xFF, 0x00, 0x00, 0xBF, // unused, NR41 - NR44
+    0x00, 0x00, 0x70, // NR50 - NR52
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, // unused
+];
+
+/// The Audio Processing Unit
+pub struct Apu {
+    channel1: SquareChannel, // 0xFF10 - 0xFF14
+    channel2: SquareChannel, // 0xFF16 - 0xFF19
+    channel3: WaveChannel,   // 0xFF1A - 0xFF1E, 0xFF30 - 0xFF3F
+    channel4: NoiseChannel,  // 0xFF20 - 0xFF23
+
+    channel_control: u8,       //0xFF24
+    sound_output_terminal: u8, //0xFF25
+    sound_on_off: u8,          // 0xFF26
+
+    frame_sequencer_counter: u16,
+    frame_sequencer_step: u8,
+
+    // Resampling from the cpu clock to the sink's sample rate
+    sample_counter: f64,
+    cycles_per_sample: f64,
+    high_pass_charge: f32,
+    high_pass_capacitor: (f32, f32),
+    buffer: Vec<f32>,
+    rate_control: RateControl,
+    sink: Box<dyn AudioSink>,
+
+    // Debugging aids, indexed by channel - 1
+    muted: [bool; 4],
+    scope: [VecDeque<f32>; 4],
+    channel_buffers: [Vec<f32>; 4],
+    channel_sinks: [Option<Box<dyn AudioSink>>; 4],
+}
+
+impl Default for Apu {
+    fn default() -> Apu {
+        let sink = Box::new(NullSink::default());
+        let sample_rate = sink.sample_rate() as f64;
+        Apu {
+            channel1: SquareChannel::with_sweep(),
+            channel2: SquareChannel::default(),
+            channel3: WaveChannel::default(),
+            channel4: NoiseChannel::default(),
+            channel_control: 0,
+            sound_output_terminal: 0,
+            sound_on_off: 0,
+            frame_sequencer_counter: FRAME_SEQUENCER_PERIOD,
+            frame_sequencer_step: 0,
+            sample_counter: 0.0,
+            cycles_per_sample: CPU_CLOCK / sample_rate,
+            high_pass_charge: HIGH_PASS_CHARGE.powf(CPU_CLOCK / sample_rate) as f32,
+            high_pass_capacitor: (0.0, 0.0),
+            buffer: Vec::with_capacity(SAMPLE_BUFFER_FRAMES * 2),
+            rate_control: RateControl::default(),
+            sink,
+            muted: [false; 4],
+            scope: Default::default(),
+            channel_buffers: Default::default(),
+            channel_sinks: Default::default(),
+        }
+    }
+}
+
+impl Apu {
+    pub fn read(&self, address: u16) -> u8 {
+        let value = match address {
+            0xFF10..=0xFF14 => self.channel1.read((address - 0xFF10) as u8),
+            0xFF16..=0xFF19 => self.channel2.read((address - 0xFF15) as u8),
+            0xFF1A..=0xFF1E => self.channel3.read((address - 0xFF1A) as u8),
+            0xFF20..=0xFF23 => self.channel4.read((address - 0xFF1F) as u8),
+
+            0xFF24 => self.channel_control,
+            0xFF25 => self.sound_output_terminal,
+            0xFF26 => self.sound_on_off(),
+            0xFF15 | 0xFF1F | 0xFF27..=0xFF2F => 0x00,
+            0xFF30..=0xFF3F => return self.channel3.read_wave_ram(address),
+            _ => panic!("This should never happen"),
+        };
+        value | READ_MASKS[(address - 0xFF10) as usize]
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        // While powered off only NR52 and, on the DMG, the length counters can be written
+        if !self.powered() {
+            match address {
+                0xFF11 => self.channel1.write_length(value),
+                0xFF16 => self.channel2.write_length(value),
+                0xFF1B => self.channel3.write_length(value),
+                0xFF20 => self.channel4.write_length(value),
+                0xFF26 => self.write_sound_on_off(value),
+                0xFF30..=0xFF3F => self.channel3.write_wave_ram(address, value),
+                _ => {}
+            }
+            return;
+        }
+        match address {
+            0xFF10..=0xFF14 => self.channel1.write((address - 0xFF10) as u8, value),
+            0xFF16..=0xFF19 => self.channel2.write((address - 0xFF15) as u8, value),
+            0xFF1A..=0xFF1E => self.channel3.write((address - 0xFF1A) as u8, value),
+            0xFF20..=0xFF23 => self.channel4.write((address - 0xFF1F) as u8, value),
+
+            0xFF24 => self.channel_control = value,
+            0xFF25 => self.sound_output_terminal = value,
+            0xFF26 => self.write_sound_on_off(value),
+            0xFF15 | 0xFF1F | 0xFF27..=0xFF2F => {}
+            0xFF30..=0xFF3F => self.channel3.write_wave_ram(address, value),
+            _ => panic!("This should never happen"),
+        }
+    }
+
+    /// NR52: only the power bit is writable, the lower 4 bits report which channels are on
+    fn sound_on_off(&self) -> u8 {
+        let mut value = self.sound_on_off & 0x80;
+        let channels = [
+            self.channel1.enabled(),
+            self.channel2.enabled(),
+            self.channel3.enabled(),
+            self.channel4.enabled(),
+        ];
+        for (i, enabled) in channels.iter().enumerate() {
+            if *enabled {
+                value |= 1 << i;
+            }
+        }
+        value
+    }
+
+    fn write_sound_on_off(&mut self, value: u8) {
+        let powered = self.powered();
+        self.sound_on_off = value & 0x80;
+        if powered && !self.powered() {
+            self.channel1.power_off();
+            self.channel2.power_off();
+            self.channel3.power_off();
+            self.channel4.power_off();
+            self.channel_control = 0;
+            self.sound_output_terminal = 0;
+        } else if !powered && self.powered() {
+            self.frame_sequencer_counter = FRAME_SEQUENCER_PERIOD;
+            self.frame_sequencer_step = 0;
+        }
+    }
+
+    /// Replaces the destination for the samples we produce. Anything still
+    /// buffered for the old sink is sent to it first.
+    pub fn set_sink(&mut self, sink: Box<dyn AudioSink>) {
+        self.flush();
+        let sample_rate = sink.sample_rate() as f64;
+        self.cycles_per_sample = CPU_CLOCK / sample_rate;
+        self.high_pass_charge = HIGH_PASS_CHARGE.powf(CPU_CLOCK / sample_rate) as f32;
+        self.sink = sink;
+    }
+
+    /// How full the sink's buffer is, if it plays in real time
+    pub fn sink_fill_level(&self) -> Option<f32> {
+        self.sink.fill_level()
+    }
+
+    /// Writes a single channel's output to its own sink, at the same rate as the main sink.
+    /// Channels are numbered 1 to 4
+    pub fn set_channel_sink(&mut self, channel: usize, sink: Option<Box<dyn AudioSink>>) {
+        self.flush();
+        self.channel_sinks[channel_index(channel)] = sink;
+    }
+
+    /// Removes a channel from the mix, it still shows up in `channel_samples` and its own sink
+    pub fn set_channel_muted(&mut self, channel: usize, muted: bool) {
+        self.muted[channel_index(channel)] = muted;
+    }
+
+    pub fn channel_muted(&self, channel: usize) -> bool {
+        self.muted[channel_index(channel)]
+    }
+
+    /// Mutes every other channel. Soloing the only audible channel again unmutes everything
+    pub fn solo_channel(&mut self, channel: usize) {
+        let index = channel_index(channel);
+        let soloed = (0..4).all(|i| self.muted[i] == (i != index));
+        for i in 0..4 {
+            self.muted[i] = !soloed && i != index;
+        }
+    }
+
+    /// The most recent samples of a channel before mixing, oldest first, from -1.0 to 1.0
+    pub fn channel_samples(&self, channel: usize) -> Vec<f32> {
+        self.scope[channel_index(channel)].iter().copied().collect()
+    }
+
+    fn powered(&self) -> bool {
+        self.sound_on_off & 0x80 == 0x80
+    }
+
+    pub fn step(&mut self, cycles: u8) {
+        if self.powered() {
+            let cycles = cycles as u16;
+            self.channel1.step(cycles);
+            self.channel2.step(cycles);
+            self.channel3.step(cycles);
+            self.channel4.step(cycles);
+            self.step_frame_sequencer(cycles);
+        }
+
+        self.sample_counter += cycles as f64;
+        while self.sample_counter >= self.cycles_per_sample {
+            self.sample_counter -= self.cycles_per_sample;
+            let (left, right) = self.mix();
+            self.buffer.push(left);
+            self.buffer.push(right);
+        }
+
+        if self.buffer.len() >= SAMPLE_BUFFER_FRAMES * 2 {
+            self.flush();
+        }
+    }
+
+    /// Clocks the length counters, sweep and envelopes
+    /// See: https://gbdev.io/pandocs/Audio_details.html#div-apu
+    fn step_frame_sequencer(&mut self, cycles: u16) {
+        if cycles < self.frame_sequencer_counter {
+            self.frame_sequencer_counter -= cycles;
+            return;
+        }
+        self.frame_sequencer_counter += FRAME_SEQUENCER_PERIOD - cycles;
+
+        if self.frame_sequencer_step & 0b1 == 0 {
+            self.channel1.clock_length();
+            self.channel2.clock_length();
+            self.channel3.clock_length();
+            self.channel4.clock_length();
+        }
+        if self.frame_sequencer_step == 2 || self.frame_sequencer_step == 6 {
+            self.channel1.clock_sweep();
+        }
+        if self.frame_sequencer_step == 7 {
+            self.channel1.clock_envelope();
+            self.channel2.clock_envelope();
+            self.channel4.clock_envelope();
+        }
+        self.frame_sequencer_step = (self.frame_sequencer_step + 1) % 8;
+    }
+
+    /// Mixes the 4 channels into a left and right sample
+    fn mix(&mut self) -> (f32, f32) {
+        let outputs = if self.powered() {
+            [
+                dac(self.channel1.dac_enabled(), self.channel1.output()),
+                dac(self.channel2.dac_enabled(), self.channel2.output()),
+                dac(self.channel3.dac_enabled(), self.channel3.output()),
+                dac(self.channel4.dac_enabled(), self.channel4.output()),
+            ]
+        } else {
+            [0.0; 4]
+        };
+        self.record_channels(&outputs);
+        if !self.powered() {
+            return (0.0, 0.0);
+        }
+
+        // NR51: the upper nibble sends channels to the left, the lower nibble to the right
+        let (mut left, mut right) = (0.0, 0.0);
+        for (i, output) in outputs.iter().enumerate() {
+            if self.muted[i] {
+                continue;
+            }
+            if (self.sound_output_terminal >> (i + 4)) & 0b1 == 1 {
+                left += output;
+            }
+            if (self.sound_output_terminal >> i) & 0b1 == 1 {
+                right += output;
+            }
+        }
+
+        // NR50: master volume for each side from 0 to 7
+        let left_volume = ((self.channel_control >> 4) & 0b111) as f32 + 1.0;
+        let right_volume = (self.channel_control & 0b111) as f32 + 1.0;
+        let left = left / 4.0 * left_volume / 8.0;
+        let right = right / 4.0 * right_volume / 8.0;
+
+        (self.high_pass(left, true), self.high_pass(right, false))
+    }
+
+    /// Keeps the unmixed outputs for the oscilloscope and the per channel sinks
+    fn record_channels(&mut self, outputs: &[f32; 4]) {
+        for (i, output) in outputs.iter().enumerate() {
+            if self.scope[i].len() == SCOPE_SAMPLES {
+                self.scope[i].pop_front();
+            }
+            self.scope[i].push_back(*output);
+            if self.channel_sinks[i].is_some() {
+                self.channel_buffers[i].push(*output);
+                self.channel_buffers[i].push(*output);
+            }
+        }
+    }
+
+    fn high_pass(&mut self, input: f32, left: bool) -> f32 {
+        let capacitor = if left {
+            &mut self.high_pass_capacitor.0
+        } else {
+            &mut self.high_pass_capacitor.1
+        };
+        let output = input - *capacitor;
+        *capacitor = input - output * self.high_pass_charge;
+        output
+    }
+
+    /// Hands any buffered samples to the sink and adjusts our output rate
+    /// to keep real-time sinks from under or overflowing
+    pub fn flush(&mut self) {
+        if !self.buffer.is_empty() {
+            self.sink.push_samples(&self.buffer);
+            self.buffer.clear();
+        }
+        for (sink, buffer) in self
+            .channel_sinks
+            .iter_mut()
+            .zip(self.channel_buffers.iter_mut())
+        {
+            if let Some(sink) = sink {
+                sink.push_samples(buffer);
+            }
+            buffer.clear();
+        }
+        let rate = self
+            .rate_control
+            .adjusted_rate(self.sink.sample_rate(), self.sink.fill_level());
+        self.cycles_per_sample = CPU_CLOCK / rate;
+    }
+
+    #[cfg(test)]
+    pub fn sweep_register(&self) -> u8 {
+        self.channel1.read(0)
+    }
+
+    #[cfg(test)]
+    pub fn wave_pattern_ram(&self) -> [u8; 16] {
+        self.channel3.wave_pattern_ram()
+    }
+}
+
+fn channel_index(channel: usize) -> usize {
+    match channel {
+        1..=4 => channel - 1,
+        _ => panic!("There is no sound channel {}", channel),
+    }
+}
+
+/// Converts a channel's digital output from 0 to 15 into an analog value from -1.0 to 1.0.
+/// A channel whose DAC is off outputs nothing
+fn dac(enabled: bool, output: u8) -> f32 {
+    if enabled {
+        1.0 - (output as f32 / 7.5)
+    } else {
+        0.0
+    }
+}
+
+#[cfg(test)]
+struct CaptureSink {
+    samples: Rc<RefCell<Vec<f32>>>,
+}
+
+#[cfg(test)]
+impl AudioSink for CaptureSink {
+    fn sample_rate(&self) -> u32 {
+        32768
+    }
+
+    fn push_samples(&mut self, samples: &[f32]) {
+        self.samples.borrow_mut().extend_from_slice(samples);
+    }
+}
+
+// Only the emulated hardware is saved, the sinks and debugging aids are left alone
+impl SaveState for Apu {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.channel1.save_state(state);
+        self.channel2.save_state(state);
+        self.channel3.save_state(state);
+        self.channel4.save_state(state);
+        state.u8(self.channel_control);
+        state.u8(self.sound_output_terminal);
+        state.u8(self.sound_on_off);
+        state.u16(self.frame_sequencer_counter);
+        state.u8(self.frame_sequencer_step);
+        state.f64(self.sample_counter);
+        state.f32(self.high_pass_capacitor.0);
+        state.f32(self.high_pass_capacitor.1);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.channel1.load_state(state)?;
+        self.channel2.load_state(state)?;
+        self.channel3.load_state(state)?;
+        self.channel4.load_state(state)?;
+        self.channel_control = state.u8()?;
+        self.sound_output_terminal = state.u8()?;
+        self.sound_on_off = state.u8()?;
+        self.frame_sequencer_counter = state.u16()?;
+        self.frame_sequencer_step = state.u8()?;
+        self.sample_counter = state.f64()?;
+        self.high_pass_capacitor = (state.f32()?, state.f32()?);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+fn capture_apu() -> (Apu, Rc<RefCell<Vec<f32>>>) {
+    let samples = Rc::new(RefCell::new(Vec::new()));
+    let mut apu = Apu::default();
+    apu.set_sink(Box::new(CaptureSink {
+        samples: samples.clone(),
+    }));
+    (apu, samples)
+}
+
+#[test]
+fn produces_samples_at_sink_rate() {
+    let (mut apu, samples) = capture_apu();
+    // 32768 Hz is one sample every 128 cpu cycles
+    for _ in 0..(128 * 1024 / 16) {
+        apu.step(16);
+    }
+    apu.flush();
+    assert_eq!(samples.borrow().len(), 1024 * 2);
+}
+
+#[test]
+fn powered_off_apu_is_silent() {
+    let (mut apu, samples) = capture_apu();
+    apu.write(0xFF12, 0xF0);
+    apu.write(0xFF14, 0x80);
+    apu.write(0xFF25, 0xFF);
+    for _ in 0..1024 {
+        apu.step(16);
+    }
+    apu.flush();
+    assert!(samples.borrow().iter().all(|s| *s == 0.0));
+}
+
+#[test]
+fn square_wave_reaches_the_sink() {
+    let (mut apu, samples) = capture_apu();
+    play_square_wave(&mut apu);
+    let samples = samples.borrow();
+    assert!(samples.iter().any(|s| *s > 0.1));
+    assert!(samples.iter().any(|s| *s < -0.1));
+    for frame in samples.chunks(2) {
+        assert_eq!(frame[0], frame[1], "Both sides are panned the same");
+    }
+}
+
+#[test]
+fn length_counter_disables_channel() {
+    let mut apu = Apu::default();
+    apu.write(0xFF26, 0x80);
+    apu.write(0xFF12, 0xF0);
+    apu.write(0xFF11, 0x3F); // One length clock left
+    apu.write(0xFF14, 0xC0); // Trigger with length enabled
+    assert!(apu.channel1.enabled());
+    for _ in 0..(FRAME_SEQUENCER_PERIOD / 16) {
+        apu.step(16);
+    }
+    assert!(!apu.channel1.enabled());
+}
+
+#[test]
+fn unused_bits_read_as_one() {
+    let mut apu = Apu::default();
+    apu.write(0xFF26, 0x80);
+    assert_eq!(apu.read(0xFF10), 0x80);
+    assert_eq!(
+        apu.read(0xFF13),
+        0xFF,
+        "The frequency registers are write only"
+    );
+    apu.write(0xFF11, 0x80);
+    assert_eq!(apu.read(0xFF11), 0xBF, "Only the duty can be read back");
+    apu.write(0xFF12, 0xF3);
+    assert_eq!(apu.read(0xFF12), 0xF3);
+    assert_eq!(apu.read(0xFF15), 0xFF);
+    assert_eq!(apu.read(0xFF1A), 0x7F);
+    assert_eq!(apu.read(0xFF1C), 0x9F);
+    assert_eq!(apu.read(0xFF20), 0xFF);
+    assert_eq!(apu.read(0xFF23), 0xBF);
+    assert_eq!(apu.read(0xFF27), 0xFF);
+}
+
+#[test]
+fn sound_on_off_status_is_read_only() {
+    let mut apu = Apu::default();
+    assert_eq!(apu.read(0xFF26), 0x70);
+    apu.write(0xFF26, 0xFF);
+    assert_eq!(apu.read(0xFF26), 0xF0, "No channels are playing");
+    apu.write(0xFF12, 0xF0);
+    apu.write(0xFF14, 0x80);
+    apu.write(0xFF21, 0xF0);
+    apu.write(0xFF23, 0x80);
+    assert_eq!(apu.read(0xFF26), 0xF9);
+}
+
+#[test]
+fn power_off_clears_registers() {
+    let mut apu = Apu::default();
+    apu.write(0xFF26, 0x80);
+    apu.write(0xFF30, 0x12);
+    apu.write(0xFF10, 0x7F);
+    apu.write(0xFF12, 0xF0);
+    apu.write(0xFF14, 0x80);
+    apu.write(0xFF24, 0x77);
+    apu.write(0xFF25, 0xFF);
+    apu.write(0xFF26, 0x00);
+    assert_eq!(apu.read(0xFF10), 0x80);
+    assert_eq!(apu.read(0xFF12), 0x00);
+    assert_eq!(apu.read(0xFF24), 0x00);
+    assert_eq!(apu.read(0xFF25), 0x00);
+    assert_eq!(apu.read(0xFF26), 0x70);
+    assert_eq!(apu.read(0xFF30), 0x12, "The wave ram isn't cleared");
+
+    apu.write(0xFF12, 0xF0);
+    apu.write(0xFF24, 0x77);
+    assert_eq!(
+        apu.read(0xFF12),
+        0x00,
+        "Writes are ignored while powered off"
+    );
+    assert_eq!(apu.read(0xFF24), 0x00);
+
+    apu.write(0xFF26, 0x80);
+    apu.write(0xFF12, 0xF0);
+    assert_eq!(apu.read(0xFF12), 0xF0);
+}
+
+#[test]
+fn length_is_writable_while_powered_off() {
+    let mut apu = Apu::default();
+    apu.write(0xFF11, 0x3F); // One length clock left
+    apu.write(0xFF26, 0x80);
+    apu.write(0xFF12, 0xF0);
+    apu.write(0xFF14, 0xC0);
+    assert!(apu.channel1.enabled());
+    for _ in 0..(FRAME_SEQUENCER_PERIOD / 16) {
+        apu.step(16);
+    }
+    assert!(!apu.channel1.enabled());
+}
+
+#[cfg(test)]
+fn play_square_wave(apu: &mut Apu) {
+    apu.write(0xFF26, 0x80);
+    apu.write(0xFF24, 0x77);
+    apu.write(0xFF25, 0x11); // Channel 1 on both sides
+    apu.write(0xFF11, 0x80); // 50% duty
+    apu.write(0xFF12, 0xF0);
+    apu.write(0xFF13, 0x00);
+    apu.write(0xFF14, 0x87);
+    for _ in 0..4096 {
+        apu.step(16);
+    }
+    apu.flush();
+}
+
+#[test]
+fn muted_channel_only_reaches_the_scope() {
+    let (mut apu, samples) = capture_apu();
+    apu.set_channel_muted(1, true);
+    play_square_wave(&mut apu);
+    assert!(samples.borrow().iter().all(|s| *s == 0.0));
+    let scope = apu.channel_samples(1);
+    assert_eq!(scope.len(), 512, "We've played 512 samples worth of cycles");
+    assert!(scope.iter().any(|s| *s > 0.5));
+    assert!(scope.iter().any(|s| *s < -0.5));
+    assert!(apu.channel_samples(2).iter().all(|s| *s == 0.0));
+}
+
+#[test]
+fn solo_channel_toggles() {
+    let mut apu = Apu::default();
+    apu.solo_channel(3);
+    assert!(apu.channel_muted(1));
+    assert!(apu.channel_muted(2));
+    assert!(!apu.channel_muted(3));
+    assert!(apu.channel_muted(4));
+    apu.solo_channel(3);
+    assert!((1..=4).all(|ch| !apu.channel_muted(ch)));
+}
+
+#[test]
+fn channel_sink_gets_the_unmixed_channel() {
+    let (mut apu, samples) = capture_apu();
+    let channel_samples = Rc::new(RefCell::new(Vec::new()));
+    apu.set_channel_sink(
+        1,
+        Some(Box::new(CaptureSink {
+            samples: channel_samples.clone(),
+        })),
+    );
+    apu.set_channel_muted(1, true);
+    play_square_wave(&mut apu);
+    let channel_samples = channel_samples.borrow();
+    assert_eq!(channel_samples.len(), samples.borrow().len());
+    assert!(channel_samples.iter().any(|s| *s > 0.5));
+    assert!(channel_samples.iter().any(|s| *s < -0.5));
+}
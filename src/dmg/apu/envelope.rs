@@ -0,0 +1,91 @@
+use crate::dmg::save_state::{SaveState, StateReader, StateWriter};
+
+/// The volume envelope shared by the square and noise channels (NRx2).
+/// It is clocked at 64Hz by the frame sequencer.
+/// See: https://gbdev.io/pandocs/Audio_details.html#envelope
+#[derive(Default)]
+pub struct Envelope {
+    initial_volume: u8,
+    increase: bool,
+    period: u8,
+    volume: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    pub fn write(&mut self, value: u8) {
+        self.initial_volume = value >> 4;
+        self.increase = (value >> 3) & 0b1 == 1;
+        self.period = value & 0b111;
+    }
+
+    /// The channel's DAC is powered whenever the upper 5 bits of NRx2 are set
+    pub fn dac_enabled(&self) -> bool {
+        self.initial_volume != 0 || self.increase
+    }
+
+    pub fn trigger(&mut self) {
+        self.volume = self.initial_volume;
+        self.timer = self.period;
+    }
+
+    pub fn clock(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.increase && self.volume < 15 {
+                self.volume += 1;
+            } else if !self.increase && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+
+    pub fn volume(&self) -> u8 {
+        self.volume
+    }
+}
+
+impl SaveState for Envelope {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.initial_volume);
+        state.bool(self.increase);
+        state.u8(self.period);
+        state.u8(self.volume);
+        state.u8(self.timer);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.initial_volume = state.u8()?;
+        self.increase = state.bool()?;
+        self.period = state.u8()?;
+        self.volume = state.u8()?;
+        self.timer = state.u8()?;
+        Ok(())
+    }
+}
+
+#[test]
+fn envelope_decreases_to_zero() {
+    let mut env = Envelope::default();
+    env.write(0x21); // volume 2, decreasing, period 1
+    env.trigger();
+    assert_eq!(env.volume(), 2);
+    env.clock();
+    assert_eq!(env.volume(), 1);
+    env.clock();
+    env.clock();
+    assert_eq!(env.volume(), 0, "Volume should stop at zero");
+}
+
+#[test]
+fn envelope_dac() {
+    let mut env = Envelope::default();
+    env.write(0x08);
+    assert!(env.dac_enabled(), "An increasing envelope powers the DAC");
+    env.write(0x07);
+    assert!(!env.dac_enabled());
+}
@@ -0,0 +1,76 @@
+use crate::dmg::save_state::{SaveState, StateReader, StateWriter};
+
+/// Silences a channel after a set amount of time, clocked at 256Hz
+/// by the frame sequencer when enabled by bit 6 of NRx4.
+/// See: https://gbdev.io/pandocs/Audio_Registers.html#length-timer
+pub struct LengthCounter {
+    max: u16,
+    counter: u16,
+    enabled: bool,
+}
+
+impl LengthCounter {
+    /// Channels 1, 2 and 4 count down from 64, channel 3 from 256
+    pub fn new(max: u16) -> LengthCounter {
+        LengthCounter {
+            max,
+            counter: 0,
+            enabled: false,
+        }
+    }
+
+    pub fn load(&mut self, value: u8) {
+        self.counter = self.max - (value as u16 & (self.max - 1));
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    pub fn trigger(&mut self) {
+        if self.counter == 0 {
+            self.counter = self.max;
+        }
+    }
+
+    /// Returns true when the counter has just expired and the channel should be disabled
+    pub fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            return self.counter == 0;
+        }
+        false
+    }
+}
+
+impl SaveState for LengthCounter {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u16(self.counter);
+        state.bool(self.enabled);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.counter = state.u16()?;
+        self.enabled = state.bool()?;
+        Ok(())
+    }
+}
+
+#[test]
+fn length_expires() {
+    let mut length = LengthCounter::new(64);
+    length.load(62);
+    length.set_enabled(true);
+    assert!(!length.clock());
+    assert!(length.clock(), "The counter should expire after two clocks");
+    assert!(!length.clock(), "An expired counter stays expired");
+}
+
+#[test]
+fn disabled_length_does_not_count() {
+    let mut length = LengthCounter::new(256);
+    length.load(255);
+    assert!(!length.clock());
+    length.set_enabled(true);
+    assert!(length.clock());
+}
@@ -0,0 +1,7 @@
+#[allow(clippy::module_inception)]
+pub mod apu;
+pub mod envelope;
+pub mod length_counter;
+pub mod noise;
+pub mod square;
+pub mod wave;
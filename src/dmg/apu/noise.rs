@@ -0,0 +1,188 @@
+use crate::dmg::apu::envelope::Envelope;
+use crate::dmg::apu::length_counter::LengthCounter;
+use crate::dmg::save_state::{SaveState, StateReader, StateWriter};
+
+// The base divisors selected by the lower 3 bits of NR43
+const DIVISORS: [u16; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+/// Sound channel 4, pseudo-random noise from a linear feedback shift register
+/// See: https://gbdev.io/pandocs/Audio_details.html#noise-channel-ch4
+pub struct NoiseChannel {
+    length: LengthCounter,
+    envelope: Envelope,
+    clock_shift: u8,
+    width_mode: bool,
+    divisor_code: u8,
+    frequency_timer: u32,
+    lfsr: u16,
+    enabled: bool,
+
+    // The last values written to NR41 - NR44
+    registers: [u8; 4],
+}
+
+impl Default for NoiseChannel {
+    fn default() -> NoiseChannel {
+        NoiseChannel {
+            length: LengthCounter::new(64),
+            envelope: Envelope::default(),
+            clock_shift: 0,
+            width_mode: false,
+            divisor_code: 0,
+            frequency_timer: 8,
+            lfsr: 0x7FFF,
+            enabled: false,
+            registers: [0; 4],
+        }
+    }
+}
+
+impl NoiseChannel {
+    /// Reads NR41 - NR44 where `register` is the 1 to 4 offset
+    pub fn read(&self, register: u8) -> u8 {
+        self.registers[register as usize - 1]
+    }
+
+    /// Writes NR41 - NR44 where `register` is the 1 to 4 offset
+    pub fn write(&mut self, register: u8, value: u8) {
+        self.registers[register as usize - 1] = value;
+        match register {
+            1 => self.length.load(value),
+            2 => {
+                self.envelope.write(value);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => {
+                self.clock_shift = value >> 4;
+                self.width_mode = (value >> 3) & 0b1 == 1;
+                self.divisor_code = value & 0b111;
+            }
+            4 => {
+                self.length.set_enabled(value & 0x40 == 0x40);
+                if value & 0x80 == 0x80 {
+                    self.trigger();
+                }
+            }
+            _ => panic!("This should never happen"),
+        }
+    }
+
+    /// On the DMG the length counter keeps running while the APU is off
+    /// and can still be loaded by writing to NR41
+    pub fn write_length(&mut self, value: u8) {
+        self.length.load(value);
+    }
+
+    /// Clears every register, except for the length counter which survives on the DMG
+    pub fn power_off(&mut self) {
+        let mut length = std::mem::replace(&mut self.length, LengthCounter::new(64));
+        length.set_enabled(false);
+        *self = NoiseChannel {
+            length,
+            ..NoiseChannel::default()
+        };
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.length.trigger();
+        self.envelope.trigger();
+        self.frequency_timer = self.period();
+        self.lfsr = 0x7FFF;
+    }
+
+    fn period(&self) -> u32 {
+        (DIVISORS[self.divisor_code as usize] as u32) << self.clock_shift
+    }
+
+    /// Advances the shift register by the given number of cpu cycles
+    pub fn step(&mut self, cycles: u16) {
+        let mut cycles = cycles as u32;
+        while cycles >= self.frequency_timer {
+            cycles -= self.frequency_timer;
+            self.frequency_timer = self.period();
+            let xor = (self.lfsr & 0b1) ^ ((self.lfsr >> 1) & 0b1);
+            self.lfsr = (self.lfsr >> 1) | (xor << 14);
+            if self.width_mode {
+                // 7 bit mode also feeds the result back into bit 6
+                self.lfsr = (self.lfsr & !(1 << 6)) | (xor << 6);
+            }
+        }
+        self.frequency_timer -= cycles;
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn dac_enabled(&self) -> bool {
+        self.envelope.dac_enabled()
+    }
+
+    /// The current digital output of the channel, from 0 to 15
+    pub fn output(&self) -> u8 {
+        if self.enabled && self.lfsr & 0b1 == 0 {
+            self.envelope.volume()
+        } else {
+            0
+        }
+    }
+}
+
+impl SaveState for NoiseChannel {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.length.save_state(state);
+        self.envelope.save_state(state);
+        state.u8(self.clock_shift);
+        state.bool(self.width_mode);
+        state.u8(self.divisor_code);
+        state.u32(self.frequency_timer);
+        state.u16(self.lfsr);
+        state.bool(self.enabled);
+        state.bytes(&self.registers);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.length.load_state(state)?;
+        self.envelope.load_state(state)?;
+        self.clock_shift = state.u8()?;
+        self.width_mode = state.bool()?;
+        self.divisor_code = state.u8()?;
+        self.frequency_timer = state.u32()?;
+        self.lfsr = state.u16()?;
+        self.enabled = state.bool()?;
+        state.bytes(&mut self.registers)
+    }
+}
+
+#[test]
+fn lfsr_shifts() {
+    let mut ch = NoiseChannel::default();
+    ch.write(2, 0xF0);
+    ch.write(3, 0x00); // divisor 8, no shift
+    ch.write(4, 0x80);
+    assert_eq!(
+        ch.output(),
+        0,
+        "Bit 0 of the reset LFSR is 1 so the output is low"
+    );
+    ch.step(8 * 15);
+    assert_eq!(
+        ch.lfsr & 0b1,
+        0,
+        "15 shifts of 0x7FFF feeds a zero into bit 0"
+    );
+    assert_eq!(ch.output(), 15);
+}
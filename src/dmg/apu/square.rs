@@ -0,0 +1,365 @@
+use crate::dmg::apu::envelope::Envelope;
+use crate::dmg::apu::length_counter::LengthCounter;
+use crate::dmg::save_state::{SaveState, StateReader, StateWriter};
+
+// Each row is one of the 4 selectable waveforms, 8 steps long
+// See: https://gbdev.io/pandocs/Audio_Registers.html#ff11--nr11-channel-1-length-timer--duty-cycle
+const DUTY_PATTERNS: [[u8; 8]; 4] = [
+    [0, 0, 0, 0, 0, 0, 0, 1], // 12.5%
+    [1, 0, 0, 0, 0, 0, 0, 1], // 25%
+    [1, 0, 0, 0, 0, 1, 1, 1], // 50%
+    [0, 1, 1, 1, 1, 1, 1, 0], // 75%
+];
+
+/// Sound channels 1 and 2, a square wave with a volume envelope.
+/// Only channel 1 has a frequency sweep (NR10).
+pub struct SquareChannel {
+    sweep: Option<Sweep>,
+    length: LengthCounter,
+    envelope: Envelope,
+    duty: u8,
+    frequency: u16,
+    frequency_timer: u16,
+    duty_position: u8,
+    enabled: bool,
+
+    // The last values written to NRx0 - NRx4
+    registers: [u8; 5],
+}
+
+impl Default for SquareChannel {
+    /// Channel 2
+    fn default() -> SquareChannel {
+        SquareChannel {
+            sweep: None,
+            length: LengthCounter::new(64),
+            envelope: Envelope::default(),
+            duty: 0,
+            frequency: 0,
+            frequency_timer: 8192,
+            duty_position: 0,
+            enabled: false,
+            registers: [0; 5],
+        }
+    }
+}
+
+impl SquareChannel {
+    /// Channel 1
+    pub fn with_sweep() -> SquareChannel {
+        SquareChannel {
+            sweep: Some(Sweep::default()),
+            ..SquareChannel::default()
+        }
+    }
+
+    /// Reads NRx0 - NRx4 where `register` is the x0 to x4 offset
+    pub fn read(&self, register: u8) -> u8 {
+        self.registers[register as usize]
+    }
+
+    /// Writes NRx0 - NRx4 where `register` is the x0 to x4 offset
+    pub fn write(&mut self, register: u8, value: u8) {
+        self.registers[register as usize] = value;
+        match register {
+            0 => {
+                if let Some(sweep) = &mut self.sweep {
+                    sweep.write(value);
+                }
+            }
+            1 => {
+                self.duty = value >> 6;
+                self.length.load(value);
+            }
+            2 => {
+                self.envelope.write(value);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => self.frequency = (self.frequency & 0x700) | value as u16,
+            4 => {
+                self.frequency = (self.frequency & 0xFF) | ((value as u16 & 0b111) << 8);
+                self.length.set_enabled(value & 0x40 == 0x40);
+                if value & 0x80 == 0x80 {
+                    self.trigger();
+                }
+            }
+            _ => panic!("This should never happen"),
+        }
+    }
+
+    /// On the DMG the length counter keeps running while the APU is off
+    /// and can still be loaded by writing to NRx1
+    pub fn write_length(&mut self, value: u8) {
+        self.length.load(value);
+    }
+
+    /// Clears every register, except for the length counter which survives on the DMG
+    pub fn power_off(&mut self) {
+        let mut length = std::mem::replace(&mut self.length, LengthCounter::new(64));
+        length.set_enabled(false);
+        let sweep = self.sweep.is_some();
+        *self = SquareChannel {
+            sweep: if sweep { Some(Sweep::default()) } else { None },
+            length,
+            ..SquareChannel::default()
+        };
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.length.trigger();
+        self.envelope.trigger();
+        self.frequency_timer = self.period();
+        if let Some(sweep) = &mut self.sweep {
+            if sweep.trigger(self.frequency) {
+                self.enabled = false;
+            }
+        }
+    }
+
+    fn period(&self) -> u16 {
+        (2048 - self.frequency) * 4
+    }
+
+    /// Advances the waveform by the given number of cpu cycles
+    pub fn step(&mut self, cycles: u16) {
+        let mut cycles = cycles;
+        while cycles >= self.frequency_timer {
+            cycles -= self.frequency_timer;
+            self.frequency_timer = self.period();
+            self.duty_position = (self.duty_position + 1) % 8;
+        }
+        self.frequency_timer -= cycles;
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub fn clock_sweep(&mut self) {
+        if let Some(sweep) = &mut self.sweep {
+            match sweep.clock() {
+                SweepResult::Overflow => self.enabled = false,
+                SweepResult::NewFrequency(frequency) => {
+                    self.frequency = frequency;
+                    self.registers[3] = frequency as u8;
+                    self.registers[4] = (self.registers[4] & !0b111) | (frequency >> 8) as u8;
+                }
+                SweepResult::Unchanged => {}
+            }
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn dac_enabled(&self) -> bool {
+        self.envelope.dac_enabled()
+    }
+
+    /// The current digital output of the channel, from 0 to 15
+    pub fn output(&self) -> u8 {
+        if self.enabled {
+            DUTY_PATTERNS[self.duty as usize][self.duty_position as usize] * self.envelope.volume()
+        } else {
+            0
+        }
+    }
+}
+
+enum SweepResult {
+    Unchanged,
+    NewFrequency(u16),
+    Overflow,
+}
+
+/// The frequency sweep of channel 1, clocked at 128Hz by the frame sequencer
+/// See: https://gbdev.io/pandocs/Audio_Registers.html#ff10--nr10-channel-1-sweep
+#[derive(Default)]
+struct Sweep {
+    period: u8,
+    negate: bool,
+    shift: u8,
+    timer: u8,
+    enabled: bool,
+    shadow_frequency: u16,
+}
+
+impl Sweep {
+    fn write(&mut self, value: u8) {
+        self.period = (value >> 4) & 0b111;
+        self.negate = (value >> 3) & 0b1 == 1;
+        self.shift = value & 0b111;
+    }
+
+    fn reload_timer(&mut self) {
+        // A period of 0 is treated as 8
+        self.timer = if self.period == 0 { 8 } else { self.period };
+    }
+
+    /// Returns true if the channel should be disabled because of an overflow
+    fn trigger(&mut self, frequency: u16) -> bool {
+        self.shadow_frequency = frequency;
+        self.reload_timer();
+        self.enabled = self.period != 0 || self.shift != 0;
+        self.shift != 0 && self.next_frequency() > 2047
+    }
+
+    fn next_frequency(&self) -> u16 {
+        let delta = self.shadow_frequency >> self.shift;
+        if self.negate {
+            self.shadow_frequency.wrapping_sub(delta)
+        } else {
+            self.shadow_frequency + delta
+        }
+    }
+
+    fn clock(&mut self) -> SweepResult {
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer > 0 {
+            return SweepResult::Unchanged;
+        }
+        self.reload_timer();
+        if !self.enabled || self.period == 0 {
+            return SweepResult::Unchanged;
+        }
+        let frequency = self.next_frequency();
+        if frequency > 2047 {
+            return SweepResult::Overflow;
+        }
+        if self.shift == 0 {
+            return SweepResult::Unchanged;
+        }
+        self.shadow_frequency = frequency;
+        // The new frequency is checked for overflow a second time
+        if self.next_frequency() > 2047 {
+            return SweepResult::Overflow;
+        }
+        SweepResult::NewFrequency(frequency)
+    }
+}
+
+impl SaveState for SquareChannel {
+    fn save_state(&self, state: &mut StateWriter) {
+        if let Some(sweep) = &self.sweep {
+            sweep.save_state(state);
+        }
+        self.length.save_state(state);
+        self.envelope.save_state(state);
+        state.u8(self.duty);
+        state.u16(self.frequency);
+        state.u16(self.frequency_timer);
+        state.u8(self.duty_position);
+        state.bool(self.enabled);
+        state.bytes(&self.registers);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        if let Some(sweep) = &mut self.sweep {
+            sweep.load_state(state)?;
+        }
+        self.length.load_state(state)?;
+        self.envelope.load_state(state)?;
+        self.duty = state.u8()?;
+        self.frequency = state.u16()?;
+        self.frequency_timer = state.u16()?;
+        self.duty_position = state.u8()?;
+        self.enabled = state.bool()?;
+        state.bytes(&mut self.registers)
+    }
+}
+
+impl SaveState for Sweep {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.period);
+        state.bool(self.negate);
+        state.u8(self.shift);
+        state.u8(self.timer);
+        state.bool(self.enabled);
+        state.u16(self.shadow_frequency);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.period = state.u8()?;
+        self.negate = state.bool()?;
+        self.shift = state.u8()?;
+        self.timer = state.u8()?;
+        self.enabled = state.bool()?;
+        self.shadow_frequency = state.u16()?;
+        Ok(())
+    }
+}
+
+#[test]
+fn trigger_without_dac_stays_disabled() {
+    let mut ch = SquareChannel::default();
+    ch.write(4, 0x80);
+    assert!(!ch.enabled());
+    ch.write(2, 0xF0);
+    ch.write(4, 0x80);
+    assert!(ch.enabled());
+}
+
+#[test]
+fn duty_advances_with_frequency_timer() {
+    let mut ch = SquareChannel::default();
+    ch.write(1, 0b1100_0000); // 75% duty
+    ch.write(2, 0xF0);
+    ch.write(3, 0xFF);
+    ch.write(4, 0x87); // frequency 2047, a period of 4 cycles
+    assert_eq!(ch.output(), 0, "The first step of the 75% duty is low");
+    ch.step(4);
+    assert_eq!(ch.output(), 15);
+}
+
+#[test]
+fn sweep_overflow_disables_channel() {
+    let mut ch = SquareChannel::with_sweep();
+    ch.write(0, 0x11); // period 1, increasing, shift 1
+    ch.write(2, 0xF0);
+    ch.write(3, 0xFF);
+    ch.write(4, 0x85); // frequency 0x5FF + 0x2FF overflows 2047
+    assert!(!ch.enabled(), "Overflow is checked as soon as we trigger");
+}
+
+#[test]
+fn sweep_updates_frequency() {
+    let mut ch = SquareChannel::with_sweep();
+    ch.write(0, 0x11);
+    ch.write(2, 0xF0);
+    ch.write(3, 0x00);
+    ch.write(4, 0x81); // frequency 0x100
+    ch.clock_sweep();
+    assert!(ch.enabled());
+    assert_eq!(ch.read(3), 0x80);
+    assert_eq!(ch.read(4) & 0b111, 0x1, "0x100 + 0x80 = 0x180");
+}
+
+#[test]
+fn power_off_keeps_length() {
+    let mut ch = SquareChannel::with_sweep();
+    ch.write(0, 0x11);
+    ch.write(1, 0xBF); // 50% duty, one length clock left
+    ch.write(2, 0xF0);
+    ch.write(4, 0xC0);
+    ch.power_off();
+    assert_eq!(ch.read(0), 0);
+    assert_eq!(ch.read(1), 0);
+    assert!(!ch.enabled());
+    ch.write(2, 0xF0);
+    ch.write(4, 0xC0);
+    ch.clock_length();
+    assert!(
+        !ch.enabled(),
+        "The length counter should have survived the power cycle"
+    );
+}
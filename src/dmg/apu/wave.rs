@@ -0,0 +1,302 @@
+use crate::dmg::apu::length_counter::LengthCounter;
+use crate::dmg::save_state::{SaveState, StateReader, StateWriter};
+
+/// Sound channel 3, plays back the 32 4-bit samples stored in the wave pattern ram
+pub struct WaveChannel {
+    length: LengthCounter,
+    dac_enabled: bool,
+    volume_code: u8,
+    frequency: u16,
+    frequency_timer: u16,
+    position: u8,
+    sample_buffer: u8,
+    cycles_since_fetch: u16,
+    enabled: bool,
+    wave_pattern_ram: WavePatternRam, // 0xFF30 - 0xFF3F // arbitrary sound data storage
+
+    // The last values written to NR30 - NR34
+    registers: [u8; 5],
+}
+
+impl Default for WaveChannel {
+    fn default() -> WaveChannel {
+        WaveChannel {
+            length: LengthCounter::new(256),
+            dac_enabled: false,
+            volume_code: 0,
+            frequency: 0,
+            frequency_timer: 4096,
+            position: 0,
+            sample_buffer: 0,
+            cycles_since_fetch: u16::MAX,
+            enabled: false,
+            wave_pattern_ram: WavePatternRam { ram: [0; 16] },
+            registers: [0; 5],
+        }
+    }
+}
+
+impl WaveChannel {
+    /// Reads NR30 - NR34 where `register` is the 0 to 4 offset
+    pub fn read(&self, register: u8) -> u8 {
+        self.registers[register as usize]
+    }
+
+    /// Writes NR30 - NR34 where `register` is the 0 to 4 offset
+    pub fn write(&mut self, register: u8, value: u8) {
+        self.registers[register as usize] = value;
+        match register {
+            0 => {
+                self.dac_enabled = value & 0x80 == 0x80;
+                if !self.dac_enabled {
+                    self.enabled = false;
+                }
+            }
+            1 => self.length.load(value),
+            2 => self.volume_code = (value >> 5) & 0b11,
+            3 => self.frequency = (self.frequency & 0x700) | value as u16,
+            4 => {
+                self.frequency = (self.frequency & 0xFF) | ((value as u16 & 0b111) << 8);
+                self.length.set_enabled(value & 0x40 == 0x40);
+                if value & 0x80 == 0x80 {
+                    self.trigger();
+                }
+            }
+            _ => panic!("This should never happen"),
+        }
+    }
+
+    /// While the channel is playing the cpu can only see the byte the channel
+    /// is reading, and only if the channel fetched it within the last couple of
+    /// cycles. Otherwise we read 0xFF. See: https://gbdev.io/pandocs/Audio_Registers.html#ff30ff3f--wave-pattern-ram
+    pub fn read_wave_ram(&self, address: u16) -> u8 {
+        if !self.enabled {
+            self.wave_pattern_ram.read(address)
+        } else if self.cycles_since_fetch < 2 {
+            self.wave_pattern_ram.ram[self.position as usize / 2]
+        } else {
+            0xFF
+        }
+    }
+
+    /// Writes follow the same rules as reads while the channel is playing
+    pub fn write_wave_ram(&mut self, address: u16, value: u8) {
+        if !self.enabled {
+            self.wave_pattern_ram.write(address, value)
+        } else if self.cycles_since_fetch < 2 {
+            self.wave_pattern_ram.ram[self.position as usize / 2] = value;
+        }
+    }
+
+    /// On the DMG the length counter keeps running while the APU is off
+    /// and can still be loaded by writing to NR31
+    pub fn write_length(&mut self, value: u8) {
+        self.length.load(value);
+    }
+
+    /// Clears every register, the wave pattern ram and the length counter are
+    /// untouched on the DMG
+    pub fn power_off(&mut self) {
+        let mut length = std::mem::replace(&mut self.length, LengthCounter::new(256));
+        length.set_enabled(false);
+        let ram = self.wave_pattern_ram.ram;
+        *self = WaveChannel {
+            length,
+            wave_pattern_ram: WavePatternRam { ram },
+            ..WaveChannel::default()
+        };
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        self.length.trigger();
+        self.frequency_timer = self.period();
+        self.position = 0;
+        self.cycles_since_fetch = u16::MAX;
+    }
+
+    fn period(&self) -> u16 {
+        (2048 - self.frequency) * 2
+    }
+
+    /// Advances through the wave pattern by the given number of cpu cycles
+    pub fn step(&mut self, cycles: u16) {
+        let mut cycles = cycles;
+        let mut fetched = false;
+        while cycles >= self.frequency_timer {
+            cycles -= self.frequency_timer;
+            fetched = true;
+            self.frequency_timer = self.period();
+            self.position = (self.position + 1) % 32;
+            let byte = self.wave_pattern_ram.ram[self.position as usize / 2];
+            self.sample_buffer = if self.position & 0b1 == 0 {
+                byte >> 4
+            } else {
+                byte & 0x0F
+            };
+        }
+        self.frequency_timer -= cycles;
+        self.cycles_since_fetch = if fetched {
+            cycles
+        } else {
+            self.cycles_since_fetch.saturating_add(cycles)
+        };
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn dac_enabled(&self) -> bool {
+        self.dac_enabled
+    }
+
+    /// The current digital output of the channel, from 0 to 15
+    pub fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        match self.volume_code {
+            0 => 0,
+            1 => self.sample_buffer,
+            2 => self.sample_buffer >> 1,
+            3 => self.sample_buffer >> 2,
+            _ => panic!("We've defied a law of mathematics!!"),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn wave_pattern_ram(&self) -> [u8; 16] {
+        self.wave_pattern_ram.ram()
+    }
+}
+
+struct WavePatternRam {
+    ram: [u8; 0xFF40 - 0xFF30],
+}
+
+impl WavePatternRam {
+    fn read(&self, address: u16) -> u8 {
+        match address {
+            0xFF30..=0xFF3F => self.ram[(address - 0xFF30) as usize],
+            _ => panic!(
+                "This address: {:#02x} does not belong to the WavePatternRam",
+                address
+            ),
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        match address {
+            0xFF30..=0xFF3F => self.ram[(address - 0xFF30) as usize] = value,
+            _ => panic!(
+                "This address: {:#02x} does not belong to the WavePatternRam",
+                address
+            ),
+        }
+    }
+
+    #[cfg(test)]
+    fn ram(&self) -> [u8; 16] {
+        self.ram
+    }
+}
+
+impl SaveState for WaveChannel {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.length.save_state(state);
+        state.bool(self.dac_enabled);
+        state.u8(self.volume_code);
+        state.u16(self.frequency);
+        state.u16(self.frequency_timer);
+        state.u8(self.position);
+        state.u8(self.sample_buffer);
+        state.u16(self.cycles_since_fetch);
+        state.bool(self.enabled);
+        state.bytes(&self.wave_pattern_ram.ram);
+        state.bytes(&self.registers);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.length.load_state(state)?;
+        self.dac_enabled = state.bool()?;
+        self.volume_code = state.u8()?;
+        self.frequency = state.u16()?;
+        self.frequency_timer = state.u16()?;
+        self.position = state.u8()?;
+        self.sample_buffer = state.u8()?;
+        self.cycles_since_fetch = state.u16()?;
+        self.enabled = state.bool()?;
+        state.bytes(&mut self.wave_pattern_ram.ram)?;
+        state.bytes(&mut self.registers)
+    }
+}
+
+#[test]
+fn plays_back_wave_ram() {
+    let mut ch = WaveChannel::default();
+    ch.write_wave_ram(0xFF30, 0xA5);
+    ch.write(0, 0x80);
+    ch.write(2, 0x20); // 100% volume
+    ch.write(3, 0xFF);
+    ch.write(4, 0x87); // frequency 2047, a period of 2 cycles
+    assert!(ch.enabled());
+    ch.step(2);
+    assert_eq!(
+        ch.output(),
+        0x5,
+        "Position 1 is the low nibble of the first byte"
+    );
+    ch.step(62);
+    assert_eq!(ch.output(), 0xA, "We've wrapped back around to position 0");
+}
+
+#[test]
+fn volume_code_shifts_sample() {
+    let mut ch = WaveChannel::default();
+    ch.write_wave_ram(0xFF30, 0x0F);
+    ch.write(0, 0x80);
+    ch.write(2, 0x40); // 50% volume
+    ch.write(3, 0xFF);
+    ch.write(4, 0x87);
+    ch.step(2);
+    assert_eq!(ch.output(), 0x7);
+}
+
+#[test]
+fn wave_ram_while_playing() {
+    let mut ch = WaveChannel::default();
+    ch.write_wave_ram(0xFF30, 0x12);
+    ch.write_wave_ram(0xFF31, 0x34);
+    ch.write(0, 0x80);
+    ch.write(3, 0x00);
+    ch.write(4, 0x84); // frequency 0x400, a period of 2048 cycles
+    assert_eq!(
+        ch.read_wave_ram(0xFF30),
+        0xFF,
+        "Nothing has been fetched yet"
+    );
+    ch.step(2048 * 2);
+    assert_eq!(
+        ch.read_wave_ram(0xFF3F),
+        0x34,
+        "We just fetched position 2, any address reads the current byte"
+    );
+    ch.write_wave_ram(0xFF3F, 0xAA);
+    assert_eq!(ch.wave_pattern_ram()[1], 0xAA);
+    assert_eq!(ch.wave_pattern_ram()[0xF], 0x00);
+    ch.step(4);
+    assert_eq!(ch.read_wave_ram(0xFF31), 0xFF);
+    ch.write_wave_ram(0xFF31, 0xBB);
+    assert_eq!(
+        ch.wave_pattern_ram()[1],
+        0xAA,
+        "The write should have been ignored"
+    );
+}
@@ -0,0 +1,96 @@
+use crate::dmg::audio::sink::AudioSink;
+
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::{FromSample, SizedSample};
+use log::error;
+use std::collections::VecDeque;
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+
+// Roughly 100ms of stereo audio at 48kHz. Rate control aims for this to be half full.
+const BUFFER_FRAMES: usize = 4800;
+
+/// Plays the audio on the host's default output device
+pub struct HostSink {
+    _stream: cpal::Stream,
+    buffer: Arc<Mutex<VecDeque<f32>>>,
+    sample_rate: u32,
+}
+
+impl HostSink {
+    pub fn new() -> Result<HostSink, Box<dyn Error>> {
+        let device = cpal::default_host()
+            .default_output_device()
+            .ok_or("No audio output device available")?;
+        let config = device.default_output_config()?;
+        let sample_rate = config.sample_rate().0;
+        let buffer = Arc::new(Mutex::new(VecDeque::with_capacity(BUFFER_FRAMES * 2)));
+
+        let stream = match config.sample_format() {
+            cpal::SampleFormat::F32 => build_stream::<f32>(&device, &config.into(), &buffer)?,
+            cpal::SampleFormat::I16 => build_stream::<i16>(&device, &config.into(), &buffer)?,
+            cpal::SampleFormat::U16 => build_stream::<u16>(&device, &config.into(), &buffer)?,
+            format => return Err(format!("Unsupported sample format: {}", format).into()),
+        };
+        stream.play()?;
+
+        Ok(HostSink {
+            _stream: stream,
+            buffer,
+            sample_rate,
+        })
+    }
+}
+
+fn build_stream<T>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    buffer: &Arc<Mutex<VecDeque<f32>>>,
+) -> Result<cpal::Stream, cpal::BuildStreamError>
+where
+    T: SizedSample + FromSample<f32>,
+{
+    let channels = config.channels as usize;
+    let buffer = buffer.clone();
+    device.build_output_stream(
+        config,
+        move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+            let mut buffer = buffer.lock().unwrap();
+            for frame in data.chunks_mut(channels) {
+                // On an underrun we play silence rather than repeating old samples
+                let left = buffer.pop_front().unwrap_or(0.0);
+                let right = buffer.pop_front().unwrap_or(0.0);
+                for (i, sample) in frame.iter_mut().enumerate() {
+                    let value = match (channels, i) {
+                        (1, _) => (left + right) / 2.0,
+                        (_, 0) => left,
+                        (_, 1) => right,
+                        _ => 0.0,
+                    };
+                    *sample = T::from_sample(value);
+                }
+            }
+        },
+        |err| error!("Audio stream error: {}", err),
+        None,
+    )
+}
+
+impl AudioSink for HostSink {
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn push_samples(&mut self, samples: &[f32]) {
+        let mut buffer = self.buffer.lock().unwrap();
+        // If we're running ahead of the device we drop the newest samples,
+        // rate control will bring us back towards half full.
+        let space = (BUFFER_FRAMES * 2).saturating_sub(buffer.len()) & !1;
+        buffer.extend(samples.iter().take(space));
+    }
+
+    fn fill_level(&self) -> Option<f32> {
+        let buffer = self.buffer.lock().unwrap();
+        Some(buffer.len() as f32 / (BUFFER_FRAMES * 2) as f32)
+    }
+}
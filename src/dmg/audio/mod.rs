@@ -0,0 +1,5 @@
+#[cfg(feature = "host-audio")]
+pub mod host_sink;
+pub mod rate_control;
+pub mod sink;
+pub mod wav_sink;
@@ -0,0 +1,57 @@
+/// The largest amount the output rate is allowed to drift from the sink's
+/// nominal rate. 0.5% is well below what anyone can hear as a pitch change.
+const MAX_RATE_DELTA: f64 = 0.005;
+
+/// Dynamic rate control keeps a real-time sink's buffer about half full.
+///
+/// The emulator and the sound card run off different clocks, and frame
+/// pacing drifts, so producing samples at exactly the sink's rate will
+/// eventually underrun (crackling) or overflow (latency). Instead we nudge
+/// the rate we resample at depending on how full the sink's buffer is.
+/// See: https://docs.libretro.com/development/cores/dynamic-rate-control/
+pub struct RateControl {
+    max_delta: f64,
+}
+
+impl Default for RateControl {
+    fn default() -> RateControl {
+        RateControl {
+            max_delta: MAX_RATE_DELTA,
+        }
+    }
+}
+
+impl RateControl {
+    /// Returns the rate samples should be produced at for a sink running at
+    /// `sample_rate` whose buffer is `fill_level` full
+    pub fn adjusted_rate(&self, sample_rate: u32, fill_level: Option<f32>) -> f64 {
+        match fill_level {
+            Some(fill) => {
+                let fill = fill.clamp(0.0, 1.0) as f64;
+                sample_rate as f64 * (1.0 + self.max_delta * (1.0 - 2.0 * fill))
+            }
+            None => sample_rate as f64,
+        }
+    }
+}
+
+#[test]
+fn half_full_buffer_keeps_nominal_rate() {
+    let rc = RateControl::default();
+    assert_eq!(rc.adjusted_rate(48000, Some(0.5)), 48000.0);
+    assert_eq!(rc.adjusted_rate(48000, None), 48000.0);
+}
+
+#[test]
+fn rate_moves_towards_half_full() {
+    let rc = RateControl::default();
+    assert!(
+        rc.adjusted_rate(48000, Some(0.1)) > 48000.0,
+        "A draining buffer should be fed faster"
+    );
+    assert!(
+        rc.adjusted_rate(48000, Some(0.9)) < 48000.0,
+        "A filling buffer should be fed slower"
+    );
+    assert_eq!(rc.adjusted_rate(48000, Some(1.0)), 48000.0 * 0.995);
+}
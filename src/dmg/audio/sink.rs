@@ -0,0 +1,51 @@
+/// The sample rate used by sinks that aren't tied to a real audio device
+pub const DEFAULT_SAMPLE_RATE: u32 = 44100;
+
+/// A destination for the audio produced by the APU.
+///
+/// Samples are interleaved stereo pairs (left, right) in the range -1.0 to 1.0
+pub trait AudioSink {
+    /// The number of stereo frames per second the sink expects to receive
+    fn sample_rate(&self) -> u32;
+
+    /// Hands a block of interleaved left/right samples to the sink
+    fn push_samples(&mut self, samples: &[f32]);
+
+    /// How full the sink's playback buffer is, from 0.0 (empty) to 1.0 (full).
+    /// Sinks that consume samples as soon as they're pushed return None and
+    /// are never rate controlled.
+    fn fill_level(&self) -> Option<f32> {
+        None
+    }
+}
+
+/// A sink that throws away everything it is given
+pub struct NullSink {
+    sample_rate: u32,
+}
+
+impl Default for NullSink {
+    fn default() -> NullSink {
+        NullSink {
+            sample_rate: DEFAULT_SAMPLE_RATE,
+        }
+    }
+}
+
+impl AudioSink for NullSink {
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn push_samples(&mut self, _samples: &[f32]) {
+        /* Do nothing */
+    }
+}
+
+#[test]
+fn null_sink_is_not_rate_controlled() {
+    let mut sink = NullSink::default();
+    sink.push_samples(&[0.5, -0.5]);
+    assert_eq!(sink.sample_rate(), DEFAULT_SAMPLE_RATE);
+    assert_eq!(sink.fill_level(), None);
+}
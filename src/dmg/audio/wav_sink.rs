@@ -0,0 +1,106 @@
+use crate::dmg::audio::sink::{AudioSink, DEFAULT_SAMPLE_RATE};
+
+use log::error;
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+
+const CHANNELS: u16 = 2;
+const BITS_PER_SAMPLE: u16 = 16;
+const HEADER_SIZE: u32 = 44;
+
+// Offsets of the two size fields in the RIFF header that are only known
+// once we've finished writing
+const RIFF_SIZE_OFFSET: u64 = 4;
+const DATA_SIZE_OFFSET: u64 = 40;
+
+/// Writes the audio to a 16 bit stereo PCM WAV file.
+///
+/// The header is rewritten after every block of samples so the file is
+/// always playable, even if the emulator is killed rather than shut down.
+pub struct WavSink {
+    file: BufWriter<File>,
+    sample_rate: u32,
+    data_size: u32,
+}
+
+impl WavSink {
+    pub fn create(path: &str) -> std::io::Result<WavSink> {
+        WavSink::with_sample_rate(path, DEFAULT_SAMPLE_RATE)
+    }
+
+    pub fn with_sample_rate(path: &str, sample_rate: u32) -> std::io::Result<WavSink> {
+        let mut sink = WavSink {
+            file: BufWriter::new(File::create(path)?),
+            sample_rate,
+            data_size: 0,
+        };
+        sink.write_header()?;
+        Ok(sink)
+    }
+
+    fn write_header(&mut self) -> std::io::Result<()> {
+        let block_align = CHANNELS * BITS_PER_SAMPLE / 8;
+        let byte_rate = self.sample_rate * block_align as u32;
+        let f = &mut self.file;
+        f.write_all(b"RIFF")?;
+        f.write_all(&(HEADER_SIZE - 8 + self.data_size).to_le_bytes())?;
+        f.write_all(b"WAVE")?;
+        f.write_all(b"fmt ")?;
+        f.write_all(&16u32.to_le_bytes())?; // Size of the fmt chunk
+        f.write_all(&1u16.to_le_bytes())?; // PCM
+        f.write_all(&CHANNELS.to_le_bytes())?;
+        f.write_all(&self.sample_rate.to_le_bytes())?;
+        f.write_all(&byte_rate.to_le_bytes())?;
+        f.write_all(&block_align.to_le_bytes())?;
+        f.write_all(&BITS_PER_SAMPLE.to_le_bytes())?;
+        f.write_all(b"data")?;
+        f.write_all(&self.data_size.to_le_bytes())
+    }
+
+    fn append(&mut self, samples: &[f32]) -> std::io::Result<()> {
+        for sample in samples {
+            let value = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            self.file.write_all(&value.to_le_bytes())?;
+        }
+        self.data_size += (samples.len() * 2) as u32;
+
+        self.file.seek(SeekFrom::Start(RIFF_SIZE_OFFSET))?;
+        self.file
+            .write_all(&(HEADER_SIZE - 8 + self.data_size).to_le_bytes())?;
+        self.file.seek(SeekFrom::Start(DATA_SIZE_OFFSET))?;
+        self.file.write_all(&self.data_size.to_le_bytes())?;
+        self.file.seek(SeekFrom::End(0))?;
+        self.file.flush()
+    }
+}
+
+impl AudioSink for WavSink {
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn push_samples(&mut self, samples: &[f32]) {
+        if let Err(err) = self.append(samples) {
+            error!("Error writing audio to WAV file: {}", err);
+        }
+    }
+}
+
+#[test]
+fn wav_header_tracks_data_size() {
+    let path = std::env::temp_dir().join("oxide_boy_wav_header_test.wav");
+    let path = path.to_str().unwrap();
+    let mut sink = WavSink::create(path).unwrap();
+    sink.push_samples(&[0.0, 0.0, 1.0, -1.0]);
+    sink.push_samples(&[0.5, 0.5]);
+
+    let bytes = std::fs::read(path).unwrap();
+    assert_eq!(&bytes[0..4], b"RIFF");
+    assert_eq!(&bytes[8..12], b"WAVE");
+    assert_eq!(bytes.len(), 44 + 12, "Six 16 bit samples should follow the header");
+    assert_eq!(u32::from_le_bytes([bytes[40], bytes[41], bytes[42], bytes[43]]), 12);
+    assert_eq!(u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]), 36 + 12);
+    assert_eq!(i16::from_le_bytes([bytes[48], bytes[49]]), i16::MAX);
+    assert_eq!(i16::from_le_bytes([bytes[50], bytes[51]]), -i16::MAX);
+    std::fs::remove_file(path).unwrap();
+}
@@ -3,6 +3,7 @@ use crate::dmg::busconnection::BusConnection;
 use std::fs::File;
 use std::io::Read;
 
+use crate::dmg::save_state::{SaveState, StateReader, StateWriter};
 use log::error;
 
 const BOOT_ROM: &str = "src/dmg/rom/DMG_ROM.bin";
@@ -10,6 +11,7 @@ const BOOT_ROM: &str = "src/dmg/rom/DMG_ROM.bin";
 pub struct BootRom {
     rom: [u8; 0xFF + 1],
     enabled: bool,
+    custom: bool,
 }
 
 impl Default for BootRom {
@@ -17,6 +19,7 @@ impl Default for BootRom {
         BootRom {
             rom: load_boot_rom(BOOT_ROM),
             enabled: true,
+            custom: false,
         }
     }
 }
@@ -57,15 +60,33 @@ impl BootRom {
         self.enabled
     }
 
+    /// Whether it was loaded from a file other than the built in DMG boot rom
+    pub fn is_custom(&self) -> bool {
+        self.custom
+    }
+
     /// Allows you to specify the bootrom
     pub fn custom(file: &str) -> BootRom {
         BootRom {
             rom: load_boot_rom(file),
             enabled: true,
+            custom: true,
         }
     }
 }
 
+// The boot rom itself is read from a file, only whether it's mapped in is saved
+impl SaveState for BootRom {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.enabled);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.enabled = state.bool()?;
+        Ok(())
+    }
+}
+
 #[test]
 fn read_memory() {
     let rom = BootRom::default();
@@ -1,12 +1,21 @@
-use log::error;
+use minifb::Key;
 
-use crate::dmg::apu::Apu;
+use crate::dmg::apu::apu::Apu;
+use crate::dmg::audio::sink::AudioSink;
 use crate::dmg::boot_rom::BootRom;
-use crate::dmg::busconnection::BusConnection;
+use crate::dmg::busconnection::{BusConnection, MemoryBus};
 use crate::dmg::cartridge::cartridge::Cartridge;
+use crate::dmg::coverage::Coverage;
+use crate::dmg::debugger::{Access, Watchpoints};
+use crate::dmg::disasm::instruction_length;
+use crate::dmg::dma::Dma;
 use crate::dmg::joypad::Joypad;
 use crate::dmg::memory::{Interrupt, Memory};
-use crate::dmg::ppu::ppu::PPU;
+use crate::dmg::model::Model;
+use crate::dmg::ppu::ppu::{HEIGHT, PPU, WIDTH};
+use crate::dmg::save_state::{SaveState, StateReader, StateWriter};
+use crate::dmg::serial::{Serial, SerialDevice};
+use crate::dmg::speed::Speed;
 use crate::dmg::timer::Timer;
 
 /// Struct for representing the bus which serves as the interface
@@ -16,9 +25,15 @@ pub struct Bus {
     timer: Timer,
     ppu: PPU,
     apu: Apu,
+    dma: Dma,
+    serial: Serial,
+    speed: Speed,
     joypad: Joypad,
     cartridge: Cartridge,
     boot_rom: BootRom,
+    model: Model,
+    watchpoints: Watchpoints,
+    coverage: Option<Coverage>,
 }
 
 impl Default for Bus {
@@ -28,9 +43,15 @@ impl Default for Bus {
             timer: Timer::default(),
             ppu: PPU::default(),
             apu: Apu::default(),
+            dma: Dma::default(),
+            serial: Serial::default(),
+            speed: Speed::default(),
             joypad: Joypad::default(),
             cartridge: Cartridge::default(),
             boot_rom: BootRom::default(),
+            model: Model::Dmg,
+            watchpoints: Watchpoints::default(),
+            coverage: None,
         }
     }
 }
@@ -42,9 +63,15 @@ impl Bus {
             timer: Timer::default(),
             ppu: PPU::new(),
             apu: Apu::default(),
+            dma: Dma::default(),
+            serial: Serial::default(),
+            speed: Speed::default(),
             joypad: Joypad::default(),
             cartridge: Cartridge::new(file),
             boot_rom: BootRom::default(),
+            model: Model::Dmg,
+            watchpoints: Watchpoints::default(),
+            coverage: None,
         }
     }
 
@@ -55,48 +82,97 @@ impl Bus {
             timer: Timer::default(),
             ppu: PPU::new(),
             apu: Apu::default(),
+            dma: Dma::default(),
+            serial: Serial::default(),
+            speed: Speed::default(),
             joypad: Joypad::default(),
             cartridge: Cartridge::new(file),
             boot_rom: BootRom::custom(boot_file),
+            model: Model::Dmg,
+            watchpoints: Watchpoints::default(),
+            coverage: None,
+        }
+    }
+
+    /// A bus without a window, for running a cartridge that isn't loaded from a file
+    pub fn with_cartridge(cartridge: Cartridge) -> Bus {
+        Bus {
+            memory: Memory::default(),
+            timer: Timer::default(),
+            ppu: PPU::default(),
+            apu: Apu::default(),
+            dma: Dma::default(),
+            serial: Serial::default(),
+            speed: Speed::default(),
+            joypad: Joypad::default(),
+            cartridge,
+            boot_rom: BootRom::default(),
+            model: Model::Dmg,
+            watchpoints: Watchpoints::default(),
+            coverage: None,
         }
     }
 
     pub fn read_byte(&self, address: u16) -> u8 {
+        self.watchpoints.check(address, Access::Read);
+        if let Some(value) = self.dma_conflict(address) {
+            return value;
+        }
+        if let Some(coverage) = &self.coverage {
+            if let Some(offset) = self.rom_offset(address) {
+                coverage.read(address, offset);
+            }
+        }
+        self.read_mapped(address)
+    }
+
+    /// Reads whatever is mapped to an address, without the checks on the cpu's reads
+    fn read_mapped(&self, address: u16) -> u8 {
         // TODO: Add the rest pointing to other devices
         if address <= 0xFF && self.boot_rom.enabled() {
             return self.boot_rom.read_byte(address);
         }
         match address {
             0x0000..=0x7FFF | 0xA000..=0xBFFF => self.cartridge.read_byte(address),
-            0x8000..=0x9FFF | 0xFF40..=0xFF4B | 0xFE00..=0xFE9F => self.ppu.read_byte(address),
+            0x8000..=0x9FFF
+            | 0xFF40..=0xFF45
+            | 0xFF47..=0xFF4B
+            | 0xFF4F
+            | 0xFF68..=0xFF6B
+            | 0xFE00..=0xFE9F => self.ppu.read_byte(address),
+            0xFF4D if self.model == Model::Cgb => self.speed.read_byte(address),
             0xFF00 => self.joypad.read_byte(address),
             0xFF04..=0xFF07 => self.timer.read_byte(address),
-            0xFF10..=0xFF1E | 0xFF20..=0xFF26 | 0xFF30..=0xFF3F => self.apu.read(address),
+            0xFF10..=0xFF3F => self.apu.read(address),
             0xFEA0..=0xFEFF => 0xFF, /* Unused Memory. Return Default value */
-            0xFF01..=0xFF03 => {
-                error!("unimplemented serial transfer port");
-                0xFF // TODO: The serial transfer port
-            }
+            0xFF01..=0xFF02 => self.serial.read_byte(address),
+            0xFF46 => self.dma.register(),
             0xC000..=0xFDFF => self.memory.read_byte(address),
             _ => self.memory.read_byte(address),
         }
     }
 
     pub fn write_byte(&mut self, address: u16, value: u8) {
+        self.watchpoints.check(address, Access::Write);
+        if self.dma_conflict(address).is_some() {
+            return;
+        }
         // TODO: Add the rest pointing to other devices
         match address {
             0x0000..=0x7FFF | 0xA000..=0xBFFF => self.cartridge.write_byte(address, value),
             0xFF00 => self.joypad.write_byte(address, value),
             0xFF04..=0xFF07 => self.timer.write_byte(address, value),
-            0xFF10..=0xFF14 | 0xFF16..=0xFF1E | 0xFF20..=0xFF26 | 0xFF30..=0xFF3F => {
-                self.apu.write(address, value)
-            }
-            0x8000..=0x9FFF | 0xFF40..=0xFF45 | 0xFF47..=0xFF4B | 0xFE00..=0xFE9F => {
-                self.ppu.write_byte(address, value)
-            }
-            0xFF01..=0xFF03 => error!("unimplemented link cable"),
+            0xFF10..=0xFF3F => self.apu.write(address, value),
+            0x8000..=0x9FFF
+            | 0xFF40..=0xFF45
+            | 0xFF47..=0xFF4B
+            | 0xFF4F
+            | 0xFF68..=0xFF6B
+            | 0xFE00..=0xFE9F => self.ppu.write_byte(address, value),
+            0xFF4D if self.model == Model::Cgb => self.speed.write_byte(address, value),
+            0xFF01..=0xFF02 => self.serial.write_byte(address, value),
             0xFEA0..=0xFEFF => { /* Unused memory. Do Nothing */ }
-            0xFF46 => self.dma(value),
+            0xFF46 => self.dma.start(value),
             0xFF50 => self.boot_rom.write_byte(address, value),
             0xC000..=0xFDFF => self.memory.write_byte(address, value),
             _ => self.memory.write_byte(address, value),
@@ -116,8 +192,8 @@ impl Bus {
         self.write_byte(address + 1, h_byte);
     }
 
-    pub fn interrupt_flag_off(&mut self) {
-        self.memory.interrupt_flag_off();
+    pub fn interrupt_flag_off(&mut self, interrupt: &Interrupt) {
+        self.memory.interrupt_flag_off(interrupt);
     }
 
     pub fn return_interrupt(&self) -> Interrupt {
@@ -125,22 +201,276 @@ impl Bus {
     }
 
     pub fn step(&mut self, cycles: u8) {
-        self.timer.step(cycles);
-        self.ppu.step(cycles);
-    }
-
-    /// Initiates the dma transfer
-    /// Moves sprite data from to OAM
-    /// TODO: This takes 160 cycles
-    fn dma(&mut self, value: u8) {
-        let source_address = (value as u16) << 8;
-        for i in 0..=0x9F {
-            let val = self.read_byte(source_address + i);
-            self.write_byte(0xFE00 + i, val);
+        for _ in 0..cycles / 4 {
+            if let Some((address, index)) = self.dma.tick() {
+                let value = self.read_mapped(address);
+                self.ppu.dma_write(index, value);
+            }
+        }
+        if self.timer.step(cycles) {
+            self.memory.request_interrupt(Interrupt::TimerOverflow);
+        }
+        // In double speed the PPU and APU get half as many cycles as the rest
+        let cycles_at_normal_speed = if self.speed.double() {
+            cycles / 2
+        } else {
+            cycles
+        };
+        self.ppu.step(cycles_at_normal_speed);
+        self.apu.step(cycles_at_normal_speed);
+        if self.serial.step(cycles) {
+            self.memory.request_interrupt(Interrupt::SerialLink);
+        }
+    }
+
+    /// Makes the bus a DMG or a CGB, the CGB's registers and banks do nothing on a DMG
+    pub fn set_model(&mut self, model: Model) {
+        self.model = model;
+        self.memory.set_cgb(model == Model::Cgb);
+        self.ppu.set_cgb(model == Model::Cgb);
+    }
+
+    pub fn model(&self) -> Model {
+        self.model
+    }
+
+    /// Whether the boot rom is still mapped over the start of the cartridge
+    pub fn boot_rom_enabled(&self) -> bool {
+        self.boot_rom.enabled()
+    }
+
+    pub fn has_custom_boot_rom(&self) -> bool {
+        self.boot_rom.is_custom()
+    }
+
+    pub fn set_boot_rom(&mut self, boot_rom: BootRom) {
+        self.boot_rom = boot_rom;
+    }
+
+    /// The model the inserted rom asks for in its header
+    pub fn cartridge_model(&self) -> Model {
+        Model::from_cgb_flag(self.cartridge.read_byte(0x0143))
+    }
+
+    /// STOP switches the CGB between normal and double speed if KEY1 asked for it
+    pub fn switch_speed(&mut self) -> bool {
+        self.model == Model::Cgb && self.speed.switch()
+    }
+
+    /// In double speed the cpu runs twice as many cycles for each frame the PPU draws
+    pub fn double_speed(&self) -> bool {
+        self.speed.double()
+    }
+
+    /// Plugs a device into the link port
+    pub fn set_serial_device(&mut self, device: Box<dyn SerialDevice>) {
+        self.serial.set_device(device);
+    }
+
+    /// The bytes sent over the link port, if the device keeps them
+    pub fn serial_output(&self) -> &[u8] {
+        self.serial.output()
+    }
+
+    /// Sets where the audio produced by the APU is sent
+    pub fn set_audio_sink(&mut self, sink: Box<dyn AudioSink>) {
+        self.apu.set_sink(sink);
+    }
+
+    pub fn apu(&self) -> &Apu {
+        &self.apu
+    }
+
+    pub fn apu_mut(&mut self) -> &mut Apu {
+        &mut self.apu
+    }
+
+    /// The keys pressed in the window since the last call
+    pub fn take_pressed_keys(&mut self) -> Vec<Key> {
+        self.ppu.take_pressed_keys()
+    }
+
+    pub fn is_key_down(&self, key: Key) -> bool {
+        self.ppu.is_key_down(key)
+    }
+
+    pub fn stub_ly(&mut self, stub: bool) {
+        self.ppu.stub_ly(stub);
+    }
+
+    /// Records which rom bytes are run and read
+    pub fn set_coverage(&mut self, coverage: Option<Coverage>) {
+        self.coverage = coverage;
+    }
+
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+
+    /// Marks the instruction at the address as run, and its reads of other rom bytes as data
+    pub fn begin_instruction(&self, address: u16) {
+        if let Some(coverage) = &self.coverage {
+            let offset = self.rom_offset(address);
+            let length = match offset {
+                Some(_) => instruction_length(self.cartridge.read_byte(address)),
+                None => 0,
+            };
+            coverage.begin_instruction(address, offset, length);
+        }
+    }
+
+    pub fn end_instruction(&self) {
+        if let Some(coverage) = &self.coverage {
+            coverage.end_instruction();
+        }
+    }
+
+    // Where in the rom file the address is read from, if it's in the rom
+    fn rom_offset(&self, address: u16) -> Option<usize> {
+        match address {
+            0..=0xFF if self.boot_rom.enabled() => None,
+            0..=0x3FFF => Some(address as usize),
+            0x4000..=0x7FFF => Some(self.cartridge.rom_bank() * 0x4000 + address as usize - 0x4000),
+            _ => None,
+        }
+    }
+
+    pub fn watchpoints(&self) -> &Watchpoints {
+        &self.watchpoints
+    }
+
+    pub fn watchpoints_mut(&mut self) -> &mut Watchpoints {
+        &mut self.watchpoints
+    }
+
+    /// Identifies the inserted rom
+    pub fn rom_checksum(&self) -> u64 {
+        self.cartridge.rom_checksum()
+    }
+
+    /// The bank switched in at the address, numbered the way symbol files do
+    pub fn bank(&self, address: u16) -> u16 {
+        match address {
+            0x4000..=0x7FFF => self.cartridge.rom_bank() as u16,
+            0xA000..=0xBFFF => self.cartridge.ram_bank() as u16,
+            // The CGB switches banks of wram in here, on the DMG it is always WRAMX bank 1
+            0xD000..=0xDFFF => self.memory.wram_bank() as u16,
+            _ => 0,
+        }
+    }
+
+    pub fn screen(&self) -> &[[u32; WIDTH]; HEIGHT] {
+        self.ppu.screen()
+    }
+
+    /// While OAM DMA runs, OAM reads 0xFF and the cpu can't use the bus it copies from.
+    /// Reads from that bus get the byte being copied instead and writes are dropped.
+    /// Returns what a conflicting read gets, or None when the access goes through
+    /// See: https://gbdev.io/pandocs/OAM_DMA_Transfer.html
+    fn dma_conflict(&self, address: u16) -> Option<u8> {
+        let source = self.dma.source()?;
+        let video = |address: u16| (0x8000..=0x9FFF).contains(&address);
+        match address {
+            0xFE00..=0xFE9F => Some(0xFF),
+            0x0000..=0xFDFF if video(address) == video(source) => Some(self.read_mapped(source)),
+            _ => None,
         }
     }
 }
 
+// Lets the bus be used wherever a component is expected, e.g. by the disassembler
+impl BusConnection for Bus {
+    fn read_byte(&self, address: u16) -> u8 {
+        Bus::read_byte(self, address)
+    }
+
+    fn write_byte(&mut self, address: u16, value: u8) {
+        Bus::write_byte(self, address, value)
+    }
+}
+
+impl MemoryBus for Bus {
+    fn tick(&mut self) {
+        Bus::step(self, 4)
+    }
+
+    // The peripherals can run a whole instruction at once
+    fn step(&mut self, cycles: u8) {
+        Bus::step(self, cycles)
+    }
+
+    fn return_interrupt(&self) -> Interrupt {
+        Bus::return_interrupt(self)
+    }
+
+    fn interrupt_flag_off(&mut self, interrupt: &Interrupt) {
+        Bus::interrupt_flag_off(self, interrupt)
+    }
+
+    fn bank(&self, address: u16) -> u16 {
+        Bus::bank(self, address)
+    }
+
+    fn begin_instruction(&self, address: u16) {
+        Bus::begin_instruction(self, address)
+    }
+
+    fn end_instruction(&self) {
+        Bus::end_instruction(self)
+    }
+
+    fn stub_ly(&mut self, stub: bool) {
+        Bus::stub_ly(self, stub)
+    }
+
+    fn switch_speed(&mut self) -> bool {
+        Bus::switch_speed(self)
+    }
+
+    fn double_speed(&self) -> bool {
+        Bus::double_speed(self)
+    }
+
+    fn model(&self) -> Model {
+        Bus::model(self)
+    }
+}
+
+impl SaveState for Bus {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.model == Model::Cgb);
+        self.memory.save_state(state);
+        self.timer.save_state(state);
+        self.ppu.save_state(state);
+        self.apu.save_state(state);
+        self.dma.save_state(state);
+        self.serial.save_state(state);
+        self.speed.save_state(state);
+        self.joypad.save_state(state);
+        self.cartridge.save_state(state);
+        self.boot_rom.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        let model = if state.bool()? {
+            Model::Cgb
+        } else {
+            Model::Dmg
+        };
+        self.set_model(model);
+        self.memory.load_state(state)?;
+        self.timer.load_state(state)?;
+        self.ppu.load_state(state)?;
+        self.apu.load_state(state)?;
+        self.dma.load_state(state)?;
+        self.serial.load_state(state)?;
+        self.speed.load_state(state)?;
+        self.joypad.load_state(state)?;
+        self.cartridge.load_state(state)?;
+        self.boot_rom.load_state(state)
+    }
+}
+
 #[cfg(test)]
 fn setup() -> Bus {
     Bus::default()
@@ -181,9 +511,22 @@ fn write_to_timer_tima() {
     assert_eq!(bus.timer.tima(), 0xAA);
 }
 
+#[test]
+fn timer_overflow_requests_interrupt() {
+    let mut bus = setup();
+    bus.write_byte(0xFFFF, 0x04);
+    bus.write_byte(0xFF05, 0xFF);
+    bus.write_byte(0xFF07, 0b101);
+    bus.step(16);
+    assert!(matches!(bus.return_interrupt(), Interrupt::NONE));
+    bus.step(4);
+    assert!(matches!(bus.return_interrupt(), Interrupt::TimerOverflow));
+}
+
 #[test]
 fn write_to_apu_sweep_register() {
     let mut bus = setup();
+    bus.write_byte(0xFF26, 0x80); // The registers can only be written while powered on
     bus.write_byte(0xFF10, 0xAA);
     assert_eq!(bus.apu.sweep_register(), 0xAA);
 }
@@ -220,6 +563,36 @@ fn oam() {
     assert_eq!(bus.ppu.oam()[0x0010], 0xAA);
 }
 
+#[test]
+fn cgb_model() {
+    let mut bus = setup();
+    bus.set_model(Model::Cgb);
+    bus.write_byte(0xFF70, 0x03);
+    bus.write_byte(0xD000, 0xAA);
+    assert_eq!(bus.bank(0xD000), 3);
+    bus.write_byte(0xFF70, 0x01);
+    assert_eq!(bus.read_byte(0xD000), 0x00);
+    assert_eq!(bus.read_byte(0xFF4D), 0x7E);
+}
+
+#[test]
+fn double_speed_runs_the_ppu_at_half_speed() {
+    let mut bus = setup();
+    bus.set_model(Model::Cgb);
+    bus.write_byte(0xFF4D, 0x01);
+    assert!(bus.switch_speed());
+    bus.write_byte(0xFF40, 0x91);
+    // A scanline is 456 cycles at normal speed
+    for _ in 0..114 {
+        bus.step(4);
+    }
+    assert_eq!(bus.read_byte(0xFF44), 0);
+    for _ in 0..114 {
+        bus.step(4);
+    }
+    assert_eq!(bus.read_byte(0xFF44), 1);
+}
+
 #[test]
 fn disable_boot_rom() {
     let mut bus = setup();
@@ -240,9 +613,15 @@ fn default_no_cart_is_rom() {
         timer: Timer::default(),
         ppu: PPU::default(),
         apu: Apu::default(),
+        dma: Dma::default(),
+        serial: Serial::default(),
+        speed: Speed::default(),
         joypad: Joypad::default(),
         cartridge: Cartridge::new("notARealFile.bin"),
         boot_rom: BootRom::default(),
+        model: Model::Dmg,
+        watchpoints: Watchpoints::default(),
+        coverage: None,
     };
     assert_eq!(bus.read_byte(0xA000), 0);
     bus.write_byte(0xA000, 10);
@@ -263,6 +642,81 @@ fn dma_transfer() {
     assert_eq!(bus.read_byte(0xFE01), 0);
     assert_eq!(bus.read_byte(0xFE02), 0);
     bus.write_byte(0xFF46, 0x1);
+    for _ in 0..162 {
+        bus.step(4);
+    }
     assert_eq!(bus.read_byte(0xFE01), 0xC3);
     assert_eq!(bus.read_byte(0xFE02), 0x50);
 }
+
+#[cfg(test)]
+fn dma_from_wram() -> Bus {
+    let mut bus = setup();
+    for i in 0..0xA0 {
+        bus.write_byte(0xC000 + i, i as u8);
+    }
+    bus.write_byte(0xFF46, 0xC0);
+    bus
+}
+
+#[test]
+fn dma_takes_160_cycles() {
+    let mut bus = dma_from_wram();
+    bus.step(4);
+    assert_eq!(bus.read_byte(0xFE00), 0, "The transfer is starting up");
+    bus.step(4);
+    assert_eq!(bus.read_byte(0xFE00), 0xFF, "OAM belongs to the transfer");
+    for _ in 0..159 {
+        bus.step(4);
+    }
+    assert_eq!(bus.read_byte(0xFE05), 0xFF);
+    bus.step(4);
+    assert_eq!(bus.read_byte(0xFE05), 0x05);
+    assert_eq!(bus.read_byte(0xFE9F), 0x9F);
+    assert_eq!(bus.read_byte(0xFF46), 0xC0);
+}
+
+#[test]
+fn dma_ignores_the_ppu_mode() {
+    let mut bus = dma_from_wram();
+    // Mode 2, OAM is locked to the cpu
+    bus.write_byte(0xFF41, 0x02);
+    for _ in 0..162 {
+        bus.step(4);
+    }
+    assert_eq!(bus.ppu.oam()[0x10], 0x10);
+}
+
+#[test]
+fn dma_bus_conflicts() {
+    let mut bus = dma_from_wram();
+    for _ in 0..3 {
+        bus.step(4);
+    }
+    assert_eq!(
+        bus.read_byte(0xD000),
+        0x01,
+        "Reads from the bus being copied from get the byte being copied"
+    );
+    bus.write_byte(0xC001, 0xAA);
+    assert_eq!(bus.memory.read_byte(0xC001), 0x01, "Writes are dropped");
+    bus.write_byte(0xFF80, 0xAA);
+    assert_eq!(bus.read_byte(0xFF80), 0xAA, "HRAM can still be used");
+    bus.write_byte(0x8000, 0xAA);
+    assert_eq!(
+        bus.read_byte(0x8000),
+        0xAA,
+        "So can VRAM, it's on another bus"
+    );
+}
+
+#[test]
+fn dma_from_echo_ram_sources() {
+    let mut bus = setup();
+    bus.write_byte(0xDE10, 0xAB);
+    bus.write_byte(0xFF46, 0xFE);
+    for _ in 0..162 {
+        bus.step(4);
+    }
+    assert_eq!(bus.read_byte(0xFE10), 0xAB);
+}
@@ -1,3 +1,6 @@
+use crate::dmg::memory::Interrupt;
+use crate::dmg::model::Model;
+
 pub trait BusConnection {
     /// Returns a byte at a given address
     fn read_byte(&self, address: u16) -> u8;
@@ -22,3 +25,51 @@ pub trait BusConnection {
         self.write_byte(address + 1, h_byte);
     }
 }
+
+/// Everything the cpu needs from the memory it runs against. `Bus` is the Game Boy's,
+/// `FlatBus` is plain 64KB of memory for testing and fuzzing instructions on their own.
+pub trait MemoryBus: BusConnection {
+    /// Runs the rest of the hardware for one M-cycle, 4 clock cycles. A bus that keeps
+    /// its timers and PPU in step with the cpu's memory accesses does it all here.
+    fn tick(&mut self) {}
+
+    /// Runs the rest of the hardware for the cycles an instruction took
+    fn step(&mut self, cycles: u8) {
+        for _ in 0..cycles / 4 {
+            self.tick();
+        }
+    }
+
+    /// The highest priority interrupt that's both requested and enabled
+    fn return_interrupt(&self) -> Interrupt {
+        Interrupt::NONE
+    }
+
+    fn interrupt_flag_off(&mut self, _interrupt: &Interrupt) {}
+
+    /// The bank switched in at the address, for labels and profiles
+    fn bank(&self, _address: u16) -> u16 {
+        0
+    }
+
+    /// Called around each instruction, see `Bus::begin_instruction`
+    fn begin_instruction(&self, _address: u16) {}
+
+    fn end_instruction(&self) {}
+
+    /// Makes LY always read 0x90, see `Bus::stub_ly`
+    fn stub_ly(&mut self, _stub: bool) {}
+
+    /// Run by STOP, see `Bus::switch_speed`
+    fn switch_speed(&mut self) -> bool {
+        false
+    }
+
+    fn double_speed(&self) -> bool {
+        false
+    }
+
+    fn model(&self) -> Model {
+        Model::Dmg
+    }
+}
@@ -1,6 +1,7 @@
 use crate::dmg::busconnection::BusConnection;
 use crate::dmg::cartridge::mbc1::MBC1;
 use crate::dmg::cartridge::rom_only::RomOnly;
+use crate::dmg::save_state::{SaveState, StateReader, StateWriter};
 
 use log::error;
 use std::fs::File;
@@ -8,8 +9,22 @@ use std::io::Read;
 
 const DEFAULT_ROM: &str = "src/dmg/rom/DEFAULT_ROM.bin";
 
+/// A memory bank controller, which also has to be able to save its banks and ram
+pub trait Mapper: BusConnection + SaveState {
+    /// The rom bank switched in at 0x4000 - 0x7FFF
+    fn rom_bank(&self) -> usize {
+        1
+    }
+
+    /// The ram bank switched in at 0xA000 - 0xBFFF
+    fn ram_bank(&self) -> usize {
+        0
+    }
+}
+
 pub struct Cartridge {
-    cart: Box<dyn BusConnection>,
+    cart: Box<dyn Mapper>,
+    rom_checksum: u64,
 }
 
 // complete list of cartridges taken from here: https://gbdev.io/pandocs/#_0147-cartridge-type
@@ -100,8 +115,10 @@ impl Cartridge {
                     "Error opening file: {}, defaulting to empty RomOnly Cartridge",
                     err
                 );
+                let data = vec![0u8; 0xC000];
                 return Cartridge {
-                    cart: Box::new(RomOnly::new(vec![0u8; 0xC000])), // This is mainly so that tests may run without a cartridge
+                    rom_checksum: checksum(&data),
+                    cart: Box::new(RomOnly::new(data)), // This is mainly so that tests may run without a cartridge
                 };
             }
         }
@@ -109,13 +126,38 @@ impl Cartridge {
             panic!("Cartridge is too small! Check what file you specified");
         }
         let cart_type = Type::from(data[0x147]);
+        let rom_checksum = checksum(&data);
         let cart = cart(&cart_type, data);
-        Cartridge { cart }
+        Cartridge { cart, rom_checksum }
+    }
+
+    /// Wraps an already built memory bank controller, `rom_checksum` identifies its rom in save states
+    pub fn from_cart(cart: Box<dyn Mapper>, rom_checksum: u64) -> Cartridge {
+        Cartridge { cart, rom_checksum }
+    }
+
+    pub fn rom_checksum(&self) -> u64 {
+        self.rom_checksum
+    }
+
+    pub fn rom_bank(&self) -> usize {
+        self.cart.rom_bank()
+    }
+
+    pub fn ram_bank(&self) -> usize {
+        self.cart.ram_bank()
     }
 }
 
+/// 64 bit FNV-1a hash of the rom, used to tell whether a save state belongs to it
+pub fn checksum(data: &[u8]) -> u64 {
+    data.iter().fold(0xCBF29CE484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001B3)
+    })
+}
+
 // Limiting the cartridge types that are implemented
-fn cart(cart_type: &Type, data: Vec<u8>) -> Box<dyn BusConnection> {
+fn cart(cart_type: &Type, data: Vec<u8>) -> Box<dyn Mapper> {
     match cart_type {
         Type::RomOnly => Box::new(RomOnly::new(data)),
         Type::MBC1 | Type::Mbc1Ram | Type::Mbc1RamBattery => Box::new(MBC1::new(data)),
@@ -133,6 +175,16 @@ impl BusConnection for Cartridge {
     }
 }
 
+impl SaveState for Cartridge {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.cart.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.cart.load_state(state)
+    }
+}
+
 #[test]
 fn convert_byte_to_type() {
     assert_eq!(Type::from(0x00), Type::RomOnly);
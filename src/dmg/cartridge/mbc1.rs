@@ -1,4 +1,6 @@
 use crate::dmg::busconnection::BusConnection;
+use crate::dmg::cartridge::cartridge::Mapper;
+use crate::dmg::save_state::{SaveState, StateReader, StateWriter};
 
 // (max 2MByte ROM and/or 32KByte RAM)
 // https://gbdev.io/pandocs/#mbc1
@@ -32,19 +34,29 @@ enum BankingMode {
     Ram,
 }
 
+impl Mapper for MBC1 {
+    fn rom_bank(&self) -> usize {
+        // If the rom bank is 0x00,0x20,0x40,0x60 we add one to the rom
+        // bank number. This kind of bizarre behaviour is documented here:
+        // https://gbdev.io/pandocs/#_4000-7fff-rom-bank-01-7f-read-only
+        let rom_bank = match self.rom_bank_number {
+            0x00 | 0x20 | 0x40 | 0x60 => self.rom_bank_number + 1,
+            _ => self.rom_bank_number,
+        };
+        rom_bank as usize
+    }
+
+    fn ram_bank(&self) -> usize {
+        self.ram_bank_numer as usize
+    }
+}
+
 impl BusConnection for MBC1 {
     fn read_byte(&self, address: u16) -> u8 {
         match address {
             0..=0x3FFF => self.rom_bank[address as usize],
             0x4000..=0x7FFF => {
-                // If the rom bank is 0x00,0x20,0x40,0x60 we add one to the rom
-                // bank number. This kind of bizarre behaviour is documented here:
-                // https://gbdev.io/pandocs/#_4000-7fff-rom-bank-01-7f-read-only
-                let rom_bank = match self.rom_bank_number {
-                    0x00 | 0x20 | 0x40 | 0x60 => self.rom_bank_number + 1,
-                    _ => self.rom_bank_number,
-                };
-                self.rom_bank[(rom_bank as usize * 0x4000) + (address as usize - 0x4000)]
+                self.rom_bank[(self.rom_bank() * 0x4000) + (address as usize - 0x4000)]
             }
             0xA000..=0xBFFF => {
                 if self.ram_enable {
@@ -91,6 +103,29 @@ impl BusConnection for MBC1 {
     }
 }
 
+impl SaveState for MBC1 {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.ram_bank);
+        state.bool(self.ram_enable);
+        state.u8(self.rom_bank_number);
+        state.u8(self.ram_bank_numer);
+        state.bool(self.mode == BankingMode::Ram);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        state.bytes(&mut self.ram_bank)?;
+        self.ram_enable = state.bool()?;
+        self.rom_bank_number = state.u8()?;
+        self.ram_bank_numer = state.u8()?;
+        self.mode = if state.bool()? {
+            BankingMode::Ram
+        } else {
+            BankingMode::Rom
+        };
+        Ok(())
+    }
+}
+
 #[test]
 fn write_ram_enabled() {
     let mut mbc1 = MBC1::new(vec![0u8; 0x4000]);
@@ -1,3 +1,4 @@
+#[allow(clippy::module_inception)]
 pub mod cartridge;
 pub mod mbc1;
 pub mod rom_only;
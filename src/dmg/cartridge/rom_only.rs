@@ -1,4 +1,6 @@
 use crate::dmg::busconnection::BusConnection;
+use crate::dmg::cartridge::cartridge::Mapper;
+use crate::dmg::save_state::{SaveState, StateReader, StateWriter};
 
 pub struct RomOnly {
     rom: Vec<u8>,
@@ -10,12 +12,25 @@ impl RomOnly {
     }
 }
 
+// There is no banking, the second 16KB are always at 0x4000
+impl Mapper for RomOnly {}
+
 impl BusConnection for RomOnly {
     fn read_byte(&self, address: u16) -> u8 {
-        self.rom[address as usize]
+        // There's no ram at 0xA000 - 0xBFFF, nothing drives the bus
+        self.rom.get(address as usize).copied().unwrap_or(0xFF)
     }
 
     fn write_byte(&mut self, _address: u16, _value: u8) {
         /* Do Nothing, this is ROM */
     }
 }
+
+// Nothing to save, the rom is loaded again from the file
+impl SaveState for RomOnly {
+    fn save_state(&self, _state: &mut StateWriter) {}
+
+    fn load_state(&mut self, _state: &mut StateReader) -> Result<(), String> {
+        Ok(())
+    }
+}
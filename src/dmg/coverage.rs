@@ -0,0 +1,278 @@
+use crate::dmg::save_state::{StateReader, StateWriter};
+use log::error;
+use std::cell::{Cell, RefCell};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const MAGIC: &[u8; 4] = b"OXCV";
+const VERSION: u16 = 1;
+
+// How a rom byte was used, a byte can be any combination of these
+pub const OPCODE: u8 = 0x01;
+pub const OPERAND: u8 = 0x02;
+pub const DATA: u8 = 0x04;
+
+// The coverage is saved every so many instructions, as the emulator is usually closed rather than exited
+const SAVE_INSTRUCTIONS: u32 = 1 << 20;
+
+const BANK_SIZE: usize = 0x4000;
+
+/// Which bytes of the rom were run as opcodes, read as their operands or read as data.
+/// Bytes are numbered by their offset in the rom file, so every bank is kept apart.
+#[derive(Default)]
+pub struct Coverage {
+    flags: RefCell<Vec<u8>>,
+    // The address and length of the instruction being run, reads of its own bytes aren't data.
+    // Reads outside of an instruction, like the debugger's, aren't recorded at all.
+    instruction: Cell<Option<(u16, u16)>>,
+    instructions: Cell<u32>,
+    rom_checksum: u64,
+    file: Option<PathBuf>,
+}
+
+impl Coverage {
+    /// Records into `file`, adding to what it already holds for the same rom,
+    /// and also writes the bytes seen as code or data to `file`.cdl
+    pub fn create(file: &str, rom_checksum: u64, rom_size: usize) -> Result<Coverage, String> {
+        let mut coverage = Coverage::default();
+        coverage.rom_checksum = rom_checksum;
+        // Sized up front so the banks that are never used count too
+        coverage.flags.get_mut().resize(rom_size, 0);
+        if Path::new(file).exists() {
+            coverage.merge(&Coverage::load(Path::new(file))?)?;
+        }
+        coverage.file = Some(PathBuf::from(file));
+        Ok(coverage)
+    }
+
+    pub fn load(file: &Path) -> Result<Coverage, String> {
+        let data =
+            fs::read(file).map_err(|e| format!("Error reading {}: {}", file.display(), e))?;
+        Coverage::from_bytes(&data).map_err(|e| format!("{}: {}", file.display(), e))
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Coverage, String> {
+        let mut state = StateReader::new(data);
+        let mut magic = [0u8; 4];
+        state.bytes(&mut magic)?;
+        if &magic != MAGIC {
+            return Err("Not a coverage file".to_string());
+        }
+        let version = state.u16()?;
+        if version != VERSION {
+            return Err(format!("Coverage version {} isn't supported", version));
+        }
+        let mut coverage = Coverage::default();
+        coverage.rom_checksum = state.u64()?;
+        // Checked before allocating, so a corrupt length can't ask for gigabytes
+        let length = state.u32()? as usize;
+        if length > state.remaining() {
+            return Err("The coverage file is truncated".to_string());
+        }
+        let mut flags = vec![0u8; length];
+        state.bytes(&mut flags)?;
+        coverage.flags = RefCell::new(flags);
+        Ok(coverage)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let flags = self.flags.borrow();
+        let mut state = StateWriter::default();
+        state.bytes(MAGIC);
+        state.u16(VERSION);
+        state.u64(self.rom_checksum);
+        state.u32(flags.len() as u32);
+        state.bytes(&flags);
+        state.into_bytes()
+    }
+
+    pub fn rom_checksum(&self) -> u64 {
+        self.rom_checksum
+    }
+
+    /// Adds in the coverage of another run of the same rom
+    pub fn merge(&mut self, other: &Coverage) -> Result<(), String> {
+        if self.rom_checksum != other.rom_checksum {
+            return Err("The coverage was recorded with a different ROM".to_string());
+        }
+        let other = other.flags.borrow();
+        let flags = self.flags.get_mut();
+        if flags.len() < other.len() {
+            flags.resize(other.len(), 0);
+        }
+        for (flag, other) in flags.iter_mut().zip(other.iter()) {
+            *flag |= other;
+        }
+        Ok(())
+    }
+
+    /// Called before the cpu runs the instruction at `address`, with its offset in the rom if it's there
+    pub fn begin_instruction(&self, address: u16, offset: Option<usize>, length: u16) {
+        self.instruction.set(Some((address, length)));
+        if let Some(offset) = offset {
+            self.mark(offset, OPCODE);
+            for i in 1..length as usize {
+                self.mark(offset + i, OPERAND);
+            }
+        }
+        self.instructions.set(self.instructions.get() + 1);
+        if self.instructions.get().is_multiple_of(SAVE_INSTRUCTIONS) {
+            self.save();
+        }
+    }
+
+    pub fn end_instruction(&self) {
+        self.instruction.set(None);
+    }
+
+    /// Called for every read of the rom
+    pub fn read(&self, address: u16, offset: usize) {
+        if let Some((start, length)) = self.instruction.get() {
+            if address.wrapping_sub(start) >= length {
+                self.mark(offset, DATA);
+            }
+        }
+    }
+
+    fn mark(&self, offset: usize, flag: u8) {
+        let mut flags = self.flags.borrow_mut();
+        if offset >= flags.len() {
+            // Grow a bank at a time when the rom size wasn't given
+            flags.resize((offset / BANK_SIZE + 1) * BANK_SIZE, 0);
+        }
+        flags[offset] |= flag;
+    }
+
+    /// The flags of each rom byte, see `OPCODE`, `OPERAND` and `DATA`
+    pub fn flags(&self) -> Vec<u8> {
+        self.flags.borrow().clone()
+    }
+
+    /// A code/data log, one byte per rom byte with bit 0 set for code and bit 1 for data,
+    /// the layout FCEUX and Mesen use and many disassembly tools read
+    pub fn cdl(&self) -> Vec<u8> {
+        let flags = self.flags.borrow();
+        let code = |flag: u8| (flag & (OPCODE | OPERAND) != 0) as u8;
+        let data = |flag: u8| ((flag & DATA != 0) as u8) << 1;
+        flags.iter().map(|flag| code(*flag) | data(*flag)).collect()
+    }
+
+    /// How much of each bank was used as code and as data
+    pub fn summary(&self) -> String {
+        let flags = self.flags.borrow();
+        let percent = |count: usize, total: usize| count as f64 * 100.0 / total.max(1) as f64;
+        let line = |name: String, bytes: &[u8]| {
+            let code = bytes
+                .iter()
+                .filter(|f| *f & (OPCODE | OPERAND) != 0)
+                .count();
+            let data = bytes.iter().filter(|f| *f & DATA != 0).count();
+            let used = bytes.iter().filter(|f| **f != 0).count();
+            format!(
+                "{:>5} {:>7.2}% {:>7.2}% {:>7.2}%",
+                name,
+                percent(code, bytes.len()),
+                percent(data, bytes.len()),
+                percent(used, bytes.len())
+            )
+        };
+        let mut lines = vec![format!(
+            "{:>5} {:>8} {:>8} {:>8}",
+            "Bank", "Code", "Data", "Used"
+        )];
+        for (bank, bytes) in flags.chunks(BANK_SIZE).enumerate() {
+            lines.push(line(format!("{:02X}", bank), bytes));
+        }
+        lines.push(line("All".to_string(), &flags));
+        lines.join("\n")
+    }
+
+    /// Writes the coverage and the code/data log next to it
+    pub fn save_to_file(&self, file: &Path) -> Result<(), String> {
+        let mut cdl = file.to_path_buf().into_os_string();
+        cdl.push(".cdl");
+        fs::write(file, self.to_bytes())
+            .and_then(|_| fs::write(cdl, self.cdl()))
+            .map_err(|e| format!("Error writing the coverage {}: {}", file.display(), e))
+    }
+
+    fn save(&self) {
+        if let Some(file) = &self.file {
+            if let Err(err) = self.save_to_file(file) {
+                error!("{}", err);
+            }
+        }
+    }
+}
+
+impl Drop for Coverage {
+    fn drop(&mut self) {
+        self.save();
+    }
+}
+
+#[test]
+fn records_code_and_data() {
+    use crate::dmg::bus::Bus;
+    use crate::dmg::cartridge::cartridge::Cartridge;
+    use crate::dmg::cartridge::rom_only::RomOnly;
+    use crate::dmg::cpu::CPU;
+
+    let cartridge = Cartridge::from_cart(Box::new(RomOnly::new(vec![0; 0x8000])), 0);
+    let mut cpu = CPU::with_bus(Bus::with_cartridge(cartridge));
+    cpu.bus.write_byte(0xFF50, 1);
+    cpu.bus.set_coverage(Some(Coverage::default()));
+    // The rom is all zeroes, ld a, [$0150] has to go in ram
+    for (i, byte) in [0xFA, 0x50, 0x01].iter().enumerate() {
+        cpu.bus.write_byte(0xC000 + i as u16, *byte);
+    }
+    cpu.pc = 0x4000;
+    cpu.step(); // NOP in bank 1
+    cpu.pc = 0xC000;
+    cpu.step();
+    // Reads outside of instructions, like a debugger's, don't count
+    cpu.bus.read_byte(0x0200);
+
+    let flags = cpu.bus.coverage().unwrap().flags();
+    assert_eq!(flags.len(), 0x8000);
+    assert_eq!(flags[0x4000], OPCODE);
+    assert_eq!(flags[0x0150], DATA);
+    assert_eq!(flags.iter().filter(|f| **f != 0).count(), 2);
+    assert_eq!(cpu.bus.coverage().unwrap().cdl()[0x4000], 0x01);
+    assert_eq!(cpu.bus.coverage().unwrap().cdl()[0x0150], 0x02);
+}
+
+#[test]
+fn merges_runs() {
+    let first = Coverage::default();
+    first.begin_instruction(0x100, Some(0x100), 3);
+    first.end_instruction();
+    let mut second = Coverage::from_bytes(&first.to_bytes()).unwrap();
+    let other = Coverage::default();
+    other.begin_instruction(0x200, Some(0x200), 1);
+    other.read(0x300, 0x300);
+    other.end_instruction();
+    second.merge(&other).unwrap();
+
+    let flags = second.flags();
+    assert_eq!(flags[0x100..0x104], [OPCODE, OPERAND, OPERAND, 0]);
+    assert_eq!(flags[0x200], OPCODE);
+    assert_eq!(flags[0x300], DATA);
+    assert!(second
+        .summary()
+        .ends_with("  All    0.02%    0.01%    0.03%"));
+
+    let mut other_rom = Coverage::default();
+    other_rom.rom_checksum = 1;
+    assert!(second.merge(&other_rom).is_err());
+}
+
+#[test]
+fn refuses_truncated_files() {
+    let mut data = Coverage::default().to_bytes();
+    data[14..18].copy_from_slice(&u32::MAX.to_le_bytes());
+    assert_eq!(
+        Coverage::from_bytes(&data).err(),
+        Some("The coverage file is truncated".to_string())
+    );
+}
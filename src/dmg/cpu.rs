@@ -1,13 +1,18 @@
 use log::info;
 
 use crate::dmg::bus::Bus;
+use crate::dmg::busconnection::MemoryBus;
 use crate::dmg::instructions::{
     ArithmeticTarget, Instruction, JumpCond, RestartAddr, SixteenBitArithmeticTarget, StackTarget,
 };
 use crate::dmg::memory::{
     Interrupt, LoadByteSource, LoadByteTarget, LoadType, LoadWordSource, LoadWordTarget,
 };
+use crate::dmg::model::Model;
+use crate::dmg::profiler::Profiler;
 use crate::dmg::registers::Registers;
+use crate::dmg::save_state::{SaveState, StateReader, StateWriter};
+use crate::dmg::trace::Trace;
 
 // Interrupt starting addresses
 const V_BLANK_ISR: u16 = 0x40;
@@ -16,49 +21,88 @@ const TIMER_ISR: u16 = 0x50;
 const SERIAL_ISR: u16 = 0x58;
 const JOYPAD_ISR: u16 = 0x60;
 
-pub struct CPU {
+/// Runs against the Game Boy's `Bus` unless given another memory, e.g. to test instructions
+pub struct CPU<B: MemoryBus = Bus> {
     pub registers: Registers,
     pub pc: u16,
     pub sp: u16,
-    pub bus: Bus,
+    pub bus: B,
     pub is_halted: bool,
     pub ime: bool, // Interrupt Master Enable
+    /// Set whenever `LD B, B` runs, test roms and debuggers use it as a breakpoint
+    pub software_breakpoint: bool,
+    // The clock cycles the bus has been ticked by during the current step
+    cycles: u8,
+    trace: Option<Trace>,
+    profiler: Option<Profiler>,
 }
 
 impl Default for CPU {
     fn default() -> Self {
-        CPU {
-            registers: Registers::default(),
-            bus: Bus::default(),
-            pc: 0,
-            sp: 0xFFFE,
-            is_halted: false,
-            ime: false,
-        }
+        CPU::with_bus(Bus::default())
     }
 }
 
 impl CPU {
     pub fn new(file: &str) -> CPU {
-        CPU {
-            registers: Registers::default(),
-            bus: Bus::new(file),
-            pc: 0,
-            sp: 0xFFFE,
-            is_halted: false,
-            ime: false,
-        }
+        CPU::with_bus(Bus::new(file))
     }
 
     pub fn custom_boot_rom(boot_file: &str, file: &str) -> CPU {
+        CPU::with_bus(Bus::custom_boot_rom(boot_file, file))
+    }
+}
+
+impl<B: MemoryBus> CPU<B> {
+    /// Runs against an already configured bus, e.g. one without a window
+    pub fn with_bus(bus: B) -> CPU<B> {
         CPU {
             registers: Registers::default(),
-            bus: Bus::custom_boot_rom(boot_file, file),
+            bus,
             pc: 0,
             sp: 0xFFFE,
             is_halted: false,
             ime: false,
+            software_breakpoint: false,
+            cycles: 0,
+            trace: None,
+            profiler: None,
+        }
+    }
+
+    /// Disables the boot rom and sets the registers the boot rom leaves behind.
+    /// Games tell they're on a CGB by A being 0x11
+    /// See: https://gbdev.io/pandocs/Power_Up_Sequence.html#cpu-registers
+    pub fn skip_boot_rom(&mut self) {
+        self.bus.write_byte(0xFF50, 0x1);
+        if self.bus.model() == Model::Cgb {
+            self.registers.set_af(0x1180);
+            self.registers.set_bc(0x0000);
+            self.registers.set_de(0xFF56);
+            self.registers.set_hl(0x000D);
+        } else {
+            self.registers.set_af(0x01B0);
+            self.registers.set_bc(0x0013);
+            self.registers.set_de(0x00D8);
+            self.registers.set_hl(0x014D);
         }
+        self.sp = 0xFFFE;
+        self.pc = 0x100;
+    }
+
+    /// Logs every instruction for Gameboy Doctor, which also expects LY to always read 0x90
+    pub fn set_trace(&mut self, trace: Option<Trace>) {
+        self.bus.stub_ly(trace.is_some());
+        self.trace = trace;
+    }
+
+    /// Counts the cycles spent in each function
+    pub fn set_profiler(&mut self, profiler: Option<Profiler>) {
+        self.profiler = profiler;
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
     }
 
     fn set_register_by_target(&mut self, target: &ArithmeticTarget, value: u8) {
@@ -70,7 +114,7 @@ impl CPU {
             ArithmeticTarget::E => self.registers.e = value,
             ArithmeticTarget::H => self.registers.h = value,
             ArithmeticTarget::L => self.registers.l = value,
-            ArithmeticTarget::HLI => self.bus.write_byte(self.registers.get_hl(), value),
+            ArithmeticTarget::HLI => self.write(self.registers.get_hl(), value),
             _ => panic!("target: {:?}, not allowed", target),
         }
         if self.registers.b == 1 && &ArithmeticTarget::B == target {
@@ -78,12 +122,39 @@ impl CPU {
         }
     }
 
-    pub fn step(&mut self) {
-        let mut instruction_byte = self.bus.read_byte(self.pc);
+    /// Executes a single instruction and returns the number of cycles it took.
+    /// Every memory access and internal delay ticks the bus by an M-cycle as it happens,
+    /// so the timer, PPU and DMA see the accesses at the right time within an instruction.
+    pub fn step(&mut self) -> u8 {
+        self.cycles = 0;
+        // Halted, the cpu idles until an interrupt is requested, even with IME off
+        // See: https://gbdev.io/pandocs/halt.html
+        if self.is_halted {
+            self.tick();
+            if !matches!(self.bus.return_interrupt(), Interrupt::NONE) {
+                self.is_halted = false;
+            }
+            // Halted cycles count towards the function that halted
+            if let Some(profiler) = &mut self.profiler {
+                profiler.set_double_speed(self.bus.double_speed());
+                profiler.cycles(self.cycles);
+            }
+            self.handle_interrupts();
+            return self.cycles;
+        }
+        if let Some(mut trace) = self.trace.take() {
+            trace.log_cpu(self);
+            self.trace = Some(trace);
+        }
+        let old_sp = self.sp;
+        self.bus.begin_instruction(self.pc);
+        let mut instruction_byte = self.read(self.pc);
         let prefixed = instruction_byte == 0xCB;
         if instruction_byte == 0xCB {
-            instruction_byte = self.bus.read_byte(self.pc + 1);
+            instruction_byte = self.read(self.pc.wrapping_add(1));
             info!("Prefix: {:#02x}", instruction_byte);
+        } else if instruction_byte == 0x40 {
+            self.software_breakpoint = true;
         }
 
         let (next_pc, cycles) =
@@ -101,29 +172,75 @@ impl CPU {
                 );
                 panic!("Unkown instruction found for: {}", description)
             };
+        // The internal delays that aren't ticked by the instruction itself come at its end
+        debug_assert!(
+            self.cycles <= cycles,
+            "{:#04x} took {} cycles rather than {}",
+            instruction_byte,
+            self.cycles,
+            cycles
+        );
+        while self.cycles < cycles {
+            self.tick();
+        }
+        self.bus.end_instruction();
         let prefix = if prefixed { 1 } else { 0 };
-        self.pc = next_pc + prefix;
-        self.bus.step(cycles);
+        self.pc = next_pc.wrapping_add(prefix);
+        if let Some(profiler) = &mut self.profiler {
+            let pc = (self.bus.bank(self.pc), self.pc);
+            let opcode = if prefixed { 0xCB } else { instruction_byte };
+            profiler.set_double_speed(self.bus.double_speed());
+            profiler.instruction(opcode, cycles, old_sp, self.sp, pc);
+        }
         self.handle_interrupts();
+        self.cycles
+    }
+
+    /// Runs the rest of the hardware for one M-cycle
+    fn tick(&mut self) {
+        self.bus.tick();
+        self.cycles += 4;
+    }
+
+    fn read(&mut self, address: u16) -> u8 {
+        self.tick();
+        self.bus.read_byte(address)
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        self.tick();
+        self.bus.write_byte(address, value);
     }
 
     fn handle_interrupts(&mut self) {
         if self.ime {
-            match self.bus.return_interrupt() {
-                Interrupt::VBlank => self.execute_interrupt(V_BLANK_ISR),
-                Interrupt::LcdStat => self.execute_interrupt(LCD_ISR),
-                Interrupt::TimerOverflow => self.execute_interrupt(TIMER_ISR),
-                Interrupt::SerialLink => self.execute_interrupt(SERIAL_ISR),
-                Interrupt::JoypadPress => self.execute_interrupt(JOYPAD_ISR),
+            let interrupt = self.bus.return_interrupt();
+            match interrupt {
+                Interrupt::VBlank => self.execute_interrupt(V_BLANK_ISR, &interrupt),
+                Interrupt::LcdStat => self.execute_interrupt(LCD_ISR, &interrupt),
+                Interrupt::TimerOverflow => self.execute_interrupt(TIMER_ISR, &interrupt),
+                Interrupt::SerialLink => self.execute_interrupt(SERIAL_ISR, &interrupt),
+                Interrupt::JoypadPress => self.execute_interrupt(JOYPAD_ISR, &interrupt),
                 Interrupt::NONE => { /* Do nothing */ }
             }
         }
     }
 
-    fn execute_interrupt(&mut self, isr: u16) {
+    /// Takes 5 M-cycles, two waiting, the push and one to jump
+    /// See: https://gbdev.io/pandocs/Interrupts.html#interrupt-handling
+    fn execute_interrupt(&mut self, isr: u16, interrupt: &Interrupt) {
+        let start = self.cycles;
+        self.ime = false;
+        self.tick();
         self.push(self.pc);
+        self.tick();
         self.pc = isr;
-        self.bus.interrupt_flag_off();
+        self.bus.interrupt_flag_off(interrupt);
+        if let Some(profiler) = &mut self.profiler {
+            // Like a call, the dispatch counts towards the function that was interrupted
+            profiler.cycles(self.cycles - start);
+            profiler.enter((0, isr), self.sp);
+        }
     }
 
     pub fn execute(&mut self, instruction: Instruction) -> (u16, u8) {
@@ -213,7 +330,7 @@ impl CPU {
             ArithmeticTarget::E => self.registers.e,
             ArithmeticTarget::H => self.registers.h,
             ArithmeticTarget::L => self.registers.l,
-            ArithmeticTarget::HLI => self.bus.read_byte(self.registers.get_hl()),
+            ArithmeticTarget::HLI => self.read(self.registers.get_hl()),
             ArithmeticTarget::D8 => self.read_next_byte(),
         }
     }
@@ -467,8 +584,31 @@ impl CPU {
         true
     }
 
-    fn daa(&self) -> bool {
-        // unimplemented!();
+    // Adjusts A back into binary coded decimal after an add or subtract of two BCD numbers
+    // * - 0 *
+    // See: https://ehaskins.com/2018-01-30%20Z80%20DAA/
+    fn daa(&mut self) -> bool {
+        let mut a = self.registers.a;
+        let mut carry = self.registers.carry();
+        if self.registers.negative() {
+            if carry {
+                a = a.wrapping_sub(0x60);
+            }
+            if self.registers.half_carry() {
+                a = a.wrapping_sub(0x06);
+            }
+        } else {
+            if carry || a > 0x99 {
+                a = a.wrapping_add(0x60);
+                carry = true;
+            }
+            if self.registers.half_carry() || a & 0x0F > 0x09 {
+                a = a.wrapping_add(0x06);
+            }
+        }
+        self.registers.a = a;
+        self.registers
+            .set_flags(a == 0, self.registers.negative(), false, carry);
         true
     }
 
@@ -601,13 +741,12 @@ impl CPU {
     // - - - -
     fn jump(&mut self, condition: JumpCond, cycles: u8, cond_cycle: u8) -> (bool, u8) {
         let should_jump = self.should_jump(condition);
+        // The address is read whether or not the jump is taken
+        let address = self.read_next_word();
         if should_jump {
-            let least_sig = self.bus.read_byte(self.pc.wrapping_add(1)) as u16;
-            let most_sig = self.bus.read_byte(self.pc.wrapping_add(2)) as u16;
-            self.pc = (most_sig << 8) | least_sig;
+            self.pc = address;
             (false, cond_cycle)
         } else {
-            self.pc = self.pc.wrapping_add(2);
             (true, cycles)
         }
     }
@@ -625,7 +764,7 @@ impl CPU {
                 self.pc = self.pc.wrapping_add(jump_addr + 1);
                 info!("jumping to {:#02x}, from: {:#02x}", self.pc, old_pc);
             } else {
-                let jump_addr = next_byte.abs() as u16;
+                let jump_addr = next_byte.unsigned_abs() as u16;
                 info!("next byte is dec {:#02x}", next_byte);
                 self.pc = self.pc.wrapping_sub(jump_addr.wrapping_sub(1));
                 info!("jumping to {:#02x}, from: {:#02x}", self.pc, old_pc);
@@ -657,17 +796,17 @@ impl CPU {
             LoadByteTarget::E => self.registers.e = source_value,
             LoadByteTarget::H => self.registers.h = source_value,
             LoadByteTarget::L => self.registers.l = source_value,
-            LoadByteTarget::HLI => self.bus.write_byte(self.registers.get_hl(), source_value),
+            LoadByteTarget::HLI => self.write(self.registers.get_hl(), source_value),
             // I think the only possible source value here comes from register a.
-            LoadByteTarget::BCI => self.bus.write_byte(self.registers.get_bc(), source_value), // write to the location in memory stored at the address stored in this register
-            LoadByteTarget::DEI => self.bus.write_byte(self.registers.get_de(), source_value),
+            LoadByteTarget::BCI => self.write(self.registers.get_bc(), source_value), // write to the location in memory stored at the address stored in this register
+            LoadByteTarget::DEI => self.write(self.registers.get_de(), source_value),
             LoadByteTarget::HLINC => {
-                self.bus.write_byte(self.registers.get_hl(), source_value);
+                self.write(self.registers.get_hl(), source_value);
                 self.registers
                     .set_hl(self.registers.get_hl().wrapping_add(1));
             }
             LoadByteTarget::HLDEC => {
-                self.bus.write_byte(self.registers.get_hl(), source_value);
+                self.write(self.registers.get_hl(), source_value);
                 self.registers
                     .set_hl(self.registers.get_hl().wrapping_sub(1));
             }
@@ -696,10 +835,10 @@ impl CPU {
             LoadByteSource::L => self.registers.l,
             LoadByteSource::D8 => self.read_next_byte(),
             LoadByteSource::HLI | LoadByteSource::HLINC | LoadByteSource::HLDEC => {
-                self.bus.read_byte(self.registers.get_hl())
+                self.read(self.registers.get_hl())
             }
-            LoadByteSource::BCI => self.bus.read_byte(self.registers.get_bc()),
-            LoadByteSource::DEI => self.bus.read_byte(self.registers.get_de()),
+            LoadByteSource::BCI => self.read(self.registers.get_bc()),
+            LoadByteSource::DEI => self.read(self.registers.get_de()),
         }
     }
 
@@ -712,7 +851,8 @@ impl CPU {
             LoadWordTarget::SP => self.sp = source_value,
             LoadWordTarget::D16 => {
                 let addr = self.read_next_word();
-                self.bus.write_word(addr, self.sp);
+                self.write(addr, self.sp as u8);
+                self.write(addr.wrapping_add(1), (self.sp >> 8) as u8);
             }
         }
     }
@@ -733,7 +873,7 @@ impl CPU {
     // TODO: Maybe rename this something better
     fn load_a_into_next_byte(&mut self) -> bool {
         let addr = self.read_next_word();
-        self.bus.write_byte(addr, self.registers.a);
+        self.write(addr, self.registers.a);
         true
     }
 
@@ -741,14 +881,14 @@ impl CPU {
     // - - - -
     fn load_byte_at_next_address_into_a(&mut self) -> bool {
         let addr = self.read_next_word();
-        self.registers.a = self.bus.read_byte(addr);
+        self.registers.a = self.read(addr);
         true
     }
 
     // A = mem[0xff00 + C]
     // - - - -
     fn ldac(&mut self) -> bool {
-        let value = self.bus.read_byte(0xFF00 + self.registers.c as u16);
+        let value = self.read(0xFF00 + self.registers.c as u16);
         self.registers.a = value;
         true
     }
@@ -756,8 +896,7 @@ impl CPU {
     // mem[0xff00 + C] = A
     // - - - -
     fn ldca(&mut self) -> bool {
-        self.bus
-            .write_byte(0xFF00 + self.registers.c as u16, self.registers.a);
+        self.write(0xFF00 + self.registers.c as u16, self.registers.a);
         true
     }
 
@@ -790,12 +929,14 @@ impl CPU {
     }
 
     // (SP-1) = ssh, (SP-2) = ssl, SP = SP-2
+    // An internal delay while SP is decremented comes before the writes
     // - - - -
     fn push(&mut self, value: u16) -> bool {
+        self.tick();
         self.sp = self.sp.wrapping_sub(1);
-        self.bus.write_byte(self.sp, ((value & 0xFF00) >> 8) as u8);
+        self.write(self.sp, ((value & 0xFF00) >> 8) as u8);
         self.sp = self.sp.wrapping_sub(1);
-        self.bus.write_byte(self.sp, (value & 0xFF) as u8);
+        self.write(self.sp, (value & 0xFF) as u8);
         true
     }
 
@@ -812,10 +953,10 @@ impl CPU {
 
     // ddl == (SP), ddh = (SP+1), SP = SP+2
     fn pop(&mut self) -> u16 {
-        let lsb = self.bus.read_byte(self.sp) as u16;
+        let lsb = self.read(self.sp) as u16;
         self.sp = self.sp.wrapping_add(1);
 
-        let msb = self.bus.read_byte(self.sp) as u16;
+        let msb = self.read(self.sp) as u16;
         self.sp = self.sp.wrapping_add(1);
 
         (msb << 8) | lsb
@@ -825,15 +966,15 @@ impl CPU {
     /// - - - -
     fn call(&mut self, condition: JumpCond, cycles: u8, cond_cycle: u8) -> (bool, u8) {
         let should_jump = self.should_jump(condition);
-        let next_pc = self.pc.wrapping_add(3);
+        let address = self.read_next_word();
+        let next_pc = self.pc.wrapping_add(1);
         if should_jump {
             self.push(next_pc);
-            self.pc = self.bus.read_word(self.pc.wrapping_add(1));
+            self.pc = address;
             info!("Calling {:#02x}", self.pc);
             (false, cond_cycle)
         } else {
             self.pc = next_pc;
-            info!("Calling {:#02x}", self.pc);
             (false, cycles)
         }
     }
@@ -841,6 +982,10 @@ impl CPU {
     /// Pops the stack and jumps to that address
     /// - - - -
     fn ret(&mut self, condition: JumpCond, cycles: u8, cond_cycle: u8) -> (bool, u8) {
+        // Checking the condition takes an M-cycle of its own
+        if condition != JumpCond::Always {
+            self.tick();
+        }
         if self.should_jump(condition) {
             self.pc = self.pop();
             info!("RET to {:#02x}", self.pc);
@@ -851,7 +996,7 @@ impl CPU {
     }
 
     fn reti(&mut self) -> bool {
-        self.ime = false;
+        self.ime = true;
         self.pc = self.pop();
         true
     }
@@ -882,7 +1027,7 @@ impl CPU {
     // - - - -
     fn ldha(&mut self) -> bool {
         let n = self.read_next_byte();
-        self.bus.write_byte(0xFF00 + n as u16, self.registers.a);
+        self.write(0xFF00 + n as u16, self.registers.a);
         true
     }
 
@@ -890,26 +1035,173 @@ impl CPU {
     // - - - -
     fn ldha8(&mut self) -> bool {
         let n = self.read_next_byte();
-        self.registers.a = self.bus.read_byte(0xFF00 + n as u16);
+        self.registers.a = self.read(0xFF00 + n as u16);
         info!("A is now {:#02x}", self.registers.a);
         true
     }
 
+    // TODO: Stop the cpu and LCD until a button is pressed
     fn stop(&mut self) -> bool {
-        // TODO: We'll need to impl this op to continue
-        // unimplemented!();
+        self.bus.switch_speed();
         true
     }
 
     fn read_next_byte(&mut self) -> u8 {
         self.pc = self.pc.wrapping_add(1);
-        self.bus.read_byte(self.pc)
+        self.read(self.pc)
     }
 
     fn read_next_word(&mut self) -> u16 {
-        self.pc = self.pc.wrapping_add(1);
-        let word = self.bus.read_word(self.pc);
-        self.pc = self.pc.wrapping_add(1); // We read two bytes so we need to increment one more;
-        word
+        let low = self.read_next_byte() as u16;
+        let high = self.read_next_byte() as u16;
+        (high << 8) | low
     }
 }
+
+impl SaveState for CPU {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.registers.save_state(state);
+        state.u16(self.pc);
+        state.u16(self.sp);
+        state.bool(self.is_halted);
+        state.bool(self.ime);
+        self.bus.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.registers.load_state(state)?;
+        self.pc = state.u16()?;
+        self.sp = state.u16()?;
+        self.is_halted = state.bool()?;
+        self.ime = state.bool()?;
+        self.bus.load_state(state)
+    }
+}
+
+#[test]
+fn ticks_every_cycle() {
+    use crate::dmg::flat_bus::FlatBus;
+    use crate::dmg::instructions::{OPERATION_CYCLES, PREFIXED_OPERATION_CYCLES};
+
+    for prefixed in [false, true] {
+        for opcode in 0..=0xFFu8 {
+            if !prefixed && opcode == 0xCB {
+                continue;
+            }
+            let instruction = match Instruction::from_byte(opcode, prefixed) {
+                Some(instruction) => instruction,
+                None => continue,
+            };
+            // The cycles with the condition failing and passing
+            let allowed = match instruction {
+                Instruction::JP(_, cycles, taken)
+                | Instruction::JR(_, cycles, taken)
+                | Instruction::CALL(_, cycles, taken)
+                | Instruction::RET(_, cycles, taken) => vec![cycles, taken],
+                _ if prefixed => vec![PREFIXED_OPERATION_CYCLES[opcode as usize]],
+                _ => vec![OPERATION_CYCLES[opcode as usize]],
+            };
+            for flags in [0x00, 0xF0] {
+                let mut bus = FlatBus::default();
+                let program = if prefixed {
+                    vec![0xCB, opcode]
+                } else {
+                    vec![opcode]
+                };
+                bus.load(0xC000, &program);
+                let mut cpu = CPU::with_bus(bus);
+                cpu.pc = 0xC000;
+                cpu.sp = 0xD000;
+                cpu.registers.set_af(flags);
+                let cycles = cpu.step();
+                let name = format!("{}{:02x}", if prefixed { "cb " } else { "" }, opcode);
+                assert!(allowed.contains(&cycles), "{} took {} cycles", name, cycles);
+                assert_eq!(cpu.bus.ticks() * 4, cycles as u64, "{}", name);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+#[derive(Default)]
+struct TimedBus {
+    memory: crate::dmg::flat_bus::FlatBus,
+    // The M-cycle of each write, and the address written
+    writes: Vec<(u64, u16)>,
+}
+
+#[cfg(test)]
+impl crate::dmg::busconnection::BusConnection for TimedBus {
+    fn read_byte(&self, address: u16) -> u8 {
+        self.memory.read_byte(address)
+    }
+
+    fn write_byte(&mut self, address: u16, value: u8) {
+        self.writes.push((self.memory.ticks(), address));
+        self.memory.write_byte(address, value);
+    }
+}
+
+#[cfg(test)]
+impl MemoryBus for TimedBus {
+    fn tick(&mut self) {
+        self.memory.tick();
+    }
+}
+
+#[test]
+fn accesses_happen_mid_instruction() {
+    use crate::dmg::busconnection::BusConnection;
+
+    let mut cpu = CPU::with_bus(TimedBus::default());
+    // CALL $C100: the opcode, the address, a delay, then the return address is pushed
+    cpu.bus.memory.load(0xC000, &[0xCD, 0x00, 0xC1]);
+    cpu.pc = 0xC000;
+    cpu.sp = 0xD000;
+    assert_eq!(cpu.step(), 24);
+    assert_eq!(cpu.bus.writes, [(5, 0xCFFF), (6, 0xCFFE)]);
+    assert_eq!(cpu.pc, 0xC100);
+    assert_eq!(cpu.bus.read_byte(0xCFFE), 0x03);
+}
+
+#[test]
+fn halt_waits_for_an_interrupt() {
+    let mut cpu = CPU::default();
+    cpu.skip_boot_rom();
+    cpu.bus.write_byte(0xC000, 0x76); // HALT
+    cpu.bus.write_byte(0xFFFF, 0x04);
+    cpu.pc = 0xC000;
+    cpu.step();
+    assert!(cpu.is_halted);
+    assert_eq!(cpu.step(), 4);
+    assert!(cpu.is_halted);
+    assert_eq!(cpu.pc, 0xC001);
+
+    // Requesting an enabled interrupt wakes the cpu, without IME it carries on after the HALT
+    cpu.bus.write_byte(0xFF0F, 0x04);
+    cpu.step();
+    assert!(!cpu.is_halted);
+    assert_eq!(cpu.pc, 0xC001);
+}
+
+#[test]
+fn stop_switches_cgb_speed() {
+    use crate::dmg::model::Model;
+
+    let mut cpu = CPU::default();
+    cpu.bus.set_model(Model::Cgb);
+    cpu.skip_boot_rom();
+    assert_eq!(
+        cpu.registers.a, 0x11,
+        "Games look for 0x11 in A to tell it's a CGB"
+    );
+    cpu.bus.write_byte(0xC000, 0x10); // STOP
+    cpu.bus.write_byte(0xFF4D, 0x01);
+    cpu.pc = 0xC000;
+    cpu.step();
+    assert_eq!(
+        cpu.bus.read_byte(0xFF4D),
+        0xFE,
+        "The cpu is in double speed"
+    );
+}
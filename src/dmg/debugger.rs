@@ -0,0 +1,594 @@
+use crate::dmg::cpu::CPU;
+use crate::dmg::disasm::{disassemble, parse_hex, Disassembly};
+use crate::dmg::registers::FlagsRegister;
+use crate::dmg::symbols::Symbols;
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, Write};
+
+const HELP: &str = "\
+step [n]              Executes n instructions, 1 by default
+next                  Like step, but runs calls and restarts until they return
+continue              Runs until a breakpoint or watchpoint is hit
+break <addr>          Stops before executing the instruction at addr
+watch <addr>          Stops after addr is written
+rwatch <addr>         Stops after addr is read
+awatch <addr>         Stops after addr is read or written
+delete <addr>         Removes the breakpoints and watchpoints at addr
+info                  Lists the breakpoints and watchpoints
+regs                  Shows the registers and flags
+x <addr> [len]        Dumps len bytes of memory, 64 by default
+list [addr]           Disassembles around addr, the PC by default
+set <reg> <value>     Sets a register, e.g. set hl c000
+set <addr> <value>    Writes a byte to memory
+quit                  Exits
+Addresses and values are hex, with an optional $ or 0x prefix. Counts are decimal.
+Addresses can also be labels from the symbols, or bank:addr to only break in that bank.
+An empty line repeats the last command.";
+
+/// The kinds of memory access a watchpoint can stop on
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl Access {
+    fn covers(self, access: Access) -> bool {
+        self == Access::ReadWrite || self == access
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WatchHit {
+    pub address: u16,
+    pub access: Access,
+}
+
+/// Addresses being watched, checked by the bus on every access.
+/// Only the first hit is kept until it's taken.
+#[derive(Default)]
+pub struct Watchpoints {
+    watched: Vec<(u16, Access)>,
+    hit: Cell<Option<WatchHit>>,
+}
+
+impl Watchpoints {
+    pub fn add(&mut self, address: u16, access: Access) {
+        self.remove(address);
+        self.watched.push((address, access));
+    }
+
+    pub fn remove(&mut self, address: u16) -> bool {
+        let count = self.watched.len();
+        self.watched.retain(|(watched, _)| *watched != address);
+        self.watched.len() != count
+    }
+
+    pub fn list(&self) -> &[(u16, Access)] {
+        &self.watched
+    }
+
+    pub fn check(&self, address: u16, access: Access) {
+        if self.watched.is_empty() || self.hit.get().is_some() {
+            return;
+        }
+        let mut watched = self.watched.iter();
+        if watched.any(|(watched, kind)| *watched == address && kind.covers(access)) {
+            self.hit.set(Some(WatchHit { address, access }));
+        }
+    }
+
+    pub fn take_hit(&self) -> Option<WatchHit> {
+        self.hit.take()
+    }
+}
+
+enum Stop {
+    Steps,
+    Returned,
+    Breakpoint,
+    Watchpoint(WatchHit),
+}
+
+/// A command line debugger. Breakpoints are checked between instructions and
+/// watchpoints only observe the bus, so neither changes the emulated timing.
+pub struct Debugger {
+    pub cpu: CPU,
+    // The bank a breakpoint is in, if it's only for one of them
+    breakpoints: BTreeMap<u16, Option<u16>>,
+    symbols: Symbols,
+}
+
+impl Debugger {
+    pub fn new(cpu: CPU) -> Debugger {
+        Debugger {
+            cpu,
+            breakpoints: BTreeMap::new(),
+            symbols: Symbols::default(),
+        }
+    }
+
+    pub fn set_symbols(&mut self, symbols: Symbols) {
+        self.symbols = symbols;
+    }
+
+    /// Reads commands from stdin until `quit` or the end of input
+    pub fn repl(&mut self) {
+        println!("Type help for a list of commands");
+        println!("{}", self.current_line());
+        let stdin = io::stdin();
+        let mut last = String::new();
+        loop {
+            print!("(oxide_boy) ");
+            io::stdout().flush().unwrap();
+            let mut line = String::new();
+            if stdin.lock().read_line(&mut line).unwrap_or(0) == 0 {
+                break;
+            }
+            let line = match line.trim() {
+                "" => last.clone(),
+                line => line.to_string(),
+            };
+            if line == "quit" || line == "q" {
+                break;
+            }
+            match self.command(&line) {
+                Ok(output) => println!("{}", output),
+                Err(err) => println!("{}", err),
+            }
+            last = line;
+        }
+    }
+
+    /// Runs a single command and returns what it prints
+    pub fn command(&mut self, line: &str) -> Result<String, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let argument = |i: usize| {
+            words
+                .get(i)
+                .copied()
+                .ok_or(format!("{} needs more arguments", words[0]))
+        };
+        match words.first().copied().unwrap_or("") {
+            "help" | "h" => Ok(HELP.to_string()),
+            "step" | "s" => {
+                let count = match words.get(1) {
+                    Some(count) => count
+                        .parse::<u64>()
+                        .map_err(|_| format!("Not a count: {}", count))?,
+                    None => 1,
+                };
+                let stop = self.run(count.max(1), None);
+                Ok(self.stopped(stop))
+            }
+            "next" | "n" => {
+                let stop = if self.at_call() {
+                    let return_to = self
+                        .cpu
+                        .pc
+                        .wrapping_add(self.disassemble(self.cpu.pc).length());
+                    self.run(u64::MAX, Some((return_to, self.cpu.sp)))
+                } else {
+                    self.run(1, None)
+                };
+                Ok(self.stopped(stop))
+            }
+            "continue" | "c" => {
+                let stop = self.run(u64::MAX, None);
+                Ok(self.stopped(stop))
+            }
+            "break" | "b" => {
+                let (address, bank) = self.location(argument(1)?)?;
+                self.breakpoints.insert(address, bank);
+                Ok(format!(
+                    "Breakpoint at {}",
+                    self.breakpoint_name(address, bank)
+                ))
+            }
+            "watch" | "rwatch" | "awatch" => {
+                let address = self.address(argument(1)?)?;
+                let access = match words[0] {
+                    "watch" => Access::Write,
+                    "rwatch" => Access::Read,
+                    _ => Access::ReadWrite,
+                };
+                self.cpu.bus.watchpoints_mut().add(address, access);
+                Ok(format!(
+                    "Watchpoint on {:?} of {}",
+                    access,
+                    self.name(address)
+                ))
+            }
+            "delete" | "d" => {
+                let address = self.address(argument(1)?)?;
+                let breakpoint = self.breakpoints.remove(&address).is_some();
+                let watchpoint = self.cpu.bus.watchpoints_mut().remove(address);
+                if breakpoint || watchpoint {
+                    Ok(format!("Deleted {}", self.name(address)))
+                } else {
+                    Err(format!("Nothing set at {}", self.name(address)))
+                }
+            }
+            "info" | "i" => Ok(self.info()),
+            "regs" | "r" => Ok(self.registers()),
+            "x" => {
+                let address = self.address(argument(1)?)?;
+                let length = match words.get(2) {
+                    Some(length) => length
+                        .parse::<u16>()
+                        .map_err(|_| format!("Not a length: {}", length))?,
+                    None => 64,
+                };
+                Ok(self.dump(address, length))
+            }
+            "list" | "l" => {
+                let address = match words.get(1) {
+                    Some(address) => self.address(address)?,
+                    None => self.cpu.pc,
+                };
+                Ok(self.list(address, 10))
+            }
+            "set" => self.set(argument(1)?, parse_hex(argument(2)?)?),
+            command => Err(format!("Unknown command: {}, try help", command)),
+        }
+    }
+
+    /// Steps until `steps` instructions ran, the cpu returned to `return_to` with the stack
+    /// back where it was, or a breakpoint or watchpoint was hit
+    fn run(&mut self, steps: u64, return_to: Option<(u16, u16)>) -> Stop {
+        for step in 1..=steps {
+            self.cpu.bus.watchpoints().take_hit();
+            self.cpu.step();
+            if let Some(hit) = self.cpu.bus.watchpoints().take_hit() {
+                return Stop::Watchpoint(hit);
+            }
+            if let Some((pc, sp)) = return_to {
+                if self.cpu.pc == pc && self.cpu.sp >= sp {
+                    return Stop::Returned;
+                }
+            }
+            if step == steps {
+                break;
+            }
+            if self.at_breakpoint() {
+                return Stop::Breakpoint;
+            }
+        }
+        Stop::Steps
+    }
+
+    fn at_breakpoint(&self) -> bool {
+        let pc = self.cpu.pc;
+        match self.breakpoints.get(&pc) {
+            Some(bank) => bank.is_none_or(|bank| bank == self.cpu.bus.bank(pc)),
+            None => false,
+        }
+    }
+
+    /// Parses a label, `bank:addr` or an address, returning the bank if it has to match.
+    /// Labels are tried first, a hex number that is also a label can be written with a `$`.
+    fn location(&self, text: &str) -> Result<(u16, Option<u16>), String> {
+        if let Some((bank, address)) = self.symbols.lookup(text) {
+            let banked = matches!(address, 0x4000..=0x7FFF | 0xA000..=0xBFFF);
+            return Ok((address, if banked { Some(bank) } else { None }));
+        }
+        if let Some((bank, address)) = text.split_once(':') {
+            return Ok((parse_hex(address)?, Some(parse_hex(bank)?)));
+        }
+        Ok((parse_hex(text)?, None))
+    }
+
+    fn address(&self, text: &str) -> Result<u16, String> {
+        self.location(text).map(|(address, _)| address)
+    }
+
+    fn label(&self, address: u16) -> Option<&str> {
+        self.symbols.label(self.cpu.bus.bank(address), address)
+    }
+
+    /// The address, followed by its label if it has one
+    fn name(&self, address: u16) -> String {
+        match self.label(address) {
+            Some(label) => format!("${:04X} ({})", address, label),
+            None => format!("${:04X}", address),
+        }
+    }
+
+    fn breakpoint_name(&self, address: u16, bank: Option<u16>) -> String {
+        let label = match bank {
+            Some(bank) => self.symbols.label(bank, address),
+            None => self.label(address),
+        };
+        let mut name = format!("${:04X}", address);
+        if let Some(label) = label {
+            name += &format!(" ({})", label);
+        }
+        if let Some(bank) = bank {
+            name += &format!(" in bank {}", bank);
+        }
+        name
+    }
+
+    /// Disassembles the instruction with the labels of the banks switched in
+    fn disassemble(&self, address: u16) -> Disassembly {
+        let mut instruction = disassemble(&self.cpu.bus, address);
+        if let Some(label) = instruction.operand.and_then(|operand| self.label(operand)) {
+            instruction.name_operand(label);
+        }
+        instruction
+    }
+
+    // Labels get a line of their own above the instruction, like in source
+    fn labelled(&self, marker: &str, address: u16) -> String {
+        let instruction = self.disassemble(address);
+        match self.label(address) {
+            Some(label) => format!("{}:\n{} {}", label, marker, instruction),
+            None => format!("{} {}", marker, instruction),
+        }
+    }
+
+    fn stopped(&self, stop: Stop) -> String {
+        let line = self.current_line();
+        match stop {
+            Stop::Steps | Stop::Returned => line,
+            Stop::Breakpoint => format!("Breakpoint at {}\n{}", self.name(self.cpu.pc), line),
+            Stop::Watchpoint(hit) => format!(
+                "Watchpoint: {} {}, which is now ${:02X}\n{}",
+                if hit.access == Access::Read {
+                    "read"
+                } else {
+                    "wrote"
+                },
+                self.name(hit.address),
+                self.cpu.bus.read_byte(hit.address),
+                line
+            ),
+        }
+    }
+
+    fn current_line(&self) -> String {
+        self.labelled("=>", self.cpu.pc)
+    }
+
+    fn at_call(&self) -> bool {
+        let opcode = self.cpu.bus.read_byte(self.cpu.pc);
+        matches!(opcode, 0xC4 | 0xCC | 0xCD | 0xD4 | 0xDC) || opcode & 0xC7 == 0xC7
+    }
+
+    fn info(&self) -> String {
+        let mut lines = vec![];
+        for (address, bank) in &self.breakpoints {
+            lines.push(format!(
+                "Breakpoint at {}",
+                self.breakpoint_name(*address, *bank)
+            ));
+        }
+        for (address, access) in self.cpu.bus.watchpoints().list() {
+            lines.push(format!(
+                "Watchpoint on {:?} of {}",
+                access,
+                self.name(*address)
+            ));
+        }
+        if lines.is_empty() {
+            lines.push("No breakpoints or watchpoints".to_string());
+        }
+        lines.join("\n")
+    }
+
+    fn registers(&self) -> String {
+        let registers = &self.cpu.registers;
+        let flag = |set: bool, name: char| if set { name } else { '-' };
+        format!(
+            "AF=${:04X} BC=${:04X} DE=${:04X} HL=${:04X} SP=${:04X} PC=${:04X}\nFlags: {}{}{}{} IME: {} Halted: {}",
+            registers.get_af(),
+            registers.get_bc(),
+            registers.get_de(),
+            registers.get_hl(),
+            self.cpu.sp,
+            self.cpu.pc,
+            flag(registers.zero(), 'Z'),
+            flag(registers.negative(), 'N'),
+            flag(registers.half_carry(), 'H'),
+            flag(registers.carry(), 'C'),
+            self.cpu.ime,
+            self.cpu.is_halted
+        )
+    }
+
+    fn dump(&self, address: u16, length: u16) -> String {
+        let mut lines = vec![];
+        for row in (0..length).step_by(16) {
+            let start = address.wrapping_add(row);
+            let bytes: Vec<String> = (0..16.min(length - row))
+                .map(|i| format!("{:02X}", self.cpu.bus.read_byte(start.wrapping_add(i))))
+                .collect();
+            lines.push(format!("${:04X}: {}", start, bytes.join(" ")));
+        }
+        lines.join("\n")
+    }
+
+    // A few instructions before the address are guessed at, as we can't decode backwards
+    fn list(&self, address: u16, count: usize) -> String {
+        let mut address = address.saturating_sub(4);
+        let mut lines = vec![];
+        for _ in 0..count {
+            let marker = if address == self.cpu.pc { "=>" } else { "  " };
+            lines.push(self.labelled(marker, address));
+            address = address.wrapping_add(self.disassemble(address).length());
+        }
+        lines.join("\n")
+    }
+
+    fn set(&mut self, target: &str, value: u16) -> Result<String, String> {
+        let registers = &mut self.cpu.registers;
+        let byte = value as u8;
+        match target.to_lowercase().as_str() {
+            "a" => registers.a = byte,
+            "b" => registers.b = byte,
+            "c" => registers.c = byte,
+            "d" => registers.d = byte,
+            "e" => registers.e = byte,
+            "f" => registers.f = FlagsRegister::from(byte),
+            "h" => registers.h = byte,
+            "l" => registers.l = byte,
+            "af" => registers.set_af(value),
+            "bc" => registers.set_bc(value),
+            "de" => registers.set_de(value),
+            "hl" => registers.set_hl(value),
+            "sp" => self.cpu.sp = value,
+            "pc" => self.cpu.pc = value,
+            address => {
+                let address = self.address(address)?;
+                self.cpu.bus.write_byte(address, byte);
+                return Ok(format!("${:04X} = ${:02X}", address, byte));
+            }
+        }
+        Ok(self.registers())
+    }
+}
+
+#[cfg(test)]
+fn debugger(program: &[u8]) -> Debugger {
+    let mut cpu = CPU::default();
+    for (i, byte) in program.iter().enumerate() {
+        cpu.bus.write_byte(0xC000 + i as u16, *byte);
+    }
+    cpu.pc = 0xC000;
+    Debugger::new(cpu)
+}
+
+#[test]
+fn breakpoints_and_stepping() {
+    let mut debugger = debugger(&[
+        0x3E, 0x42, //       LD A, $42
+        0x06, 0x01, //       LD B, $01
+        0x04, //             INC B
+        0x18, 0xFD, //       JR -3
+    ]);
+    debugger.command("step").unwrap();
+    assert_eq!(debugger.cpu.registers.a, 0x42);
+    assert_eq!(debugger.cpu.pc, 0xC002);
+
+    debugger.command("break c004").unwrap();
+    let output = debugger.command("continue").unwrap();
+    assert!(output.starts_with("Breakpoint at $C004"), "{}", output);
+    debugger.command("c").unwrap();
+    debugger.command("c").unwrap();
+    assert_eq!(debugger.cpu.registers.b, 3);
+
+    debugger.command("delete $C004").unwrap();
+    debugger.command("step 4").unwrap();
+    assert_eq!(debugger.cpu.registers.b, 5);
+}
+
+#[test]
+fn watchpoints() {
+    let mut debugger = debugger(&[
+        0x21, 0x00, 0xD0, // LD HL, $D000
+        0x00, //             NOP
+        0x7E, //             LD A, (HL)
+        0x34, //             INC (HL)
+        0x18, 0xFE, //       JR -2
+    ]);
+    debugger.command("rwatch d000").unwrap();
+    let output = debugger.command("c").unwrap();
+    assert!(output.starts_with("Watchpoint: read $D000"), "{}", output);
+    assert_eq!(debugger.cpu.pc, 0xC005);
+
+    debugger.command("watch d000").unwrap();
+    let output = debugger.command("c").unwrap();
+    assert!(
+        output.starts_with("Watchpoint: wrote $D000, which is now $01"),
+        "{}",
+        output
+    );
+    assert_eq!(
+        debugger.command("info").unwrap(),
+        "Watchpoint on Write of $D000"
+    );
+}
+
+#[test]
+fn next_runs_calls() {
+    let mut debugger = debugger(&[
+        0x31, 0x00, 0xD0, // LD SP, $D000
+        0xCD, 0x08, 0xC0, // CALL $C008
+        0x00, //             NOP
+        0x00, //             NOP
+        0x04, //             INC B
+        0x04, //             INC B
+        0xC9, //             RET
+    ]);
+    debugger.command("s").unwrap();
+    debugger.command("next").unwrap();
+    assert_eq!(debugger.cpu.pc, 0xC006);
+    assert_eq!(debugger.cpu.registers.b, 2);
+}
+
+#[test]
+fn next_wraps_around_memory() {
+    let mut debugger = debugger(&[0xC9]); // RET
+    // CALL $C000 at the very end of memory, its high byte is IE
+    debugger.cpu.bus.write_byte(0xFFFD, 0xCD);
+    debugger.cpu.bus.write_byte(0xFFFE, 0x00);
+    debugger.cpu.bus.write_byte(0xFFFF, 0xC0);
+    debugger.cpu.sp = 0xD000;
+    debugger.cpu.pc = 0xFFFD;
+    debugger.command("next").unwrap();
+    assert_eq!(debugger.cpu.pc, 0x0000);
+}
+
+#[test]
+fn registers_and_memory() {
+    let mut debugger = debugger(&[0x00]);
+    debugger.command("set hl 0xC0DE").unwrap();
+    debugger.command("set f f0").unwrap();
+    assert_eq!(debugger.cpu.registers.get_hl(), 0xC0DE);
+    let registers = debugger.command("regs").unwrap();
+    assert!(registers.contains("HL=$C0DE"), "{}", registers);
+    assert!(registers.contains("Flags: ZNHC"), "{}", registers);
+
+    debugger.command("set d000 99").unwrap();
+    assert_eq!(debugger.command("x d000 2").unwrap(), "$D000: 99 00");
+    assert!(debugger.command("list").unwrap().contains("=> $C000: 00"));
+    assert!(debugger.command("bogus").is_err());
+}
+
+#[test]
+fn labels_from_symbols() {
+    let mut debugger = debugger(&[
+        0x00, //             NOP
+        0x00, //             NOP
+        0x04, //             INC B
+        0x18, 0xFD, //       JR -3
+    ]);
+    debugger.set_symbols(Symbols::parse("00:c002 Loop\n02:4000 Far\n01:4000 Near"));
+    assert_eq!(
+        debugger.command("break Loop").unwrap(),
+        "Breakpoint at $C002 (Loop)"
+    );
+    let output = debugger.command("c").unwrap();
+    assert!(
+        output.starts_with("Breakpoint at $C002 (Loop)\nLoop:\n=> $C002: 04"),
+        "{}",
+        output
+    );
+    assert!(debugger.command("list c003").unwrap().contains("jr Loop"));
+
+    // Only bank 1 is switched in at 0x4000
+    assert_eq!(
+        debugger.command("break Far").unwrap(),
+        "Breakpoint at $4000 (Far) in bank 2"
+    );
+    debugger.cpu.pc = 0x4000;
+    assert!(!debugger.at_breakpoint());
+    debugger.command("break 1:4000").unwrap();
+    assert!(debugger.at_breakpoint());
+    assert_eq!(
+        debugger.command("info").unwrap(),
+        "Breakpoint at $4000 (Near) in bank 1\nBreakpoint at $C002 (Loop)"
+    );
+}
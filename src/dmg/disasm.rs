@@ -0,0 +1,373 @@
+use crate::dmg::busconnection::BusConnection;
+use crate::dmg::instructions::{conditional_cycle, OPERATION_CYCLES, PREFIXED_OPERATION_CYCLES};
+use crate::dmg::symbols::Symbols;
+use std::fmt;
+
+// Operand names, indexed by the bits of the opcode that select them.
+// See: https://gbdev.io/gb-opcodes/optables/
+const R8: [&str; 8] = ["b", "c", "d", "e", "h", "l", "[hl]", "a"];
+const R16: [&str; 4] = ["bc", "de", "hl", "sp"];
+const R16_STACK: [&str; 4] = ["bc", "de", "hl", "af"];
+const R16_MEMORY: [&str; 4] = ["[bc]", "[de]", "[hl+]", "[hl-]"];
+const CONDITIONS: [&str; 4] = ["nz", "z", "nc", "c"];
+const ALU: [&str; 8] = ["add", "adc", "sub", "sbc", "and", "xor", "or", "cp"];
+const ACCUMULATOR: [&str; 8] = ["rlca", "rrca", "rla", "rra", "daa", "cpl", "scf", "ccf"];
+const SHIFTS: [&str; 8] = ["rlc", "rrc", "rl", "rr", "sla", "sra", "swap", "srl"];
+
+/// One decoded instruction
+#[derive(Debug, PartialEq)]
+pub struct Disassembly {
+    pub address: u16,
+    pub bytes: Vec<u8>,
+    /// In RGBDS syntax, e.g. `ld a, [hl+]`
+    pub text: String,
+    /// The cycles taken, or for conditional branches the cycles when not taken
+    pub cycles: u8,
+    /// The cycles when a conditional branch is taken
+    pub branch_cycles: Option<u8>,
+    /// The address or 16 bit value the instruction refers to, which a label can stand in for
+    pub operand: Option<u16>,
+}
+
+impl Disassembly {
+    pub fn length(&self) -> u16 {
+        self.bytes.len() as u16
+    }
+
+    /// Shows the label in place of the operand
+    pub fn name_operand(&mut self, label: &str) {
+        if let Some(operand) = self.operand {
+            self.text = self.text.replace(&format!("${:04X}", operand), label);
+        }
+    }
+}
+
+impl fmt::Display for Disassembly {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bytes: Vec<String> = self.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        write!(
+            f,
+            "${:04X}: {:<9} {}",
+            self.address,
+            bytes.join(" "),
+            self.text
+        )
+    }
+}
+
+/// Decodes the instruction at the address, reading its immediates through the bus
+pub fn disassemble(bus: &dyn BusConnection, address: u16) -> Disassembly {
+    let opcode = bus.read_byte(address);
+    let n8 = bus.read_byte(address.wrapping_add(1));
+    let n16 = n8 as u16 | (bus.read_byte(address.wrapping_add(2)) as u16) << 8;
+    // Relative jumps are shown with their target, as RGBDS expects
+    let e8 = n8 as i8;
+    let relative = address.wrapping_add(2).wrapping_add(e8 as u16);
+
+    let (x, y, z) = (opcode >> 6, (opcode >> 3) & 7, opcode & 7);
+    let (p, q) = ((y >> 1) as usize, y & 1);
+    let (y, z) = (y as usize, z as usize);
+    let (text, length) = match (x, z) {
+        _ if opcode == 0xCB => (prefixed(n8), 2),
+        (0, 0) => match y {
+            0 => ("nop".to_string(), 1),
+            1 => (format!("ld [${:04X}], sp", n16), 3),
+            // RGBDS assembles `stop` as 10 00, any other second byte has to be spelled out
+            2 if n8 == 0 => ("stop".to_string(), 2),
+            2 => (format!("db $10, ${:02X}", n8), 2),
+            3 => (format!("jr ${:04X}", relative), 2),
+            _ => (format!("jr {}, ${:04X}", CONDITIONS[y - 4], relative), 2),
+        },
+        (0, 1) if q == 0 => (format!("ld {}, ${:04X}", R16[p], n16), 3),
+        (0, 1) => (format!("add hl, {}", R16[p]), 1),
+        (0, 2) if q == 0 => (format!("ld {}, a", R16_MEMORY[p]), 1),
+        (0, 2) => (format!("ld a, {}", R16_MEMORY[p]), 1),
+        (0, 3) if q == 0 => (format!("inc {}", R16[p]), 1),
+        (0, 3) => (format!("dec {}", R16[p]), 1),
+        (0, 4) => (format!("inc {}", R8[y]), 1),
+        (0, 5) => (format!("dec {}", R8[y]), 1),
+        (0, 6) => (format!("ld {}, ${:02X}", R8[y], n8), 2),
+        (0, 7) => (ACCUMULATOR[y].to_string(), 1),
+        (1, _) if opcode == 0x76 => ("halt".to_string(), 1),
+        (1, _) => (format!("ld {}, {}", R8[y], R8[z]), 1),
+        (2, _) => (format!("{} a, {}", ALU[y], R8[z]), 1),
+        (3, 0) => match y {
+            0..=3 => (format!("ret {}", CONDITIONS[y]), 1),
+            4 => (format!("ldh [$FF{:02X}], a", n8), 2),
+            5 => (format!("add sp, {}", e8), 2),
+            6 => (format!("ldh a, [$FF{:02X}]", n8), 2),
+            _ => (
+                format!(
+                    "ld hl, sp {} {}",
+                    if e8 < 0 { '-' } else { '+' },
+                    e8.unsigned_abs()
+                ),
+                2,
+            ),
+        },
+        (3, 1) if q == 0 => (format!("pop {}", R16_STACK[p]), 1),
+        (3, 1) => (["ret", "reti", "jp hl", "ld sp, hl"][p].to_string(), 1),
+        (3, 2) => match y {
+            0..=3 => (format!("jp {}, ${:04X}", CONDITIONS[y], n16), 3),
+            4 => ("ldh [c], a".to_string(), 1),
+            5 => (format!("ld [${:04X}], a", n16), 3),
+            6 => ("ldh a, [c]".to_string(), 1),
+            _ => (format!("ld a, [${:04X}]", n16), 3),
+        },
+        (3, 3) if y == 0 => (format!("jp ${:04X}", n16), 3),
+        (3, 3) if y == 6 => ("di".to_string(), 1),
+        (3, 3) if y == 7 => ("ei".to_string(), 1),
+        (3, 4) if y < 4 => (format!("call {}, ${:04X}", CONDITIONS[y], n16), 3),
+        (3, 5) if q == 0 => (format!("push {}", R16_STACK[p]), 1),
+        (3, 5) if p == 0 => (format!("call ${:04X}", n16), 3),
+        (3, 6) => (format!("{} a, ${:02X}", ALU[y], n8), 2),
+        (3, 7) => (format!("rst ${:02X}", y * 8), 1),
+        // Opcodes the SM83 doesn't have
+        _ => (format!("db ${:02X}", opcode), 1),
+    };
+
+    let bytes = (0..length)
+        .map(|i| bus.read_byte(address.wrapping_add(i)))
+        .collect();
+    let (cycles, branch_cycles) = if opcode == 0xCB {
+        (PREFIXED_OPERATION_CYCLES[n8 as usize], None)
+    } else if is_conditional(opcode) {
+        (
+            OPERATION_CYCLES[opcode as usize],
+            Some(conditional_cycle(opcode)),
+        )
+    } else {
+        (OPERATION_CYCLES[opcode as usize], None)
+    };
+    let operand = match opcode {
+        0x18 | 0x20 | 0x28 | 0x30 | 0x38 => Some(relative),
+        0xE0 | 0xF0 => Some(0xFF00 | n8 as u16),
+        0x01 | 0x08 | 0x11 | 0x21 | 0x31 | 0xC2 | 0xC3 | 0xC4 | 0xCA | 0xCC | 0xCD | 0xD2
+        | 0xD4 | 0xDA | 0xDC | 0xEA | 0xFA => Some(n16),
+        _ => None,
+    };
+    Disassembly {
+        address,
+        bytes,
+        text,
+        cycles,
+        branch_cycles,
+        operand,
+    }
+}
+
+/// The number of bytes of the instruction starting with the opcode
+pub fn instruction_length(opcode: u8) -> u16 {
+    match opcode {
+        0x01 | 0x08 | 0x11 | 0x21 | 0x31 | 0xC2 | 0xC3 | 0xC4 | 0xCA | 0xCC | 0xCD | 0xD2
+        | 0xD4 | 0xDA | 0xDC | 0xEA | 0xFA => 3,
+        0x06 | 0x0E | 0x10 | 0x16 | 0x18 | 0x1E | 0x20 | 0x26 | 0x28 | 0x2E | 0x30 | 0x36
+        | 0x38 | 0x3E | 0xC6 | 0xCB | 0xCE | 0xD6 | 0xDE | 0xE0 | 0xE6 | 0xE8 | 0xEE | 0xF0
+        | 0xF6 | 0xF8 | 0xFE => 2,
+        _ => 1,
+    }
+}
+
+fn prefixed(opcode: u8) -> String {
+    let (x, y, z) = (opcode >> 6, (opcode >> 3) & 7, (opcode & 7) as usize);
+    match x {
+        0 => format!("{} {}", SHIFTS[y as usize], R8[z]),
+        1 => format!("bit {}, {}", y, R8[z]),
+        2 => format!("res {}, {}", y, R8[z]),
+        _ => format!("set {}, {}", y, R8[z]),
+    }
+}
+
+fn is_conditional(opcode: u8) -> bool {
+    // jr, ret, jp and call with a condition
+    opcode & 0xE7 == 0x20 || opcode & 0xE7 == 0xC0 || opcode & 0xE7 == 0xC2 || opcode & 0xE7 == 0xC4
+}
+
+/// Shows a ROM file as the cpu would see it with `bank` switched in at 0x4000
+pub struct RomBank<'a> {
+    rom: &'a [u8],
+    bank: usize,
+}
+
+impl<'a> RomBank<'a> {
+    pub fn new(rom: &'a [u8], bank: usize) -> RomBank<'a> {
+        RomBank { rom, bank }
+    }
+}
+
+impl BusConnection for RomBank<'_> {
+    fn read_byte(&self, address: u16) -> u8 {
+        let offset = match address {
+            0..=0x3FFF => address as usize,
+            0x4000..=0x7FFF => self.bank * 0x4000 + address as usize - 0x4000,
+            _ => return 0xFF,
+        };
+        self.rom.get(offset).copied().unwrap_or(0xFF)
+    }
+
+    fn write_byte(&mut self, _address: u16, _value: u8) {
+        /* Do Nothing, this is ROM */
+    }
+}
+
+/// Disassembles `start` to `end` of a rom bank into source RGBDS can assemble again,
+/// with the labels from the symbols
+pub fn listing(rom: &[u8], bank: usize, start: u16, end: u16, symbols: &Symbols) -> String {
+    let bus = RomBank::new(rom, bank);
+    let label = |address: u16| {
+        let bank = if (0x4000..=0x7FFF).contains(&address) {
+            bank as u16
+        } else {
+            0
+        };
+        symbols.label(bank, address)
+    };
+    let mut lines = vec![if start < 0x4000 {
+        format!("SECTION \"ROM0 ${:04X}\", ROM0[${:04X}]", start, start)
+    } else {
+        format!(
+            "SECTION \"ROMX ${:04X} bank {}\", ROMX[${:04X}], BANK[{}]",
+            start, bank, start, bank
+        )
+    }];
+    let mut address = start as u32;
+    while address <= end as u32 {
+        let mut instruction = disassemble(&bus, address as u16);
+        // Don't run past the end, the remaining bytes are left as data
+        if address + instruction.length() as u32 > end as u32 + 1 {
+            instruction
+                .bytes
+                .truncate((end as u32 + 1 - address) as usize);
+            let bytes: Vec<String> = instruction
+                .bytes
+                .iter()
+                .map(|b| format!("${:02X}", b))
+                .collect();
+            instruction.text = format!("db {}", bytes.join(", "));
+        }
+        if let Some(name) = instruction.operand.and_then(label) {
+            instruction.name_operand(name);
+        }
+        if let Some(name) = label(address as u16) {
+            lines.push(format!("{}:", name));
+        }
+        let bytes: Vec<String> = instruction
+            .bytes
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect();
+        lines.push(format!(
+            "    {:<24} ; ${:04X}: {}",
+            instruction.text,
+            address,
+            bytes.join(" ")
+        ));
+        address += instruction.length() as u32;
+    }
+    lines.join("\n")
+}
+
+/// Parses an address or value in hex, with an optional $ or 0x prefix
+pub fn parse_hex(text: &str) -> Result<u16, String> {
+    let digits = text
+        .trim_start_matches('$')
+        .trim_start_matches("0x")
+        .trim_start_matches("0X");
+    u16::from_str_radix(digits, 16).map_err(|_| format!("Not a hex number: {}", text))
+}
+
+#[cfg(test)]
+fn text(bytes: &[u8]) -> String {
+    let mut rom = vec![0u8; 0x200];
+    rom[0x150..0x150 + bytes.len()].copy_from_slice(bytes);
+    let instruction = disassemble(&RomBank::new(&rom, 1), 0x150);
+    assert_eq!(instruction.bytes, bytes);
+    instruction.text
+}
+
+#[test]
+fn formats_operands() {
+    assert_eq!(text(&[0x00]), "nop");
+    assert_eq!(text(&[0x3E, 0x12]), "ld a, $12");
+    assert_eq!(text(&[0x21, 0x34, 0x12]), "ld hl, $1234");
+    assert_eq!(text(&[0x22]), "ld [hl+], a");
+    assert_eq!(text(&[0x3A]), "ld a, [hl-]");
+    assert_eq!(text(&[0x08, 0x00, 0xC0]), "ld [$C000], sp");
+    assert_eq!(text(&[0x18, 0xFE]), "jr $0150");
+    assert_eq!(text(&[0x20, 0x05]), "jr nz, $0157");
+    assert_eq!(text(&[0x76]), "halt");
+    assert_eq!(text(&[0x70]), "ld [hl], b");
+    assert_eq!(text(&[0xAF]), "xor a, a");
+    assert_eq!(text(&[0xE0, 0x44]), "ldh [$FF44], a");
+    assert_eq!(text(&[0xF2]), "ldh a, [c]");
+    assert_eq!(text(&[0xE8, 0xFE]), "add sp, -2");
+    assert_eq!(text(&[0xF8, 0x05]), "ld hl, sp + 5");
+    assert_eq!(text(&[0xCD, 0x00, 0x40]), "call $4000");
+    assert_eq!(text(&[0xD8]), "ret c");
+    assert_eq!(text(&[0xFF]), "rst $38");
+    assert_eq!(text(&[0xF5]), "push af");
+    assert_eq!(text(&[0xE9]), "jp hl");
+    assert_eq!(text(&[0xCB, 0x7E]), "bit 7, [hl]");
+    assert_eq!(text(&[0xCB, 0x37]), "swap a");
+    assert_eq!(text(&[0xD3]), "db $D3");
+    assert_eq!(text(&[0x10, 0x00]), "stop");
+    assert_eq!(text(&[0x10, 0x01]), "db $10, $01");
+}
+
+#[test]
+fn lengths() {
+    for opcode in 0..=0xFF {
+        let rom = [opcode, 0, 0];
+        let length = disassemble(&RomBank::new(&rom, 1), 0).length();
+        assert_eq!(instruction_length(opcode), length, "{:02X}", opcode);
+    }
+}
+
+#[test]
+fn cycles() {
+    let rom = [0xC4, 0x00, 0x40, 0xCB, 0x46, 0x01];
+    let bus = RomBank::new(&rom, 1);
+    let call = disassemble(&bus, 0);
+    assert_eq!((call.cycles, call.branch_cycles), (12, Some(24)));
+    let bit = disassemble(&bus, 3);
+    assert_eq!((bit.length(), bit.cycles, bit.branch_cycles), (2, 12, None));
+}
+
+#[test]
+fn rgbds_listing() {
+    let mut rom = vec![0u8; 0x8000];
+    rom[0x4000..0x4004].copy_from_slice(&[0x3E, 0x01, 0xC3, 0x00]);
+    let listing = listing(&rom, 1, 0x4000, 0x4003, &Symbols::default());
+    let lines: Vec<&str> = listing.lines().collect();
+    assert_eq!(
+        lines,
+        [
+            "SECTION \"ROMX $4000 bank 1\", ROMX[$4000], BANK[1]",
+            "    ld a, $01                ; $4000: 3E 01",
+            "    db $C3, $00              ; $4002: C3 00",
+        ]
+    );
+}
+
+#[test]
+fn listing_with_labels() {
+    let mut rom = vec![0u8; 0xC000];
+    rom[0x8000..0x8005].copy_from_slice(&[0xCD, 0x04, 0x40, 0x76, 0xC9]);
+    let mut symbols = Symbols::default();
+    symbols.add(1, 0x4004, "Wrong.bank");
+    symbols.add(2, 0x4000, "Start");
+    symbols.add(2, 0x4004, "Start.done");
+    let listing = listing(&rom, 2, 0x4000, 0x4004, &symbols);
+    let lines: Vec<&str> = listing.lines().collect();
+    assert_eq!(
+        lines,
+        [
+            "SECTION \"ROMX $4000 bank 2\", ROMX[$4000], BANK[2]",
+            "Start:",
+            "    call Start.done          ; $4000: CD 04 40",
+            "    halt                     ; $4003: 76",
+            "Start.done:",
+            "    ret                      ; $4004: C9",
+        ]
+    );
+}
@@ -0,0 +1,142 @@
+use crate::dmg::save_state::{SaveState, StateReader, StateWriter};
+
+// The bytes copied to OAM by a transfer
+const LENGTH: u8 = 160;
+
+/// OAM DMA, started by writing the upper byte of the source to 0xFF46.
+/// After a cycle to start up it copies a byte to OAM every M-cycle for 160 M-cycles,
+/// and OAM belongs to it until it's done.
+/// See: https://gbdev.io/pandocs/OAM_DMA_Transfer.html
+pub struct Dma {
+    /// The value last written to 0xFF46
+    register: u8,
+
+    /// M-cycles until the last requested transfer takes over, 0 when none is waiting.
+    /// A transfer that's running keeps going until then
+    starting: u8,
+
+    /// Where the running transfer copies from
+    source: u16,
+
+    /// The bytes the running transfer has copied, it's done at 160
+    copied: u8,
+}
+
+impl Default for Dma {
+    fn default() -> Dma {
+        Dma {
+            register: 0,
+            starting: 0,
+            source: 0,
+            copied: LENGTH,
+        }
+    }
+}
+
+impl Dma {
+    pub fn start(&mut self, value: u8) {
+        self.register = value;
+        self.starting = 2;
+    }
+
+    pub fn register(&self) -> u8 {
+        self.register
+    }
+
+    /// The address the running transfer reads from this M-cycle, if one is running
+    pub fn source(&self) -> Option<u16> {
+        if self.copied < LENGTH {
+            Some(self.source + self.copied as u16)
+        } else {
+            None
+        }
+    }
+
+    /// Runs an M-cycle. Returns the address a byte is copied from and where in OAM it goes
+    pub fn tick(&mut self) -> Option<(u16, u8)> {
+        let copy = self.source().map(|address| (address, self.copied));
+        if copy.is_some() {
+            self.copied += 1;
+        }
+
+        if self.starting > 0 {
+            self.starting -= 1;
+            if self.starting == 0 {
+                self.source = source_address(self.register);
+                self.copied = 0;
+            }
+        }
+        copy
+    }
+}
+
+/// The transfer can't read from 0xE000 and up, those sources read echo RAM instead
+fn source_address(value: u8) -> u16 {
+    let address = (value as u16) << 8;
+    if address >= 0xE000 {
+        address - 0x2000
+    } else {
+        address
+    }
+}
+
+impl SaveState for Dma {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.register);
+        state.u8(self.starting);
+        state.u16(self.source);
+        state.u8(self.copied);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.register = state.u8()?;
+        self.starting = state.u8()?;
+        self.source = state.u16()?;
+        self.copied = state.u8()?;
+        Ok(())
+    }
+}
+
+#[test]
+fn starts_after_a_cycle() {
+    let mut dma = Dma::default();
+    dma.start(0xC1);
+    assert_eq!(dma.tick(), None);
+    assert_eq!(dma.source(), None, "The transfer is starting up");
+    assert_eq!(dma.tick(), None);
+    assert_eq!(dma.source(), Some(0xC100));
+    assert_eq!(dma.tick(), Some((0xC100, 0)));
+    assert_eq!(dma.tick(), Some((0xC101, 1)));
+}
+
+#[test]
+fn copies_160_bytes() {
+    let mut dma = Dma::default();
+    dma.start(0x80);
+    let copies: Vec<(u16, u8)> = (0..200).filter_map(|_| dma.tick()).collect();
+    assert_eq!(copies.len(), 160);
+    assert_eq!(copies[159], (0x809F, 159));
+    assert_eq!(dma.source(), None);
+}
+
+#[test]
+fn echo_ram_sources() {
+    assert_eq!(source_address(0xDF), 0xDF00);
+    assert_eq!(source_address(0xE0), 0xC000);
+    assert_eq!(source_address(0xFE), 0xDE00);
+    assert_eq!(source_address(0xFF), 0xDF00);
+}
+
+#[test]
+fn restarting_keeps_copying_until_the_new_transfer_starts() {
+    let mut dma = Dma::default();
+    dma.start(0xC0);
+    for _ in 0..12 {
+        dma.tick();
+    }
+    dma.start(0xD0);
+    assert_eq!(dma.register(), 0xD0);
+    assert_eq!(dma.tick(), Some((0xC00A, 10)));
+    assert_eq!(dma.tick(), Some((0xC00B, 11)));
+    assert_eq!(dma.tick(), Some((0xD000, 0)));
+}
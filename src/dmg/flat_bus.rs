@@ -0,0 +1,66 @@
+use crate::dmg::busconnection::{BusConnection, MemoryBus};
+
+/// The whole address space as plain memory, without cartridges, IO or interrupts.
+/// An instruction run against it sees nothing but what was put there, which is what
+/// instruction tests and fuzzers need, and it counts the M-cycles the cpu ticks it by.
+pub struct FlatBus {
+    memory: Vec<u8>,
+    ticks: u64,
+}
+
+impl Default for FlatBus {
+    fn default() -> FlatBus {
+        FlatBus {
+            memory: vec![0; 0x10000],
+            ticks: 0,
+        }
+    }
+}
+
+impl FlatBus {
+    /// Copies the bytes into memory from the address on
+    pub fn load(&mut self, address: u16, bytes: &[u8]) {
+        for (i, byte) in bytes.iter().enumerate() {
+            self.write_byte(address.wrapping_add(i as u16), *byte);
+        }
+    }
+
+    /// The M-cycles that have passed
+    pub fn ticks(&self) -> u64 {
+        self.ticks
+    }
+}
+
+impl BusConnection for FlatBus {
+    fn read_byte(&self, address: u16) -> u8 {
+        self.memory[address as usize]
+    }
+
+    fn write_byte(&mut self, address: u16, value: u8) {
+        self.memory[address as usize] = value;
+    }
+}
+
+impl MemoryBus for FlatBus {
+    fn tick(&mut self) {
+        self.ticks += 1;
+    }
+}
+
+#[test]
+fn runs_instructions() {
+    use crate::dmg::cpu::CPU;
+
+    let mut bus = FlatBus::default();
+    // LD A, ($C100), INC A, LD ($C100), A
+    bus.load(0x0000, &[0xFA, 0x00, 0xC1, 0x3C, 0xEA, 0x00, 0xC1]);
+    bus.load(0xC100, &[0x41]);
+    let mut cpu = CPU::with_bus(bus);
+    for _ in 0..3 {
+        cpu.step();
+    }
+    assert_eq!(cpu.pc, 0x0007);
+    assert_eq!(cpu.bus.read_byte(0xC100), 0x42);
+    // 4 + 1 + 4 M-cycles
+    assert_eq!(cpu.bus.ticks(), 9);
+}
@@ -0,0 +1,341 @@
+use crate::dmg::bus::Bus;
+use crate::dmg::busconnection::BusConnection;
+use crate::dmg::cartridge::cartridge::{checksum, Cartridge, Mapper};
+use crate::dmg::cpu::CPU;
+use crate::dmg::save_state::{SaveState, StateReader, StateWriter};
+use log::error;
+use std::fs::File;
+use std::io::Read;
+
+// The header is 0x70 bytes long, the music data follows it
+// See: https://ocremix.org/info/GBS_Format_Specification
+const HEADER_SIZE: usize = 0x70;
+
+// The number of cpu cycles between two VBlanks
+const VBLANK_PERIOD: u32 = 70224;
+
+// Calls to init and play return here, nothing is ever executed from this address
+const RETURN_ADDRESS: u16 = 0xFEFF;
+
+// If a routine hasn't returned within a second it probably never will
+const MAX_ROUTINE_CYCLES: u32 = 4194304;
+
+/// A Game Boy Sound file, the sound driver and music data ripped from a game
+pub struct Gbs {
+    pub songs: u8,
+    pub first_song: u8,
+    pub load_address: u16,
+    pub init_address: u16,
+    pub play_address: u16,
+    pub stack_pointer: u16,
+    pub timer_modulo: u8,
+    pub timer_control: u8,
+    pub title: String,
+    pub author: String,
+    pub copyright: String,
+    data: Vec<u8>,
+}
+
+impl Gbs {
+    pub fn open(file_name: &str) -> Result<Gbs, String> {
+        let mut data = vec![];
+        File::open(file_name)
+            .and_then(|mut file| file.read_to_end(&mut data))
+            .map_err(|e| format!("Error reading {}: {}", file_name, e))?;
+        Gbs::parse(&data)
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<Gbs, String> {
+        if bytes.len() < HEADER_SIZE || &bytes[0..3] != b"GBS" {
+            return Err("Not a GBS file".to_string());
+        }
+        if bytes[3] != 1 {
+            return Err(format!("Unsupported GBS version {}", bytes[3]));
+        }
+        let word = |i: usize| u16::from_le_bytes([bytes[i], bytes[i + 1]]);
+        let text = |i: usize| {
+            let field = &bytes[i..i + 32];
+            let end = field.iter().position(|b| *b == 0).unwrap_or(32);
+            String::from_utf8_lossy(&field[..end]).into_owned()
+        };
+        let gbs = Gbs {
+            songs: bytes[0x04],
+            first_song: bytes[0x05],
+            load_address: word(0x06),
+            init_address: word(0x08),
+            play_address: word(0x0A),
+            stack_pointer: word(0x0C),
+            timer_modulo: bytes[0x0E],
+            timer_control: bytes[0x0F],
+            title: text(0x10),
+            author: text(0x30),
+            copyright: text(0x50),
+            data: bytes[HEADER_SIZE..].to_vec(),
+        };
+        if gbs.load_address < 0x400 || gbs.load_address >= 0x8000 {
+            return Err(format!("Invalid load address {:#06x}", gbs.load_address));
+        }
+        Ok(gbs)
+    }
+
+    /// The number of cpu cycles between calls to the play routine,
+    /// either the timer's overflow rate or the VBlank rate
+    pub fn play_period(&self) -> u32 {
+        if self.timer_control & 0b100 == 0 {
+            return VBLANK_PERIOD;
+        }
+        let cycles_per_tick = match self.timer_control & 0b11 {
+            0 => 1024,
+            1 => 16,
+            2 => 64,
+            3 => 256,
+            _ => panic!("We've defied a law of mathematics!!"),
+        };
+        let period = (256 - self.timer_modulo as u32) * cycles_per_tick;
+        // Bit 7 asks for the CGB's double speed mode
+        if self.timer_control & 0x80 == 0x80 {
+            period / 2
+        } else {
+            period
+        }
+    }
+
+    /// Lays the data out as a cartridge, with the restart vectors jumping to
+    /// their relocated versions at the load address
+    fn cartridge(&self) -> Cartridge {
+        let end = self.load_address as usize + self.data.len();
+        let mut rom = vec![0u8; end.max(0x8000)];
+        rom[self.load_address as usize..end].copy_from_slice(&self.data);
+        for vector in (0..0x40).step_by(8) {
+            let [low, high] = (self.load_address + vector).to_le_bytes();
+            rom[vector as usize..vector as usize + 3].copy_from_slice(&[0xC3, low, high]);
+        }
+
+        rom.resize(rom.len().div_ceil(0x4000) * 0x4000, 0);
+        let checksum = checksum(&rom);
+        Cartridge::from_cart(Box::new(GbsMapper::new(rom)), checksum)
+    }
+}
+
+/// GBS players map the rip like this rather than with a real MBC:
+/// 8KB of ram at 0xA000 that's always enabled, and writes to 0x2000 - 0x3FFF
+/// picking the rom bank at 0x4000, wrapping around the number of banks
+struct GbsMapper {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    rom_bank_number: u8,
+}
+
+impl GbsMapper {
+    fn new(rom: Vec<u8>) -> GbsMapper {
+        GbsMapper {
+            rom,
+            ram: vec![0u8; 0x2000],
+            rom_bank_number: 1,
+        }
+    }
+}
+
+impl Mapper for GbsMapper {
+    fn rom_bank(&self) -> usize {
+        let bank = self.rom_bank_number as usize % (self.rom.len() / 0x4000);
+        // Like an MBC1, bank 0 can't be switched in at 0x4000
+        bank.max(1)
+    }
+}
+
+impl BusConnection for GbsMapper {
+    fn read_byte(&self, address: u16) -> u8 {
+        match address {
+            0..=0x3FFF => self.rom[address as usize],
+            0x4000..=0x7FFF => self.rom[self.rom_bank() * 0x4000 + (address as usize - 0x4000)],
+            0xA000..=0xBFFF => self.ram[address as usize - 0xA000],
+            _ => 0xFF,
+        }
+    }
+
+    fn write_byte(&mut self, address: u16, value: u8) {
+        match address {
+            0x2000..=0x3FFF => self.rom_bank_number = value,
+            0xA000..=0xBFFF => self.ram[address as usize - 0xA000] = value,
+            _ => {}
+        }
+    }
+}
+
+impl SaveState for GbsMapper {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.ram);
+        state.u8(self.rom_bank_number);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        state.bytes(&mut self.ram)?;
+        self.rom_bank_number = state.u8()?;
+        Ok(())
+    }
+}
+
+/// Plays a GBS file by calling its init routine once and its play routine
+/// at the rate given in the header
+pub struct GbsPlayer {
+    pub cpu: CPU,
+    play_address: u16,
+    stack_pointer: u16,
+    play_period: u32,
+}
+
+impl GbsPlayer {
+    /// `song` counts from 1
+    pub fn new(gbs: &Gbs, song: u8) -> GbsPlayer {
+        let mut cpu = CPU::with_bus(Bus::with_cartridge(gbs.cartridge()));
+        cpu.bus.write_byte(0xFF50, 0x1); // Disables the boot rom
+        cpu.bus.write_byte(0xFF26, 0x80);
+        cpu.bus.write_byte(0xFF25, 0xFF);
+        cpu.bus.write_byte(0xFF24, 0x77);
+        cpu.bus.write_byte(0xFF06, gbs.timer_modulo);
+        cpu.bus.write_byte(0xFF07, gbs.timer_control);
+
+        let mut player = GbsPlayer {
+            cpu,
+            play_address: gbs.play_address,
+            stack_pointer: gbs.stack_pointer,
+            play_period: gbs.play_period(),
+        };
+        player.cpu.registers.a = song.saturating_sub(1);
+        player.call(gbs.init_address);
+        player
+    }
+
+    /// Calls the play routine, then waits out the rest of its period.
+    /// Returns the number of cpu cycles that passed
+    pub fn play(&mut self) -> u32 {
+        let cycles = self.call(self.play_address);
+        let mut idle = self.play_period.saturating_sub(cycles);
+        while idle > 0 {
+            let step = idle.min(4);
+            self.cpu.bus.step(step as u8);
+            idle -= step;
+        }
+        cycles.max(self.play_period)
+    }
+
+    /// Runs a routine until it returns, and returns the cycles it took
+    fn call(&mut self, address: u16) -> u32 {
+        self.cpu.sp = self.stack_pointer.wrapping_sub(2);
+        self.cpu.bus.write_word(self.cpu.sp, RETURN_ADDRESS);
+        self.cpu.pc = address;
+        self.cpu.ime = false;
+
+        let mut cycles = 0;
+        while self.cpu.pc != RETURN_ADDRESS {
+            if cycles > MAX_ROUTINE_CYCLES {
+                error!("The routine at {:#06x} never returned", address);
+                break;
+            }
+            cycles += self.cpu.step() as u32;
+        }
+        cycles
+    }
+}
+
+#[cfg(test)]
+fn test_gbs(timer_control: u8) -> Vec<u8> {
+    let mut bytes = vec![0u8; HEADER_SIZE];
+    bytes[0..4].copy_from_slice(b"GBS\x01");
+    bytes[0x04] = 3;
+    bytes[0x05] = 1;
+    bytes[0x06..0x08].copy_from_slice(&0x0400u16.to_le_bytes());
+    bytes[0x08..0x0A].copy_from_slice(&0x0400u16.to_le_bytes());
+    bytes[0x0A..0x0C].copy_from_slice(&0x0404u16.to_le_bytes());
+    bytes[0x0C..0x0E].copy_from_slice(&0xDFFFu16.to_le_bytes());
+    bytes[0x0E] = 0xC0;
+    bytes[0x0F] = timer_control;
+    bytes[0x10..0x15].copy_from_slice(b"Title");
+    bytes.extend_from_slice(&[
+        0xEA, 0x00, 0xC0, // init: LD (0xC000), A
+        0xC9, //             RET
+        0x21, 0x01, 0xC0, // play: LD HL, 0xC001
+        0x34, //             INC (HL)
+        0xC9, //             RET
+    ]);
+    bytes
+}
+
+#[test]
+fn parse_header() {
+    let gbs = Gbs::parse(&test_gbs(0)).unwrap();
+    assert_eq!(gbs.songs, 3);
+    assert_eq!(gbs.load_address, 0x400);
+    assert_eq!(gbs.play_address, 0x404);
+    assert_eq!(gbs.stack_pointer, 0xDFFF);
+    assert_eq!(gbs.title, "Title");
+    assert_eq!(gbs.author, "");
+    assert!(Gbs::parse(b"NES").is_err());
+}
+
+#[test]
+fn play_period_from_timer() {
+    assert_eq!(
+        Gbs::parse(&test_gbs(0)).unwrap().play_period(),
+        VBLANK_PERIOD
+    );
+    // (256 - 0xC0) ticks of 16 cycles
+    assert_eq!(Gbs::parse(&test_gbs(0b101)).unwrap().play_period(), 64 * 16);
+    assert_eq!(Gbs::parse(&test_gbs(0x85)).unwrap().play_period(), 64 * 8);
+}
+
+#[test]
+fn calls_init_then_play() {
+    let gbs = Gbs::parse(&test_gbs(0)).unwrap();
+    let mut player = GbsPlayer::new(&gbs, 3);
+    assert_eq!(
+        player.cpu.bus.read_byte(0xC000),
+        2,
+        "Init gets the song index in A"
+    );
+    for _ in 0..3 {
+        assert_eq!(player.play(), VBLANK_PERIOD);
+    }
+    assert_eq!(player.cpu.bus.read_byte(0xC001), 3);
+    assert_eq!(
+        player.cpu.bus.read_byte(0x0008),
+        0xC3,
+        "RST 08 jumps to 0x408"
+    );
+    assert_eq!(player.cpu.bus.read_word(0x0009), 0x0408);
+}
+
+#[test]
+fn ram_is_always_enabled() {
+    let mut bytes = test_gbs(0);
+    bytes.truncate(HEADER_SIZE);
+    bytes.extend_from_slice(&[
+        0xEA, 0x00, 0xA0, // init: LD (0xA000), A
+        0xAF, //             XOR A
+        0xFA, 0x00, 0xA0, // LD A, (0xA000)
+        0xEA, 0x00, 0xC0, // LD (0xC000), A
+        0xC9, //             RET
+    ]);
+    let player = GbsPlayer::new(&Gbs::parse(&bytes).unwrap(), 3);
+    assert_eq!(player.cpu.bus.read_byte(0xC000), 2);
+}
+
+#[test]
+fn rom_bank_wraps_around() {
+    let mut rom = vec![0u8; 0xC000];
+    rom[0x8000] = 0x02;
+    rom[0x4000] = 0x01;
+    let mut mapper = GbsMapper::new(rom);
+    assert_eq!(mapper.read_byte(0x4000), 0x01);
+    mapper.write_byte(0x2000, 2);
+    assert_eq!(mapper.read_byte(0x4000), 0x02);
+    mapper.write_byte(0x2000, 5);
+    assert_eq!(mapper.read_byte(0x4000), 0x02, "Bank 5 of 3 is bank 2");
+    mapper.write_byte(0x2000, 3);
+    assert_eq!(
+        mapper.read_byte(0x4000),
+        0x01,
+        "Bank 3 of 3 is bank 0, which reads as 1"
+    );
+}
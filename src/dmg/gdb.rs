@@ -0,0 +1,459 @@
+use crate::dmg::cpu::CPU;
+use crate::dmg::debugger::{Access, WatchHit};
+use crate::dmg::registers::FlagsRegister;
+use log::{error, info};
+use std::collections::BTreeSet;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+// How many instructions run between checks for an interrupt from the debugger
+const INTERRUPT_POLL_STEPS: u32 = 4096;
+
+// GDB's signal numbers for the stop replies
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+// The SM83 isn't an architecture GDB knows, so the registers are described to it:
+// a, f, b, c, d, e, h, l then sp and pc, in the order of the g packet.
+// See: https://sourceware.org/gdb/current/onlinedocs/gdb.html/Target-Descriptions.html
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.oxideboy.sm83">
+    <reg name="a" bitsize="8" regnum="0"/>
+    <reg name="f" bitsize="8"/>
+    <reg name="b" bitsize="8"/>
+    <reg name="c" bitsize="8"/>
+    <reg name="d" bitsize="8"/>
+    <reg name="e" bitsize="8"/>
+    <reg name="h" bitsize="8"/>
+    <reg name="l" bitsize="8"/>
+    <reg name="sp" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+/// Lets GDB, or anything else speaking its remote serial protocol, debug the emulated cpu.
+/// See: https://sourceware.org/gdb/current/onlinedocs/gdb.html/Remote-Protocol.html
+pub struct GdbStub {
+    stream: TcpStream,
+    no_ack: bool,
+    breakpoints: BTreeSet<u16>,
+}
+
+impl GdbStub {
+    /// Waits for a debugger to connect
+    pub fn listen(address: &str) -> io::Result<GdbStub> {
+        info!("Waiting for GDB on {}", address);
+        GdbStub::accept(&TcpListener::bind(address)?)
+    }
+
+    pub fn accept(listener: &TcpListener) -> io::Result<GdbStub> {
+        let (stream, _) = listener.accept()?;
+        stream.set_nodelay(true)?;
+        Ok(GdbStub {
+            stream,
+            no_ack: false,
+            breakpoints: BTreeSet::new(),
+        })
+    }
+
+    /// Answers the debugger until it detaches, kills us or disconnects
+    pub fn serve(&mut self, cpu: &mut CPU) -> io::Result<()> {
+        loop {
+            let packet = match self.read_packet()? {
+                Some(packet) => packet,
+                // An interrupt while we're already stopped
+                None => {
+                    self.send(&format!("S{:02x}", SIGINT))?;
+                    continue;
+                }
+            };
+            match packet.as_str() {
+                "D" => return self.send("OK"),
+                "k" => return Ok(()),
+                _ => {
+                    let reply = self.handle(cpu, &packet)?;
+                    self.send(&reply)?;
+                }
+            }
+        }
+    }
+
+    // Returns None for the interrupt byte, which is sent outside of a packet.
+    // Packets with a bad checksum are dropped, gdb sends them again
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        let mut byte = [0u8];
+        loop {
+            loop {
+                self.stream.read_exact(&mut byte)?;
+                match byte[0] {
+                    b'$' => break,
+                    0x03 => return Ok(None),
+                    _ => {} // Acknowledgements
+                }
+            }
+            let mut data = vec![];
+            loop {
+                self.stream.read_exact(&mut byte)?;
+                if byte[0] == b'#' {
+                    break;
+                }
+                data.push(byte[0]);
+            }
+            let mut checksum = [0u8; 2];
+            self.stream.read_exact(&mut checksum)?;
+            if !self.no_ack {
+                let valid = std::str::from_utf8(&checksum)
+                    .ok()
+                    .and_then(|c| u8::from_str_radix(c, 16).ok())
+                    == Some(checksum_of(&data));
+                self.stream.write_all(if valid { b"+" } else { b"-" })?;
+                if !valid {
+                    continue;
+                }
+            }
+            return Ok(Some(String::from_utf8_lossy(&data).to_string()));
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+        self.stream.write_all(packet.as_bytes())
+    }
+
+    fn handle(&mut self, cpu: &mut CPU, packet: &str) -> io::Result<String> {
+        if packet.is_empty() {
+            return Ok(String::new());
+        }
+        // Every packet we understand is ASCII, and slicing anything else could split a character
+        if !packet.is_ascii() {
+            return Ok("E01".to_string());
+        }
+        let (command, arguments) = packet.split_at(1);
+        let reply = match command {
+            "?" => format!("S{:02x}", SIGTRAP),
+            "g" => registers(cpu).iter().map(|r| hex(r)).collect(),
+            "G" => {
+                let values = from_hex(arguments);
+                if values.len() != 12 {
+                    return Ok("E01".to_string());
+                }
+                for (register, value) in [0, 1, 2, 3, 4, 5, 6, 7].iter().zip(&values) {
+                    set_register(cpu, *register, *value as u16);
+                }
+                set_register(cpu, 8, u16::from_le_bytes([values[8], values[9]]));
+                set_register(cpu, 9, u16::from_le_bytes([values[10], values[11]]));
+                "OK".to_string()
+            }
+            "p" => match usize::from_str_radix(arguments, 16) {
+                Ok(register) if register < 10 => hex(&registers(cpu)[register]),
+                _ => "E01".to_string(),
+            },
+            "P" => {
+                let (register, value) = arguments.split_once('=').unwrap_or(("", ""));
+                let value = from_hex(value);
+                match usize::from_str_radix(register, 16) {
+                    Ok(register) if register < 10 && !value.is_empty() => {
+                        let value = value.iter().rev().fold(0, |word, b| word << 8 | *b as u16);
+                        set_register(cpu, register, value);
+                        "OK".to_string()
+                    }
+                    _ => "E01".to_string(),
+                }
+            }
+            "m" => match parse_range(arguments) {
+                Some((address, length)) => {
+                    let bytes: Vec<u8> = (0..length)
+                        .map(|i| cpu.bus.read_byte(address.wrapping_add(i)))
+                        .collect();
+                    hex(&bytes)
+                }
+                None => "E01".to_string(),
+            },
+            "M" => {
+                let (range, data) = arguments.split_once(':').unwrap_or(("", ""));
+                match parse_range(range) {
+                    Some((address, _)) => {
+                        for (i, byte) in from_hex(data).iter().enumerate() {
+                            cpu.bus.write_byte(address.wrapping_add(i as u16), *byte);
+                        }
+                        "OK".to_string()
+                    }
+                    None => "E01".to_string(),
+                }
+            }
+            "Z" | "z" => self.breakpoint(cpu, command == "Z", arguments),
+            "s" => self.resume(cpu, true)?,
+            "c" => self.resume(cpu, false)?,
+            "H" => "OK".to_string(),
+            "q" | "Q" => query(packet, &mut self.no_ack),
+            _ => String::new(), // Not supported
+        };
+        Ok(reply)
+    }
+
+    // Z0 / Z1 are breakpoints, Z2 - Z4 write, read and access watchpoints
+    fn breakpoint(&mut self, cpu: &mut CPU, insert: bool, arguments: &str) -> String {
+        let mut fields = arguments.split(',');
+        let kind = fields.next().unwrap_or("");
+        let address = match fields.next().map(|a| u16::from_str_radix(a, 16)) {
+            Some(Ok(address)) => address,
+            _ => return "E01".to_string(),
+        };
+        let access = match kind {
+            "0" | "1" => {
+                if insert {
+                    self.breakpoints.insert(address);
+                } else {
+                    self.breakpoints.remove(&address);
+                }
+                return "OK".to_string();
+            }
+            "2" => Access::Write,
+            "3" => Access::Read,
+            "4" => Access::ReadWrite,
+            _ => return String::new(),
+        };
+        if insert {
+            cpu.bus.watchpoints_mut().add(address, access);
+        } else {
+            cpu.bus.watchpoints_mut().remove(address);
+        }
+        "OK".to_string()
+    }
+
+    /// Runs one instruction, or until a breakpoint, watchpoint or interrupt from the
+    /// debugger, and returns the stop reply
+    fn resume(&mut self, cpu: &mut CPU, single_step: bool) -> io::Result<String> {
+        let mut steps = 0u32;
+        loop {
+            cpu.bus.watchpoints().take_hit();
+            cpu.step();
+            if let Some(hit) = cpu.bus.watchpoints().take_hit() {
+                return Ok(watch_reply(hit));
+            }
+            if single_step || self.breakpoints.contains(&cpu.pc) {
+                return Ok(format!("S{:02x}", SIGTRAP));
+            }
+            steps += 1;
+            if steps.is_multiple_of(INTERRUPT_POLL_STEPS) && self.interrupted()? {
+                return Ok(format!("S{:02x}", SIGINT));
+            }
+        }
+    }
+
+    // Checks for the interrupt byte without waiting
+    fn interrupted(&mut self) -> io::Result<bool> {
+        self.stream.set_nonblocking(true)?;
+        let mut byte = [0u8];
+        let result = self.stream.read(&mut byte);
+        self.stream.set_nonblocking(false)?;
+        match result {
+            Ok(0) => Err(ErrorKind::UnexpectedEof.into()),
+            Ok(_) => Ok(byte[0] == 0x03),
+            Err(err) if err.kind() == ErrorKind::WouldBlock => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// Runs the cpu under GDB instead of on its own
+pub fn serve(address: &str, cpu: &mut CPU) {
+    let result = GdbStub::listen(address).and_then(|mut stub| stub.serve(cpu));
+    match result {
+        Ok(()) => info!("GDB detached"),
+        Err(err) => error!("GDB disconnected: {}", err),
+    }
+}
+
+fn query(packet: &str, no_ack: &mut bool) -> String {
+    if packet.starts_with("qSupported") {
+        return "PacketSize=4000;qXfer:features:read+;QStartNoAckMode+".to_string();
+    }
+    if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+        let (offset, length) = range.split_once(',').unwrap_or(("0", "0"));
+        let offset = usize::from_str_radix(offset, 16)
+            .unwrap_or(0)
+            .min(TARGET_XML.len());
+        let length = usize::from_str_radix(length, 16).unwrap_or(0);
+        let end = (offset + length).min(TARGET_XML.len());
+        let more = if end < TARGET_XML.len() { 'm' } else { 'l' };
+        return format!("{}{}", more, &TARGET_XML[offset..end]);
+    }
+    match packet {
+        "QStartNoAckMode" => {
+            *no_ack = true;
+            "OK".to_string()
+        }
+        "qAttached" => "1".to_string(),
+        "qC" => "QC1".to_string(),
+        "qfThreadInfo" => "m1".to_string(),
+        "qsThreadInfo" => "l".to_string(),
+        _ => String::new(),
+    }
+}
+
+fn watch_reply(hit: WatchHit) -> String {
+    let kind = match hit.access {
+        Access::Write => "watch",
+        Access::Read => "rwatch",
+        Access::ReadWrite => "awatch",
+    };
+    format!("T{:02x}{}:{:04x};", SIGTRAP, kind, hit.address)
+}
+
+// Every register as it's sent to GDB, 16 bit ones in little endian
+fn registers(cpu: &CPU) -> [Vec<u8>; 10] {
+    let r = &cpu.registers;
+    [
+        vec![r.a],
+        vec![u8::from(&r.f)],
+        vec![r.b],
+        vec![r.c],
+        vec![r.d],
+        vec![r.e],
+        vec![r.h],
+        vec![r.l],
+        cpu.sp.to_le_bytes().to_vec(),
+        cpu.pc.to_le_bytes().to_vec(),
+    ]
+}
+
+fn set_register(cpu: &mut CPU, register: usize, value: u16) {
+    let r = &mut cpu.registers;
+    match register {
+        0 => r.a = value as u8,
+        1 => r.f = FlagsRegister::from(value as u8),
+        2 => r.b = value as u8,
+        3 => r.c = value as u8,
+        4 => r.d = value as u8,
+        5 => r.e = value as u8,
+        6 => r.h = value as u8,
+        7 => r.l = value as u8,
+        8 => cpu.sp = value,
+        _ => cpu.pc = value,
+    }
+}
+
+// An address and length, both in hex, e.g. c000,10
+fn parse_range(range: &str) -> Option<(u16, u16)> {
+    let (address, length) = range.split_once(',')?;
+    let address = u16::from_str_radix(address, 16).ok()?;
+    let length = u16::from_str_radix(length, 16).ok()?;
+    Some((address, length))
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(text: &str) -> Vec<u8> {
+    (0..text.len() / 2)
+        .filter_map(|i| u8::from_str_radix(&text[i * 2..i * 2 + 2], 16).ok())
+        .collect()
+}
+
+// Talks to the stub the way GDB would
+#[cfg(test)]
+fn request(stream: &mut TcpStream, data: &str) -> String {
+    let packet = format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+    stream.write_all(packet.as_bytes()).unwrap();
+    reply(stream)
+}
+
+#[cfg(test)]
+fn reply(stream: &mut TcpStream) -> String {
+    let mut byte = [0u8];
+    loop {
+        stream.read_exact(&mut byte).unwrap();
+        if byte[0] == b'$' {
+            break;
+        }
+    }
+    let mut data = vec![];
+    loop {
+        stream.read_exact(&mut byte).unwrap();
+        if byte[0] == b'#' {
+            break;
+        }
+        data.push(byte[0]);
+    }
+    let mut checksum = [0u8; 2];
+    stream.read_exact(&mut checksum).unwrap();
+    assert_eq!(
+        u8::from_str_radix(std::str::from_utf8(&checksum).unwrap(), 16).unwrap(),
+        checksum_of(&data)
+    );
+    stream.write_all(b"+").unwrap();
+    String::from_utf8(data).unwrap()
+}
+
+#[test]
+fn scripted_session() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let stub = std::thread::spawn(move || {
+        let mut cpu = CPU::default();
+        let program = [
+            0x3E, 0x05, //       LD A, $05
+            0x00, //             NOP
+            0x00, //             NOP
+            0x04, //             INC B
+            0x21, 0x00, 0xD0, // LD HL, $D000
+            0x34, //             INC (HL)
+            0x18, 0xFE, //       JR -2
+        ];
+        for (i, byte) in program.iter().enumerate() {
+            cpu.bus.write_byte(0xC000 + i as u16, *byte);
+        }
+        cpu.pc = 0xC000;
+        GdbStub::accept(&listener).unwrap().serve(&mut cpu).unwrap();
+        (cpu.registers.a, cpu.registers.b, cpu.pc)
+    });
+
+    let mut gdb = TcpStream::connect(address).unwrap();
+    assert!(request(&mut gdb, "qSupported:multiprocess+").contains("qXfer:features:read+"));
+    let xml = request(&mut gdb, "qXfer:features:read:target.xml:0,fff");
+    assert!(xml.starts_with("l<?xml") && xml.contains("name=\"pc\""));
+    assert_eq!(request(&mut gdb, "?"), "S05");
+    assert!(request(&mut gdb, "g").ends_with("feff00c0"), "sp and pc");
+
+    assert_eq!(request(&mut gdb, "Md000,2:abcd"), "OK");
+    assert_eq!(request(&mut gdb, "md000,2"), "abcd");
+    assert_eq!(request(&mut gdb, "Md000,1:00"), "OK");
+    assert_eq!(request(&mut gdb, "Md000,2:0é"), "E01");
+
+    assert_eq!(request(&mut gdb, "Z0,c004,1"), "OK");
+    assert_eq!(request(&mut gdb, "c"), "S05");
+    assert_eq!(request(&mut gdb, "p9"), "04c0");
+    assert_eq!(request(&mut gdb, "s"), "S05");
+    assert_eq!(request(&mut gdb, "p9"), "05c0");
+    assert_eq!(request(&mut gdb, "P0=42"), "OK");
+
+    // A corrupted step is refused and never executed
+    gdb.write_all(b"$s#00").unwrap();
+    let mut ack = [0u8];
+    gdb.read_exact(&mut ack).unwrap();
+    assert_eq!(ack, *b"-");
+    assert_eq!(request(&mut gdb, "p9"), "05c0");
+
+    assert_eq!(request(&mut gdb, "Z2,d000,1"), "OK");
+    assert_eq!(request(&mut gdb, "c"), "T05watch:d000;");
+    assert_eq!(request(&mut gdb, "md000,1"), "01");
+    assert_eq!(request(&mut gdb, "z2,d000,1"), "OK");
+
+    // Stuck in the loop until interrupted
+    gdb.write_all(b"$c#63").unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(50));
+    gdb.write_all(&[0x03]).unwrap();
+    assert_eq!(reply(&mut gdb), "S02");
+
+    assert_eq!(request(&mut gdb, "D"), "OK");
+    assert_eq!(stub.join().unwrap(), (0x42, 1, 0xC009));
+}
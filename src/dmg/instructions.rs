@@ -2,7 +2,8 @@ use log::info;
 
 use crate::dmg::memory::LoadType;
 
-const PREFIXED_OPERATION_CYCLES: [u8; 256] = [
+#[rustfmt::skip]
+pub const PREFIXED_OPERATION_CYCLES: [u8; 256] = [
     8, 8, 8, 8, 8, 8, 16, 8, 8, 8, 8, 8, 8, 8, 16, 8,
     8, 8, 8, 8, 8, 8, 16, 8, 8, 8, 8, 8, 8, 8, 16, 8,
     8, 8, 8, 8, 8, 8, 16, 8, 8, 8, 8, 8, 8, 8, 16, 8,
@@ -24,7 +25,8 @@ const PREFIXED_OPERATION_CYCLES: [u8; 256] = [
 // Note: Some of these operations include conditionals where there are two numbers
 // One where a conditional is taken and one where it is not, the conditional_cycle function
 // is used for these cases
-const OPERATION_CYCLES: [u8; 256] = [
+#[rustfmt::skip]
+pub const OPERATION_CYCLES: [u8; 256] = [
     4, 12, 8, 8, 4, 4, 8, 4, 20, 8, 8, 8, 4, 4, 8, 4,
     4, 12, 8, 8, 4, 4, 8, 4, 12, 8, 8, 8, 4, 4, 8, 4,
     8, 12, 8, 8, 4, 4, 8, 4, 8, 8, 8, 8, 4, 4, 8, 4,
@@ -43,7 +45,7 @@ const OPERATION_CYCLES: [u8; 256] = [
     12, 12, 8, 4, 0, 16, 8, 16, 12, 8, 16, 4, 0, 0, 8, 16
 ];
 
-fn conditional_cycle(byte: u8) -> u8 {
+pub fn conditional_cycle(byte: u8) -> u8 {
     match byte {
         0xE9 => 4,
         0x20 | 0x30 | 0x18 | 0x28 | 0x38 => 12,
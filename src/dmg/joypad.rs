@@ -1,4 +1,5 @@
 use crate::dmg::busconnection::BusConnection;
+use crate::dmg::save_state::{SaveState, StateReader, StateWriter};
 
 pub struct Joypad {
     unused_bit_7: bool,
@@ -58,6 +59,17 @@ impl BusConnection for Joypad {
     }
 }
 
+impl SaveState for Joypad {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.read_byte(0xFF00));
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.write_byte(0xFF00, state.u8()?);
+        Ok(())
+    }
+}
+
 #[test]
 fn default_oxff() {
     let joy = Joypad::default();
@@ -0,0 +1,231 @@
+use crate::dmg::serial::SerialDevice;
+use log::{error, info};
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+
+// How often, in cpu cycles, the two sides wait for each other. Transfers started
+// by the other side are seen at most this late.
+const SYNC_CYCLES: u16 = 1024;
+
+// Every message is a kind followed by a data byte
+const SYNC: u8 = 0;
+const TRANSFER: u8 = 1;
+const REPLY: u8 = 2;
+
+trait Stream: Read + Write {}
+impl<T: Read + Write> Stream for T {}
+
+/// A link cable to another emulator over a TCP or, with a `unix:` prefix, a unix socket.
+/// Both sides run in lockstep, each waiting every `SYNC_CYCLES` until the other one has
+/// caught up, so transfers using the external clock complete at the right time.
+pub struct LinkCable {
+    stream: Option<Box<dyn Stream>>,
+    cycles: u16,
+    syncs: u64,
+    peer_syncs: u64,
+}
+
+impl LinkCable {
+    /// Waits for the other emulator to connect
+    pub fn listen(address: &str) -> io::Result<LinkCable> {
+        info!("Waiting for the link cable on {}", address);
+        #[cfg(unix)]
+        if let Some(path) = address.strip_prefix("unix:") {
+            // Only clear away a socket left behind by an earlier run
+            match std::fs::symlink_metadata(path) {
+                Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)?,
+                Ok(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AlreadyExists,
+                        format!("{} exists and isn't a socket", path),
+                    ))
+                }
+                Err(_) => {}
+            }
+            let (stream, _) = UnixListener::bind(path)?.accept()?;
+            return Ok(LinkCable::new(Box::new(stream)));
+        }
+        LinkCable::accept(&TcpListener::bind(address)?)
+    }
+
+    /// Accepts a connection on an existing listener
+    pub fn accept(listener: &TcpListener) -> io::Result<LinkCable> {
+        let (stream, _) = listener.accept()?;
+        stream.set_nodelay(true)?;
+        Ok(LinkCable::new(Box::new(stream)))
+    }
+
+    pub fn connect(address: &str) -> io::Result<LinkCable> {
+        #[cfg(unix)]
+        if let Some(path) = address.strip_prefix("unix:") {
+            return Ok(LinkCable::new(Box::new(UnixStream::connect(path)?)));
+        }
+        let stream = TcpStream::connect(address)?;
+        stream.set_nodelay(true)?;
+        Ok(LinkCable::new(Box::new(stream)))
+    }
+
+    fn new(stream: Box<dyn Stream>) -> LinkCable {
+        LinkCable {
+            stream: Some(stream),
+            cycles: 0,
+            syncs: 0,
+            peer_syncs: 0,
+        }
+    }
+
+    fn send(&mut self, kind: u8, data: u8) {
+        if let Some(stream) = &mut self.stream {
+            if let Err(err) = stream.write_all(&[kind, data]) {
+                self.disconnect(err);
+            }
+        }
+    }
+
+    fn receive(&mut self) -> Option<(u8, u8)> {
+        let stream = self.stream.as_mut()?;
+        let mut message = [0u8; 2];
+        match stream.read_exact(&mut message) {
+            Ok(()) => Some((message[0], message[1])),
+            Err(err) => {
+                self.disconnect(err);
+                None
+            }
+        }
+    }
+
+    fn disconnect(&mut self, err: io::Error) {
+        error!("The link cable was disconnected: {}", err);
+        self.stream = None;
+    }
+
+    /// Waits until the other side has reached the same point in time. Transfers it
+    /// starts in the meantime are answered with `waiting`, or 0xFF if we aren't ready
+    fn sync(&mut self, mut waiting: Option<u8>) -> Option<u8> {
+        self.syncs += 1;
+        self.send(SYNC, 0);
+        let mut received = None;
+        while self.peer_syncs < self.syncs {
+            match self.receive() {
+                Some((SYNC, _)) => self.peer_syncs += 1,
+                Some((TRANSFER, byte)) => match waiting.take() {
+                    Some(ours) => {
+                        self.send(REPLY, ours);
+                        received = Some(byte);
+                    }
+                    None => self.send(REPLY, 0xFF),
+                },
+                Some((kind, _)) => error!("Unexpected link cable message {}", kind),
+                None => break,
+            }
+        }
+        received
+    }
+}
+
+impl SerialDevice for LinkCable {
+    fn transfer(&mut self, byte: u8) -> u8 {
+        self.send(TRANSFER, byte);
+        loop {
+            match self.receive() {
+                Some((SYNC, _)) => self.peer_syncs += 1,
+                // Both sides started a transfer with their own clock
+                Some((TRANSFER, _)) => self.send(REPLY, 0xFF),
+                Some((REPLY, byte)) => return byte,
+                Some((kind, _)) => error!("Unexpected link cable message {}", kind),
+                None => return 0xFF,
+            }
+        }
+    }
+
+    fn step(&mut self, cycles: u8, waiting: Option<u8>) -> Option<u8> {
+        self.stream.as_ref()?;
+        self.cycles += cycles as u16;
+        if self.cycles < SYNC_CYCLES {
+            return None;
+        }
+        self.cycles -= SYNC_CYCLES;
+        self.sync(waiting)
+    }
+}
+
+// Sends `byte` with the given clock, then stores what was received at 0xC100
+#[cfg(test)]
+fn link_program(byte: u8, control: u8) -> Vec<u8> {
+    vec![
+        0x3E, byte, //    LD A, byte
+        0xE0, 0x01, //    LDH (SB), A
+        0x3E, control, // LD A, control
+        0xE0, 0x02, //    LDH (SC), A
+        0xF0, 0x02, //    wait: LDH A, (SC)
+        0xCB, 0x7F, //    BIT 7, A
+        0x20, 0xFA, //    JR NZ, wait
+        0xF0, 0x01, //    LDH A, (SB)
+        0xEA, 0x00, 0xC1, // LD (0xC100), A
+        0x18, 0xFE, //    JR -2
+    ]
+}
+
+#[cfg(test)]
+fn run_linked(cable: LinkCable, program: Vec<u8>) -> (u8, u64) {
+    use crate::dmg::cpu::CPU;
+
+    let mut cpu = CPU::default();
+    cpu.bus.set_serial_device(Box::new(cable));
+    for (i, byte) in program.iter().enumerate() {
+        cpu.bus.write_byte(0xC000 + i as u16, *byte);
+    }
+    cpu.pc = 0xC000;
+    let mut cycles = 0u64;
+    let mut finished_at = None;
+    while cycles < 200_000 {
+        cycles += cpu.step() as u64;
+        if finished_at.is_none() && cpu.bus.read_byte(0xFF02) & 0x80 == 0 && cpu.pc > 0xC00E {
+            finished_at = Some(cycles);
+        }
+    }
+    (cpu.bus.read_byte(0xC100), finished_at.unwrap_or(u64::MAX))
+}
+
+#[test]
+fn loopback_transfer() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+
+    // The slave waits on the external clock before the master starts sending
+    let slave = std::thread::spawn(move || {
+        let cable = LinkCable::accept(&listener).unwrap();
+        run_linked(cable, link_program(0x24, 0x80))
+    });
+    let master = std::thread::spawn(move || {
+        let cable = LinkCable::connect(&address).unwrap();
+        run_linked(cable, link_program(0x42, 0x81))
+    });
+    let (master_received, master_done) = master.join().unwrap();
+    let (slave_received, slave_done) = slave.join().unwrap();
+
+    assert_eq!(master_received, 0x24);
+    assert_eq!(slave_received, 0x42);
+    assert!(master_done >= 4096, "A transfer takes 8 bits at 8192 Hz");
+    assert!(
+        slave_done.abs_diff(master_done) <= SYNC_CYCLES as u64 + 64,
+        "Both sides finish within one sync of each other: {} {}",
+        master_done,
+        slave_done
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn listen_keeps_files_that_arent_sockets() {
+    let path = std::env::temp_dir().join(format!("oxide_boy_link_test_{}", std::process::id()));
+    std::fs::write(&path, b"save data").unwrap();
+    let address = format!("unix:{}", path.display());
+    assert!(LinkCable::listen(&address).is_err());
+    assert_eq!(std::fs::read(&path).unwrap(), b"save data");
+    std::fs::remove_file(&path).unwrap();
+}
@@ -1,4 +1,5 @@
 use crate::dmg::busconnection::BusConnection;
+use crate::dmg::save_state::{SaveState, StateReader, StateWriter};
 
 /// These values are taken from The pan docs at https://gbdev.io/pandocs/#memory-map
 /// There are other blogs and sites with conflicting information, but these values seem
@@ -12,6 +13,9 @@ const INTERNAL_RAM_END: u16 = 0xDDFF;
 const ECHO_RAM_START: u16 = 0xE000;
 const ECHO_RAM_END: u16 = 0xFDFF;
 
+// Work RAM is 8 banks of 4KB on the CGB, the DMG only has the first two
+const WRAM_BANK_SIZE: usize = 0x1000;
+
 pub enum Interrupt {
     VBlank,
     LcdStat,
@@ -21,14 +25,39 @@ pub enum Interrupt {
     NONE,
 }
 
+impl Interrupt {
+    /// The interrupt's bit in IE and IF
+    /// See: https://gbdev.io/pandocs/Interrupts.html#ff0f--if-interrupt-flag
+    pub fn flag(&self) -> u8 {
+        match self {
+            Interrupt::VBlank => 0x01,
+            Interrupt::LcdStat => 0x02,
+            Interrupt::TimerOverflow => 0x04,
+            Interrupt::SerialLink => 0x08,
+            Interrupt::JoypadPress => 0x10,
+            Interrupt::NONE => 0x00,
+        }
+    }
+}
+
 pub struct Memory {
     memory: [u8; 0xFFFF + 1],
+
+    /// Bank 0 is at 0xC000 - 0xCFFF, and the bank at 0xD000 - 0xDFFF is picked by SVBK (0xFF70)
+    /// on the CGB. The DMG always has bank 1 there
+    /// See: https://gbdev.io/pandocs/CGB_Registers.html#ff70--svbk-cgb-mode-only-wram-bank
+    wram: [u8; WRAM_BANK_SIZE * 8],
+    svbk: u8,
+    cgb: bool,
 }
 
 impl Default for Memory {
     fn default() -> Self {
         Memory {
             memory: [0; 0xFFFF + 1],
+            wram: [0; WRAM_BANK_SIZE * 8],
+            svbk: 0,
+            cgb: false,
         }
     }
 }
@@ -37,24 +66,48 @@ impl BusConnection for Memory {
     /// Reads bytes from memory, or from the boot rom if 0xFF50 is zero
     fn read_byte(&self, address: u16) -> u8 {
         match address {
-            ECHO_RAM_START..=ECHO_RAM_END => {
-                self.memory[(address - ECHO_RAM_START + INTERNAL_RAM_START) as usize]
-            }
+            INTERNAL_RAM_START..=ECHO_RAM_END => self.wram[self.wram_index(address)],
+            0xFF70 if self.cgb => 0xF8 | self.svbk,
             _ => self.memory[address as usize],
         }
     }
 
     fn write_byte(&mut self, address: u16, value: u8) {
         match address {
-            ECHO_RAM_START..=ECHO_RAM_END => {
-                self.memory[(address - ECHO_RAM_START + INTERNAL_RAM_START) as usize] = value
-            }
+            INTERNAL_RAM_START..=ECHO_RAM_END => self.wram[self.wram_index(address)] = value,
+            0xFF70 if self.cgb => self.svbk = value & 0x07,
             _ => self.memory[address as usize] = value,
         }
     }
 }
 
 impl Memory {
+    /// Switches on the CGB's work RAM banking
+    pub fn set_cgb(&mut self, cgb: bool) {
+        self.cgb = cgb;
+    }
+
+    /// The work RAM bank at 0xD000 - 0xDFFF, selecting bank 0 selects bank 1
+    pub fn wram_bank(&self) -> usize {
+        if self.cgb {
+            (self.svbk as usize).max(1)
+        } else {
+            1
+        }
+    }
+
+    // Where work RAM or its echo is kept
+    fn wram_index(&self, address: u16) -> usize {
+        let address = match address {
+            ECHO_RAM_START..=ECHO_RAM_END => address - ECHO_RAM_START + INTERNAL_RAM_START,
+            _ => address,
+        } as usize;
+        match address {
+            0xC000..=0xCFFF => address - 0xC000,
+            _ => self.wram_bank() * WRAM_BANK_SIZE + address - 0xD000,
+        }
+    }
+
     fn interrupt_enable(&self) -> u8 {
         self.memory[0xFFFF]
     }
@@ -63,24 +116,29 @@ impl Memory {
         self.memory[0xFF0F]
     }
 
-    pub fn interrupt_flag_off(&mut self) {
-        self.write_byte(0xFF0F, 0);
+    /// Clears the flag of an interrupt that is being serviced
+    pub fn interrupt_flag_off(&mut self, interrupt: &Interrupt) {
+        self.memory[0xFF0F] &= !interrupt.flag();
+    }
+
+    /// Sets the interrupt's bit in IF
+    pub fn request_interrupt(&mut self, interrupt: Interrupt) {
+        self.memory[0xFF0F] |= interrupt.flag();
     }
 
+    /// The highest priority interrupt that is both enabled and requested
     pub fn return_interrupt(&self) -> Interrupt {
-        if (self.interrupt_enable() & 0x01) & (self.interrupt_flags() & 0x01) == 1 {
-            Interrupt::VBlank
-        } else if (self.interrupt_enable() & 0x02) & (self.interrupt_flags() & 0x02) == 1 {
-            Interrupt::LcdStat
-        } else if (self.interrupt_enable() & 0x03) & (self.interrupt_flags() & 0x03) == 1 {
-            Interrupt::TimerOverflow
-        } else if (self.interrupt_enable() & 0x04) & (self.interrupt_flags() & 0x04) == 1 {
-            Interrupt::SerialLink
-        } else if (self.interrupt_enable() & 0x05) & (self.interrupt_flags() & 0x05) == 1 {
-            Interrupt::JoypadPress
-        } else {
-            Interrupt::NONE
-        }
+        let pending = self.interrupt_enable() & self.interrupt_flags();
+        [
+            Interrupt::VBlank,
+            Interrupt::LcdStat,
+            Interrupt::TimerOverflow,
+            Interrupt::SerialLink,
+            Interrupt::JoypadPress,
+        ]
+        .into_iter()
+        .find(|interrupt| pending & interrupt.flag() != 0)
+        .unwrap_or(Interrupt::NONE)
     }
 }
 
@@ -207,6 +265,21 @@ impl std::convert::From<u8> for LoadType {
     }
 }
 
+impl SaveState for Memory {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.memory);
+        state.bytes(&self.wram);
+        state.u8(self.svbk);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        state.bytes(&mut self.memory)?;
+        state.bytes(&mut self.wram)?;
+        self.svbk = state.u8()?;
+        Ok(())
+    }
+}
+
 #[test]
 fn read_word() {
     let mut bus = Memory::default();
@@ -228,6 +301,7 @@ fn write_word() {
 }
 
 #[test]
+#[allow(clippy::explicit_counter_loop)]
 fn echo_ram() {
     let mut bus = Memory::default();
     bus.write_byte(INTERNAL_RAM_START + 1, 0xAA);
@@ -244,3 +318,40 @@ fn write_to_echo_ram() {
     bus.write_byte(ECHO_RAM_START + 10, 0xAA);
     assert_eq!(bus.read_byte(INTERNAL_RAM_START + 10), 0xAA);
 }
+
+#[test]
+fn interrupt_priority() {
+    let mut memory = Memory::default();
+    memory.request_interrupt(Interrupt::SerialLink);
+    memory.request_interrupt(Interrupt::TimerOverflow);
+    assert!(matches!(memory.return_interrupt(), Interrupt::NONE));
+    memory.write_byte(0xFFFF, 0x0C);
+    assert!(matches!(
+        memory.return_interrupt(),
+        Interrupt::TimerOverflow
+    ));
+    memory.interrupt_flag_off(&Interrupt::TimerOverflow);
+    assert!(matches!(memory.return_interrupt(), Interrupt::SerialLink));
+    assert_eq!(memory.read_byte(0xFF0F), 0x08);
+}
+
+#[test]
+fn wram_banks() {
+    let mut memory = Memory::default();
+    memory.write_byte(0xD000, 0x11);
+    memory.write_byte(0xFF70, 0x02);
+    assert_eq!(memory.read_byte(0xD000), 0x11, "The DMG has no banking");
+
+    memory.set_cgb(true);
+    memory.write_byte(0xFF70, 0x02);
+    assert_eq!(memory.read_byte(0xFF70), 0xFA);
+    assert_eq!(memory.read_byte(0xD000), 0x00);
+    memory.write_byte(0xD000, 0x22);
+    memory.write_byte(0xC000, 0x33);
+    memory.write_byte(0xFF70, 0x00);
+    assert_eq!(memory.wram_bank(), 1, "Bank 0 selects bank 1");
+    assert_eq!(memory.read_byte(0xD000), 0x11);
+    memory.write_byte(0xFF70, 0x02);
+    assert_eq!(memory.read_byte(0xF000), 0x22, "Echo RAM follows the bank");
+    assert_eq!(memory.read_byte(0xC000), 0x33, "Bank 0 is always at 0xC000");
+}
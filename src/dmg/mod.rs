@@ -1,12 +1,30 @@
 pub mod apu;
+pub mod audio;
 pub mod boot_rom;
 pub mod bus;
 pub mod busconnection;
 pub mod cartridge;
+pub mod coverage;
 pub mod cpu;
+pub mod debugger;
+pub mod disasm;
+pub mod dma;
+pub mod flat_bus;
+pub mod gbs;
+pub mod gdb;
 pub mod instructions;
 pub mod joypad;
+pub mod link;
 pub mod memory;
+pub mod model;
 pub mod ppu;
+pub mod printer;
+pub mod profiler;
 pub mod registers;
+pub mod rewind;
+pub mod save_state;
+pub mod serial;
+pub mod speed;
+pub mod symbols;
 pub mod timer;
+pub mod trace;
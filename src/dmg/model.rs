@@ -0,0 +1,48 @@
+use std::str::FromStr;
+
+/// The Game Boy being emulated
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum Model {
+    #[default]
+    Dmg,
+    Cgb,
+}
+
+impl Model {
+    /// The model a rom asks for with the CGB flag at 0x143 of its header.
+    /// Bit 7 is set by games that support the CGB's features, whether or not they also run on a DMG
+    /// See: https://gbdev.io/pandocs/The_Cartridge_Header.html#0143--cgb-flag
+    pub fn from_cgb_flag(flag: u8) -> Model {
+        if flag & 0x80 != 0 {
+            Model::Cgb
+        } else {
+            Model::Dmg
+        }
+    }
+}
+
+impl FromStr for Model {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Model, String> {
+        match s.to_lowercase().as_str() {
+            "dmg" => Ok(Model::Dmg),
+            "cgb" => Ok(Model::Cgb),
+            _ => Err(format!("{} isn't a model, use dmg or cgb", s)),
+        }
+    }
+}
+
+#[test]
+fn model_from_cgb_flag() {
+    assert_eq!(Model::from_cgb_flag(0x00), Model::Dmg);
+    assert_eq!(Model::from_cgb_flag(0x80), Model::Cgb);
+    assert_eq!(Model::from_cgb_flag(0xC0), Model::Cgb);
+}
+
+#[test]
+fn parse_model() {
+    assert_eq!("cgb".parse(), Ok(Model::Cgb));
+    assert_eq!("DMG".parse(), Ok(Model::Dmg));
+    assert!("gba".parse::<Model>().is_err());
+}
@@ -0,0 +1,40 @@
+const PRIORITY_POS: u8 = 7;
+const Y_FLIP_POS: u8 = 6;
+const X_FLIP_POS: u8 = 5;
+const VRAM_BANK_POS: u8 = 3;
+
+/// On the CGB each tile of the background and window maps has a byte of attributes,
+/// at the same address in VRAM bank 1 as the tile number is in bank 0
+/// See: https://gbdev.io/pandocs/Tile_Maps.html#bg-map-attributes-cgb-mode-only
+#[derive(Debug, PartialEq, Default)]
+pub struct BgMapAttributes {
+    /// The tile is drawn over objects
+    pub priority: bool,
+    pub y_flip: bool, // vertically mirrored
+    pub x_flip: bool, // horizontally mirrored
+    pub vram_bank: u8,
+    pub palette_number: u8,
+}
+
+impl std::convert::From<u8> for BgMapAttributes {
+    fn from(byte: u8) -> BgMapAttributes {
+        BgMapAttributes {
+            priority: (byte >> PRIORITY_POS) & 0b1 != 0,
+            y_flip: (byte >> Y_FLIP_POS) & 0b1 != 0,
+            x_flip: (byte >> X_FLIP_POS) & 0b1 != 0,
+            vram_bank: (byte >> VRAM_BANK_POS) & 0b1,
+            palette_number: byte & 0b111,
+        }
+    }
+}
+
+#[test]
+fn convert_byte_to_bg_map_attributes() {
+    let res = BgMapAttributes::from(0b1010_1101);
+    assert!(res.priority);
+    assert!(!res.y_flip);
+    assert!(res.x_flip);
+    assert_eq!(res.vram_bank, 1);
+    assert_eq!(res.palette_number, 5);
+    assert_eq!(BgMapAttributes::from(0), BgMapAttributes::default());
+}
@@ -38,7 +38,7 @@ impl Default for Lcdc {
             lcdc_enabled: false,
             window_tile_map_display_select: TileMap::S9800,
             window_display: false,
-            bg_window_tile_data_select: TileData::S8000,
+            bg_window_tile_data_select: TileData::S8800,
             bg_tile_map_data_select: TileMap::S9800,
             obj_size: ObjSize::S8x8,
             obj_display: false,
@@ -105,8 +105,8 @@ impl std::convert::From<&u8> for ObjSize {
 impl std::convert::From<&TileData> for u8 {
     fn from(data: &TileData) -> u8 {
         match data {
-            TileData::S8000 => 0,
-            TileData::S8800 => 16,
+            TileData::S8800 => 0,
+            TileData::S8000 => 16,
         }
     }
 }
@@ -114,8 +114,8 @@ impl std::convert::From<&TileData> for u8 {
 impl std::convert::From<&u8> for TileData {
     fn from(byte: &u8) -> TileData {
         match byte & 16 {
-            0 => TileData::S8000,
-            16 => TileData::S8800,
+            0 => TileData::S8800,
+            16 => TileData::S8000,
             _ => panic!("We've defied a law of mathematics!!"),
         }
     }
@@ -218,7 +218,7 @@ fn lcdc_to_u8() {
         lcdc_enabled: true,
         window_tile_map_display_select: TileMap::S9C00,
         window_display: false,
-        bg_window_tile_data_select: TileData::S8800,
+        bg_window_tile_data_select: TileData::S8000,
         bg_tile_map_data_select: TileMap::S9800,
         obj_size: ObjSize::S8x8,
         obj_display: true,
@@ -234,7 +234,7 @@ fn u8_to_lcdc() {
         lcdc_enabled: true,
         window_tile_map_display_select: TileMap::S9C00,
         window_display: false,
-        bg_window_tile_data_select: TileData::S8800,
+        bg_window_tile_data_select: TileData::S8000,
         bg_tile_map_data_select: TileMap::S9800,
         obj_size: ObjSize::S8x8,
         obj_display: true,
@@ -282,13 +282,13 @@ fn tile_map_to_u8() {
 #[test]
 fn u8_to_tile_data() {
     let byte = 0b0000_0000;
-    assert_eq!(TileData::from(&byte), TileData::S8000);
-    let byte = 0b0001_0000;
     assert_eq!(TileData::from(&byte), TileData::S8800);
+    let byte = 0b0001_0000;
+    assert_eq!(TileData::from(&byte), TileData::S8000);
 }
 
 #[test]
 fn tile_data_to_u8() {
-    assert_eq!(u8::from(&TileData::S8000), 0b0000_0000);
-    assert_eq!(u8::from(&TileData::S8800), 0b0001_0000);
+    assert_eq!(u8::from(&TileData::S8800), 0b0000_0000);
+    assert_eq!(u8::from(&TileData::S8000), 0b0001_0000);
 }
@@ -1,5 +1,8 @@
+pub mod bg_map_attributes;
 pub mod color;
 pub mod lcdc;
 pub mod oam;
+pub mod palette;
+#[allow(clippy::module_inception)]
 pub mod ppu;
 pub mod stat;
@@ -46,8 +46,8 @@ pub struct OamEntryFlag {
 
     /// These Flags are only required for use with
     /// the Color Gameboy
-    _cgb_tile_vram_bank: bool,
-    _cgb_palette_number: u8,
+    pub cgb_tile_vram_bank: bool,
+    pub cgb_palette_number: u8,
 }
 
 impl std::convert::From<u8> for OamEntryFlag {
@@ -56,15 +56,15 @@ impl std::convert::From<u8> for OamEntryFlag {
         let y_flip = (byte >> Y_FLIP_POS) & 0b1 != 0;
         let x_flip = (byte >> X_FLIP_POS) & 0b1 != 0;
         let palette_number = Palette::from(byte);
-        let _cgb_tile_vram_bank = (byte >> CGB_TILE_VRAM_BLANK_POS) & 0b1 != 0; // Required for CGB only
-        let _cgb_palette_number = 0; // Required for CGB only
+        let cgb_tile_vram_bank = (byte >> CGB_TILE_VRAM_BLANK_POS) & 0b1 != 0; // Required for CGB only
+        let cgb_palette_number = byte & 0b111; // Required for CGB only
         OamEntryFlag {
             obj_to_bg_priority,
             y_flip,
             x_flip,
             palette_number,
-            _cgb_tile_vram_bank,
-            _cgb_palette_number,
+            cgb_tile_vram_bank,
+            cgb_palette_number,
         }
     }
 }
@@ -80,12 +80,13 @@ impl std::convert::From<u8> for Palette {
 }
 
 #[test]
+#[allow(clippy::bool_assert_comparison)]
 fn convert_4_bytes_to_oam_entry() {
     let mut oam = [0u8; 160];
     oam[0] = 0x10;
     oam[1] = 0x20;
     oam[2] = 0x30;
-    oam[3] = 0b1101_0000;
+    oam[3] = 0b1101_1110;
     let res = OamEntry::new(oam, 0);
     assert_eq!(res.y_pos, 0x10 - 16);
     assert_eq!(res.x_pos, 0x20 - 8);
@@ -94,11 +95,12 @@ fn convert_4_bytes_to_oam_entry() {
     assert_eq!(res.attributes.y_flip, true);
     assert_eq!(res.attributes.x_flip, false);
     assert_eq!(res.attributes.palette_number, Palette::Obp1);
-
-    // Check the rest of the attributes for CGB
+    assert!(res.attributes.cgb_tile_vram_bank);
+    assert_eq!(res.attributes.cgb_palette_number, 6);
 }
 
 #[test]
+#[allow(clippy::bool_assert_comparison)]
 fn convert_byte_to_oamentryflag() {
     let byte = 0b1011_0000;
     let res = OamEntryFlag::from(byte);
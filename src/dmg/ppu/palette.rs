@@ -0,0 +1,108 @@
+use crate::dmg::save_state::{SaveState, StateReader, StateWriter};
+
+/// One of the CGB's two palette memories, 8 palettes of 4 colors for the background or objects.
+/// The specification register (BCPS/OCPS) picks a byte, the data register (BCPD/OCPD) reads
+/// or writes it and can move on to the next one. Colors are 15-bit RGB, little endian.
+/// See: https://gbdev.io/pandocs/Palettes.html#lcd-color-palettes-cgb-only
+pub struct ColorPalettes {
+    memory: [u8; 64],
+
+    /// The byte the data register accesses
+    index: u8,
+
+    /// Moves on to the next byte after each write to the data register
+    auto_increment: bool,
+}
+
+impl Default for ColorPalettes {
+    fn default() -> ColorPalettes {
+        // Every color starts out white
+        ColorPalettes {
+            memory: [0xFF; 64],
+            index: 0,
+            auto_increment: false,
+        }
+    }
+}
+
+impl ColorPalettes {
+    pub fn specification(&self) -> u8 {
+        (self.auto_increment as u8) << 7 | 0x40 | self.index
+    }
+
+    pub fn set_specification(&mut self, value: u8) {
+        self.auto_increment = value & 0x80 != 0;
+        self.index = value & 0x3F;
+    }
+
+    pub fn data(&self) -> u8 {
+        self.memory[self.index as usize]
+    }
+
+    pub fn set_data(&mut self, value: u8) {
+        self.memory[self.index as usize] = value;
+        if self.auto_increment {
+            self.index = (self.index + 1) & 0x3F;
+        }
+    }
+
+    /// The color as 0RGB, like the rest of the screen
+    pub fn rgb(&self, palette: u8, color: u8) -> u32 {
+        let index = (palette as usize * 4 + color as usize) * 2;
+        rgb555_to_rgb(u16::from_le_bytes([
+            self.memory[index],
+            self.memory[index + 1],
+        ]))
+    }
+}
+
+impl SaveState for ColorPalettes {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.memory);
+        state.u8(self.specification());
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        state.bytes(&mut self.memory)?;
+        self.set_specification(state.u8()?);
+        Ok(())
+    }
+}
+
+/// Scales each 5-bit channel of a CGB color up to 8 bits, red is in the low bits
+fn rgb555_to_rgb(color: u16) -> u32 {
+    let channel = |shift: u16| {
+        let value = ((color >> shift) & 0x1F) as u32;
+        (value << 3) | (value >> 2)
+    };
+    channel(0) << 16 | channel(5) << 8 | channel(10)
+}
+
+#[test]
+fn color_conversion() {
+    assert_eq!(rgb555_to_rgb(0x7FFF), 0xFFFFFF);
+    assert_eq!(rgb555_to_rgb(0x0000), 0x000000);
+    assert_eq!(rgb555_to_rgb(0x001F), 0xFF0000);
+    assert_eq!(rgb555_to_rgb(0x03E0), 0x00FF00);
+    assert_eq!(rgb555_to_rgb(0x7C00), 0x0000FF);
+}
+
+#[test]
+fn data_auto_increments() {
+    let mut palettes = ColorPalettes::default();
+    palettes.set_specification(0x80 | 0x3E);
+    assert_eq!(palettes.specification(), 0xFE);
+    palettes.set_data(0x1F);
+    palettes.set_data(0x00);
+    assert_eq!(palettes.specification(), 0xC0, "The index wraps around");
+    assert_eq!(palettes.rgb(7, 3), 0xFF0000);
+
+    palettes.set_specification(0x02);
+    palettes.set_data(0xE0);
+    assert_eq!(
+        palettes.specification(),
+        0x42,
+        "Without auto increment the index stays put"
+    );
+    assert_eq!(palettes.data(), 0xE0);
+}
@@ -1,9 +1,12 @@
 use crate::dmg::busconnection::BusConnection;
+use crate::dmg::ppu::bg_map_attributes::BgMapAttributes;
 use crate::dmg::ppu::color::Color;
 use crate::dmg::ppu::lcdc::{Lcdc, TileData};
+use crate::dmg::ppu::palette::ColorPalettes;
 use crate::dmg::ppu::stat::{LcdMode, Stat};
+use crate::dmg::save_state::{SaveState, StateReader, StateWriter};
 use log::info;
-use minifb::{Scale, Window, WindowOptions};
+use minifb::{Key, KeyRepeat, Scale, Window, WindowOptions};
 
 // The number of CPU cycles taken to draw one scanline
 const SCANLINE_COUNTER_MAX: i32 = 456;
@@ -17,8 +20,8 @@ const SEARCHING_FOR_SPRITES: i32 = 376;
 const TRANSFERING_TO_LCD_DRIVER: i32 = 204;
 
 // DMG Screen Dimentions
-const WIDTH: usize = 160; // 0xA0
-const HEIGHT: usize = 144; // 0x90
+pub const WIDTH: usize = 160; // 0xA0
+pub const HEIGHT: usize = 144; // 0x90
 
 // The DMG screen resolution is 160x144 meaning there are 144 visible lines
 // Everything afterwards is invisible.
@@ -45,11 +48,22 @@ pub struct PPU {
     scanline_counter: i32,
     vram: [u8; 8192],
 
+    /// The CGB's second bank of VRAM, with more tiles and the attributes of the tile maps
+    vram_bank_1: [u8; 8192],
+    vbk: u8, // 0xFF4F
+
+    bg_palettes: ColorPalettes,  // 0xFF68 - 0xFF69
+    obj_palettes: ColorPalettes, // 0xFF6A - 0xFF6B
+    cgb: bool,
+
     /// An array of 40, 4-byte objects
     oam: [u8; 160], // could also be [u32; 40]
 
     screen: [[u32; WIDTH]; HEIGHT],
     window: Option<Window>,
+    pressed_keys: Vec<Key>,
+    keys_down: Vec<Key>,
+    stub_ly: bool,
 }
 
 impl Default for PPU {
@@ -69,9 +83,17 @@ impl Default for PPU {
             wx: 0,
             scanline_counter: SCANLINE_COUNTER_MAX, // Similar to the timer counter and how we count down. There are 456 dots per scanline,
             vram: [0; 8192],
+            vram_bank_1: [0; 8192],
+            vbk: 0,
+            bg_palettes: ColorPalettes::default(),
+            obj_palettes: ColorPalettes::default(),
+            cgb: false,
             oam: [0; 160],
             screen: [[Color::White.rgb(); WIDTH]; HEIGHT],
             window,
+            pressed_keys: Vec::new(),
+            keys_down: Vec::new(),
+            stub_ly: false,
         }
     }
 }
@@ -85,6 +107,7 @@ impl BusConnection for PPU {
             0xFF41 => u8::from(&self.stat),
             0xFF42 => self.scy,
             0xFF43 => self.scx,
+            0xFF44 if self.stub_ly => 0x90,
             0xFF44 => self.ly,
             0xFF45 => self.lyc,
             0xFF47 => self.bgp,
@@ -92,6 +115,13 @@ impl BusConnection for PPU {
             0xFF49 => self.obp1,
             0xFF4A => self.wy,
             0xFF4B => self.wx,
+            0xFF4F if self.cgb => 0xFE | self.vbk,
+            0xFF68 if self.cgb => self.bg_palettes.specification(),
+            0xFF69 if self.cgb && self.palettes_accessible() => self.bg_palettes.data(),
+            0xFF6A if self.cgb => self.obj_palettes.specification(),
+            0xFF6B if self.cgb && self.palettes_accessible() => self.obj_palettes.data(),
+            // The CGB registers read 0xFF on the DMG
+            0xFF4F | 0xFF68..=0xFF6B => 0xFF,
             _ => panic!("This should never happen! Address: {:#02x}", address),
         }
     }
@@ -111,6 +141,12 @@ impl BusConnection for PPU {
             0xFF49 => self.obp1 = value,
             0xFF4A => self.wy = value,
             0xFF4B => self.wx = value,
+            0xFF4F if self.cgb => self.vbk = value & 0x01,
+            0xFF68 if self.cgb => self.bg_palettes.set_specification(value),
+            0xFF69 if self.cgb && self.palettes_accessible() => self.bg_palettes.set_data(value),
+            0xFF6A if self.cgb => self.obj_palettes.set_specification(value),
+            0xFF6B if self.cgb && self.palettes_accessible() => self.obj_palettes.set_data(value),
+            0xFF4F | 0xFF68..=0xFF6B => {}
             _ => panic!("This should never happen! Address: {:#02x}", address),
         }
     }
@@ -133,12 +169,25 @@ impl PPU {
             wx: 0,
             scanline_counter: SCANLINE_COUNTER_MAX, // Similar to the timer counter and how we count down. There are 456 dots per scanline,
             vram: [0; 8192],
+            vram_bank_1: [0; 8192],
+            vbk: 0,
+            bg_palettes: ColorPalettes::default(),
+            obj_palettes: ColorPalettes::default(),
+            cgb: false,
             oam: [0; 160],
             screen: [[Color::White.rgb(); WIDTH]; HEIGHT],
             window,
+            pressed_keys: Vec::new(),
+            keys_down: Vec::new(),
+            stub_ly: false,
         }
     }
 
+    /// Switches on the CGB's VRAM bank and color palettes
+    pub fn set_cgb(&mut self, cgb: bool) {
+        self.cgb = cgb;
+    }
+
     pub fn step(&mut self, cycles: u8) {
         self.set_lcd_status();
 
@@ -161,6 +210,7 @@ impl PPU {
         }
     }
 
+    #[allow(clippy::single_match)]
     fn draw_graphics(&mut self) {
         let mut buf = Vec::new();
         for i in 0..self.screen.len() {
@@ -169,11 +219,31 @@ impl PPU {
             }
         }
         match &mut self.window {
-            Some(window) => window.update_with_buffer(&buf, WIDTH, HEIGHT).unwrap(),
+            Some(window) => {
+                window.update_with_buffer(&buf, WIDTH, HEIGHT).unwrap();
+                // Keys are only polled when the window updates, so we hold on to them until asked
+                self.pressed_keys
+                    .extend(window.get_keys_pressed(KeyRepeat::No));
+                self.keys_down = window.get_keys();
+            }
             None => {}
         };
     }
 
+    pub fn take_pressed_keys(&mut self) -> Vec<Key> {
+        std::mem::take(&mut self.pressed_keys)
+    }
+
+    /// Makes LY always read 0x90, as if in vblank, for comparing traces with other emulators
+    pub fn stub_ly(&mut self, stub: bool) {
+        self.stub_ly = stub;
+    }
+
+    /// Whether the key was held down the last time the window updated
+    pub fn is_key_down(&self, key: Key) -> bool {
+        self.keys_down.contains(&key)
+    }
+
     fn set_lcd_status(&mut self) {
         if !self.lcdc.lcdc_enabled() {
             self.scanline_counter = SCANLINE_COUNTER_MAX;
@@ -212,7 +282,8 @@ impl PPU {
     }
 
     fn draw_scanline(&mut self) {
-        if self.lcdc.bg_window_display() {
+        // On the CGB bit 0 of LCDC gives objects priority over the background instead of hiding it
+        if self.cgb || self.lcdc.bg_window_display() {
             self.render_tiles();
         }
 
@@ -224,6 +295,7 @@ impl PPU {
     }
 
     /// Renders the window and background tiles
+    #[allow(clippy::unnecessary_cast)]
     fn render_tiles(&mut self) {
         let window_x = self.wx.wrapping_sub(7);
         let using_window = self.lcdc.window_display() && self.wy <= self.ly;
@@ -255,27 +327,45 @@ impl PPU {
 
             let tile_address = background_memory + tile_row as u16 + tile_column as u16;
             let tile_location = if unsigned {
-                let tile_num = self.read_byte(tile_address);
+                let tile_num = self.vram_byte(0, tile_address);
                 tile_data + (tile_num as u16 * 16)
             } else {
-                let tile_num = self.read_byte(tile_address) as i8;
+                let tile_num = self.vram_byte(0, tile_address) as i8;
                 tile_data + (i16::from(tile_num) as u16 + 128) * 16
             };
+            let attributes = if self.cgb {
+                BgMapAttributes::from(self.vram_byte(1, tile_address))
+            } else {
+                BgMapAttributes::default()
+            };
 
-            let line = (y_pos % 8) * 2;
-            let data1 = self.read_byte(tile_location + line as u16);
-            let data2 = self.read_byte(tile_location + line as u16 + 1);
+            let row = if attributes.y_flip {
+                7 - (y_pos % 8)
+            } else {
+                y_pos % 8
+            };
+            let line = row * 2;
+            let data1 = self.vram_byte(attributes.vram_bank, tile_location + line as u16);
+            let data2 = self.vram_byte(attributes.vram_bank, tile_location + line as u16 + 1);
 
             // pixel 0 is 7, 1 is 6 etc.
-            let colour_bit = 7 - (x_pos % 8);
+            let colour_bit = if attributes.x_flip {
+                x_pos % 8
+            } else {
+                7 - (x_pos % 8)
+            };
 
             let mut colour_num = get_pos_from_byte(data2, colour_bit);
             colour_num <<= 1;
             colour_num |= get_pos_from_byte(data1, colour_bit);
 
-            let col = get_color(colour_num, self.bgp);
+            let rgb = if self.cgb {
+                self.bg_palettes.rgb(attributes.palette_number, colour_num)
+            } else {
+                get_color(colour_num, self.bgp).rgb()
+            };
             if self.ly - 1 <= 143 && pixel <= 159 {
-                self.screen[self.ly as usize - 1][pixel as usize] = col.rgb();
+                self.screen[self.ly as usize - 1][pixel as usize] = rgb;
             }
         }
     }
@@ -291,7 +381,7 @@ impl PPU {
         if self.stat.mode_flag == LcdMode::TransferingDataToLCDDriver {
             0xFF
         } else {
-            self.vram[(address - 0x8000) as usize]
+            self.vram_byte(self.vbk, address)
         }
     }
 
@@ -300,10 +390,31 @@ impl PPU {
     fn write_vram(&mut self, address: u16, value: u8) {
         if self.stat.mode_flag != LcdMode::TransferingDataToLCDDriver {
             info!("Writing: {:#02x} to vram address {:#02x}", value, address);
-            self.vram[(address - 0x8000) as usize] = value;
+            let vram = if self.vbk == 1 {
+                &mut self.vram_bank_1
+            } else {
+                &mut self.vram
+            };
+            vram[(address - 0x8000) as usize] = value;
         }
     }
 
+    /// Reads from a bank of VRAM whichever mode the PPU is in
+    fn vram_byte(&self, bank: u8, address: u16) -> u8 {
+        let vram = if bank == 1 {
+            &self.vram_bank_1
+        } else {
+            &self.vram
+        };
+        vram[(address - 0x8000) as usize]
+    }
+
+    /// Like VRAM, palette data can't be accessed during mode 3
+    /// See: https://gbdev.io/pandocs/Palettes.html#lcd-color-palettes-cgb-only
+    fn palettes_accessible(&self) -> bool {
+        self.stat.mode_flag != LcdMode::TransferingDataToLCDDriver
+    }
+
     /// OAM is only accessible during Modes 0 & 1
     /// Reading when the mode flag is set to 2 or 3 will return the default value of 0xFF
     /// See: https://gbdev.io/pandocs/#accessing-vram-and-oam for more info
@@ -322,6 +433,16 @@ impl PPU {
         }
     }
 
+    /// OAM DMA writes to OAM whatever mode the PPU is in
+    pub fn dma_write(&mut self, index: u8, value: u8) {
+        self.oam[index as usize] = value;
+    }
+
+    /// The last frame drawn, as 0RGB pixels
+    pub fn screen(&self) -> &[[u32; WIDTH]; HEIGHT] {
+        &self.screen
+    }
+
     #[cfg(test)]
     pub fn lcdc(&self) -> u8 {
         u8::from(&self.lcdc)
@@ -392,7 +513,59 @@ fn default_window() -> Option<Window> {
     Some(window)
 }
 
+// The window and the keys pressed in it aren't part of the state
+impl SaveState for PPU {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u8(u8::from(&self.lcdc));
+        state.u8(u8::from(&self.stat));
+        for register in [
+            self.scy, self.scx, self.ly, self.lyc, self.bgp, self.obp0, self.obp1, self.wy, self.wx,
+        ] {
+            state.u8(register);
+        }
+        state.u32(self.scanline_counter as u32);
+        state.bytes(&self.vram);
+        state.bytes(&self.oam);
+        for pixel in self.screen.iter().flatten() {
+            state.u32(*pixel);
+        }
+        state.bytes(&self.vram_bank_1);
+        state.u8(self.vbk);
+        self.bg_palettes.save_state(state);
+        self.obj_palettes.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.lcdc = Lcdc::from(&state.u8()?);
+        self.stat = Stat::from(&state.u8()?);
+        for register in [
+            &mut self.scy,
+            &mut self.scx,
+            &mut self.ly,
+            &mut self.lyc,
+            &mut self.bgp,
+            &mut self.obp0,
+            &mut self.obp1,
+            &mut self.wy,
+            &mut self.wx,
+        ] {
+            *register = state.u8()?;
+        }
+        self.scanline_counter = state.u32()? as i32;
+        state.bytes(&mut self.vram)?;
+        state.bytes(&mut self.oam)?;
+        for pixel in self.screen.iter_mut().flatten() {
+            *pixel = state.u32()?;
+        }
+        state.bytes(&mut self.vram_bank_1)?;
+        self.vbk = state.u8()?;
+        self.bg_palettes.load_state(state)?;
+        self.obj_palettes.load_state(state)
+    }
+}
+
 #[test]
+#[allow(clippy::bool_assert_comparison)]
 fn lcd_is_enabled() {
     let mut ppu = PPU::default();
     assert_eq!(ppu.lcdc.lcdc_enabled(), false);
@@ -401,6 +574,7 @@ fn lcd_is_enabled() {
 }
 
 #[test]
+#[allow(clippy::field_reassign_with_default)]
 fn ly_inc() {
     let mut ppu = PPU::default();
     ppu.lcdc = Lcdc::from(&255);
@@ -417,6 +591,7 @@ fn ly_inc() {
 }
 
 #[test]
+#[allow(clippy::field_reassign_with_default)]
 fn draw_one_scanline_at_a_time() { 
     let mut ppu = PPU::default();
     ppu.lcdc = Lcdc::from(&255);
@@ -516,6 +691,7 @@ fn write_to_oam() {
 }
 
 #[test]
+#[allow(clippy::mixed_case_hex_literals)]
 fn write_to_end_of_oam() {
     let mut ppu = PPU::default();
     ppu.write_byte(0xFE9F, 0xAA);
@@ -553,6 +729,56 @@ fn write_oam_when_stat_mode_3() {
     );
 }
 
+#[test]
+fn cgb_registers_read_0xff_on_dmg() {
+    let mut ppu = PPU::default();
+    ppu.write_byte(0xFF4F, 0x01);
+    assert_eq!(ppu.read_byte(0xFF4F), 0xFF);
+    assert_eq!(ppu.read_byte(0xFF69), 0xFF);
+    ppu.set_cgb(true);
+    assert_eq!(ppu.read_byte(0xFF4F), 0xFE);
+}
+
+#[test]
+fn write_to_vram_bank_1() {
+    let mut ppu = PPU::default();
+    ppu.set_cgb(true);
+    ppu.write_byte(0x9000, 0xAA);
+    ppu.write_byte(0xFF4F, 0x01);
+    assert_eq!(ppu.read_byte(0xFF4F), 0xFF);
+    assert_eq!(ppu.read_byte(0x9000), 0x00);
+    ppu.write_byte(0x9000, 0xBB);
+    assert_eq!(ppu.vram()[0x1000], 0xAA);
+    assert_eq!(ppu.vram_bank_1[0x1000], 0xBB);
+}
+
+#[test]
+fn cgb_background_attributes() {
+    let mut ppu = PPU {
+        lcdc: Lcdc::from(&0x91),
+        ..PPU::default()
+    };
+    ppu.set_cgb(true);
+    // Tile 1 of bank 1 only has its leftmost pixels set to color 1
+    ppu.write_byte(0xFF4F, 0x01);
+    for row in 0..8 {
+        ppu.write_byte(0x8010 + row * 2, 0x80);
+    }
+    // The first tile of the map is that one flipped horizontally, with palette 3
+    ppu.write_byte(0x9800, 0b0010_1011);
+    ppu.write_byte(0xFF4F, 0x00);
+    ppu.write_byte(0x9800, 0x01);
+    // Color 1 of palette 3 is red
+    ppu.write_byte(0xFF68, 0x80 | (3 * 8 + 2));
+    ppu.write_byte(0xFF69, 0x1F);
+    ppu.write_byte(0xFF69, 0x00);
+
+    ppu.step(255);
+    ppu.step(201);
+    assert_eq!(ppu.screen[0][7], 0xFF0000);
+    assert_eq!(ppu.screen[0][0], 0xFFFFFF, "Palettes start out white");
+}
+
 #[test]
 fn get_bit_at() {
     let bit = get_pos_from_byte(0b1000_1000, 7);
@@ -0,0 +1,362 @@
+use crate::dmg::serial::SerialDevice;
+use log::{error, info};
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+// The printer is 160 pixels, or 20 tiles, wide
+// See: https://gbdev.io/pandocs/Gameboy_Printer.html
+const WIDTH: usize = 160;
+const TILES_PER_ROW: usize = WIDTH / 8;
+const BYTES_PER_TILE_ROW: usize = TILES_PER_ROW * 16;
+
+// The printer's ram holds 9 data packets of 2 tile rows each
+const BUFFER_SIZE: usize = 9 * 2 * BYTES_PER_TILE_ROW;
+
+const INIT: u8 = 0x01;
+const PRINT: u8 = 0x02;
+const DATA: u8 = 0x04;
+const STATUS: u8 = 0x0F;
+
+// Status bits
+const CHECKSUM_ERROR: u8 = 0x01;
+const PRINTING: u8 = 0x02;
+const IMAGE_DATA_FULL: u8 = 0x04;
+const UNPROCESSED_DATA: u8 = 0x08;
+
+// The number of status requests for which we pretend to still be printing
+const PRINTING_STATUS_REQUESTS: u8 = 4;
+
+// The grey levels of the 4 shades a palette can select
+const SHADES: [u8; 4] = [0xFF, 0xAA, 0x55, 0x00];
+
+// Where we are in the packet, each byte the game sends moves us along
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    Magic1,
+    Magic2,
+    Command,
+    Compression,
+    LengthLow,
+    LengthHigh,
+    Data,
+    ChecksumLow,
+    ChecksumHigh,
+    Alive,
+    Status,
+}
+
+/// The Game Boy Printer. Each printout is written as a PNG file to a directory
+pub struct Printer {
+    directory: PathBuf,
+    state: State,
+    command: u8,
+    compressed: bool,
+    length: u16,
+    data: Vec<u8>,
+    checksum: u16,
+    received_checksum: u16,
+
+    buffer: Vec<u8>,
+    status: u8,
+    printing_requests: u8,
+    printouts: Vec<PathBuf>,
+}
+
+impl Printer {
+    pub fn new(directory: &str) -> Printer {
+        Printer {
+            directory: PathBuf::from(directory),
+            state: State::Magic1,
+            command: 0,
+            compressed: false,
+            length: 0,
+            data: Vec::new(),
+            checksum: 0,
+            received_checksum: 0,
+            buffer: Vec::new(),
+            status: 0,
+            printing_requests: 0,
+            printouts: Vec::new(),
+        }
+    }
+
+    /// The files written so far
+    pub fn printouts(&self) -> &[PathBuf] {
+        &self.printouts
+    }
+
+    fn status(&self) -> u8 {
+        if self.printing_requests > 0 {
+            self.status | PRINTING
+        } else {
+            self.status
+        }
+    }
+
+    fn execute(&mut self) {
+        if self.checksum != self.received_checksum {
+            error!("Printer packet checksum mismatch");
+            self.status |= CHECKSUM_ERROR;
+            return;
+        }
+        self.status &= !CHECKSUM_ERROR;
+        match self.command {
+            INIT => {
+                self.buffer.clear();
+                self.status = 0;
+                self.printing_requests = 0;
+            }
+            DATA => {
+                let data = if self.compressed {
+                    decompress(&self.data)
+                } else {
+                    std::mem::take(&mut self.data)
+                };
+                let space = BUFFER_SIZE - self.buffer.len();
+                self.buffer.extend(data.iter().take(space));
+                if !self.buffer.is_empty() {
+                    self.status |= UNPROCESSED_DATA;
+                }
+                if self.buffer.len() == BUFFER_SIZE {
+                    self.status |= IMAGE_DATA_FULL;
+                }
+            }
+            PRINT => {
+                if self.data.len() < 4 {
+                    error!("Print command without its 4 arguments");
+                    return;
+                }
+                let (sheets, margins, palette) = (self.data[0], self.data[1], self.data[2]);
+                // The exposure in data[3] only changes how dark the thermal paper gets
+                if sheets > 0 {
+                    self.print(margins >> 4, margins & 0x0F, palette);
+                }
+                self.buffer.clear();
+                self.status &= !(UNPROCESSED_DATA | IMAGE_DATA_FULL);
+                self.printing_requests = PRINTING_STATUS_REQUESTS;
+            }
+            STATUS => self.printing_requests = self.printing_requests.saturating_sub(1),
+            _ => error!("Unknown printer command {:#04x}", self.command),
+        }
+    }
+
+    /// Margins are in lines of paper feed, 8 pixels each
+    fn print(&mut self, margin_before: u8, margin_after: u8, palette: u8) {
+        let mut pixels = vec![SHADES[0]; margin_before as usize * 8 * WIDTH];
+        pixels.extend(decode_tiles(&self.buffer, palette));
+        pixels.extend(vec![SHADES[0]; margin_after as usize * 8 * WIDTH]);
+        if pixels.is_empty() {
+            return;
+        }
+
+        let path = next_file(&self.directory);
+        match write_png(&path, &pixels) {
+            Ok(()) => {
+                info!("Printed {}", path.display());
+                self.printouts.push(path);
+            }
+            Err(err) => error!("Error writing {}: {}", path.display(), err),
+        }
+    }
+}
+
+impl SerialDevice for Printer {
+    fn transfer(&mut self, byte: u8) -> u8 {
+        let mut response = 0x00;
+        self.state = match self.state {
+            State::Magic1 if byte == 0x88 => State::Magic2,
+            State::Magic1 => State::Magic1,
+            State::Magic2 if byte == 0x33 => State::Command,
+            State::Magic2 => State::Magic1,
+            State::Command => {
+                self.command = byte;
+                self.checksum = byte as u16;
+                State::Compression
+            }
+            State::Compression => {
+                self.compressed = byte & 0b1 == 1;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                State::LengthLow
+            }
+            State::LengthLow => {
+                self.length = byte as u16;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                State::LengthHigh
+            }
+            State::LengthHigh => {
+                self.length |= (byte as u16) << 8;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                self.data.clear();
+                if self.length == 0 {
+                    State::ChecksumLow
+                } else {
+                    State::Data
+                }
+            }
+            State::Data => {
+                self.data.push(byte);
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                if self.data.len() == self.length as usize {
+                    State::ChecksumLow
+                } else {
+                    State::Data
+                }
+            }
+            State::ChecksumLow => {
+                self.received_checksum = byte as u16;
+                State::ChecksumHigh
+            }
+            State::ChecksumHigh => {
+                self.received_checksum |= (byte as u16) << 8;
+                State::Alive
+            }
+            State::Alive => {
+                response = 0x81;
+                State::Status
+            }
+            State::Status => {
+                self.execute();
+                response = self.status();
+                State::Magic1
+            }
+        };
+        response
+    }
+}
+
+/// Expands the printer's run length encoding. A control byte with the top bit set
+/// repeats the next byte (control & 0x7F) + 2 times, otherwise (control + 1) bytes follow
+fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let control = data[i];
+        i += 1;
+        if control & 0x80 == 0x80 {
+            if let Some(byte) = data.get(i) {
+                output.extend(std::iter::repeat_n(*byte, (control & 0x7F) as usize + 2));
+            }
+            i += 1;
+        } else {
+            let end = (i + control as usize + 1).min(data.len());
+            output.extend_from_slice(&data[i..end]);
+            i = end;
+        }
+    }
+    output
+}
+
+/// Turns rows of 20 2bpp tiles into one grey level per pixel
+fn decode_tiles(data: &[u8], palette: u8) -> Vec<u8> {
+    let rows = data.len() / BYTES_PER_TILE_ROW;
+    let mut pixels = vec![0u8; rows * 8 * WIDTH];
+    for (i, tile) in data[..rows * BYTES_PER_TILE_ROW].chunks(16).enumerate() {
+        let (tile_x, tile_y) = (i % TILES_PER_ROW, i / TILES_PER_ROW);
+        for line in 0..8 {
+            let (low, high) = (tile[line * 2], tile[line * 2 + 1]);
+            for bit in 0..8 {
+                let color = ((high >> (7 - bit)) & 0b1) << 1 | ((low >> (7 - bit)) & 0b1);
+                let shade = (palette >> (color * 2)) & 0b11;
+                let (x, y) = (tile_x * 8 + bit, tile_y * 8 + line);
+                pixels[y * WIDTH + x] = SHADES[shade as usize];
+            }
+        }
+    }
+    pixels
+}
+
+// The first print-NNNN.png that doesn't exist yet
+fn next_file(directory: &Path) -> PathBuf {
+    (1..)
+        .map(|i| directory.join(format!("print-{:04}.png", i)))
+        .find(|path| !path.exists())
+        .unwrap()
+}
+
+fn write_png(path: &Path, pixels: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+    std::fs::create_dir_all(path.parent().unwrap_or(Path::new(".")))?;
+    let file = BufWriter::new(File::create(path)?);
+    let mut encoder = png::Encoder::new(file, WIDTH as u32, (pixels.len() / WIDTH) as u32);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.write_header()?.write_image_data(pixels)?;
+    Ok(())
+}
+
+#[cfg(test)]
+fn send_packet(printer: &mut Printer, command: u8, compressed: bool, data: &[u8]) -> (u8, u8) {
+    let length = (data.len() as u16).to_le_bytes();
+    let mut packet = vec![command, compressed as u8, length[0], length[1]];
+    packet.extend_from_slice(data);
+    let checksum = packet
+        .iter()
+        .fold(0u16, |sum, b| sum.wrapping_add(*b as u16));
+    packet.extend_from_slice(&checksum.to_le_bytes());
+    for byte in [0x88, 0x33].iter().chain(packet.iter()) {
+        assert_eq!(printer.transfer(*byte), 0x00);
+    }
+    (printer.transfer(0x00), printer.transfer(0x00))
+}
+
+#[test]
+fn decompress_runs_and_literals() {
+    assert_eq!(
+        decompress(&[0x81, 0xAA, 0x01, 0x12, 0x34, 0x80, 0xFF]),
+        vec![0xAA, 0xAA, 0xAA, 0x12, 0x34, 0xFF, 0xFF]
+    );
+}
+
+#[test]
+fn status_packets() {
+    let mut printer = Printer::new("unused");
+    assert_eq!(send_packet(&mut printer, INIT, false, &[]), (0x81, 0x00));
+    let data = vec![0u8; BYTES_PER_TILE_ROW * 2];
+    assert_eq!(
+        send_packet(&mut printer, DATA, false, &data),
+        (0x81, UNPROCESSED_DATA)
+    );
+    assert_eq!(
+        send_packet(&mut printer, STATUS, false, &[]),
+        (0x81, UNPROCESSED_DATA)
+    );
+
+    // A bad checksum
+    for byte in [0x88, 0x33, STATUS, 0, 0, 0, 0xFF, 0xFF] {
+        printer.transfer(byte);
+    }
+    assert_eq!(printer.transfer(0), 0x81);
+    assert_eq!(printer.transfer(0), UNPROCESSED_DATA | CHECKSUM_ERROR);
+}
+
+#[test]
+fn prints_png() {
+    let directory = std::env::temp_dir().join("oxide_boy_printer_test");
+    let _ = std::fs::remove_dir_all(&directory);
+    let mut printer = Printer::new(directory.to_str().unwrap());
+
+    // One tile row where the first tile's top line uses color 3, the rest color 0.
+    // Compressed as: 2 literal bytes, then a run of the remaining zeros
+    let mut data = vec![0x01, 0xFF, 0xFF];
+    let mut zeros = BYTES_PER_TILE_ROW - 2;
+    while zeros > 0 {
+        let run = zeros.min(0x81);
+        data.extend_from_slice(&[0x80 | (run - 2) as u8, 0x00]);
+        zeros -= run;
+    }
+    send_packet(&mut printer, INIT, false, &[]);
+    send_packet(&mut printer, DATA, true, &data);
+    send_packet(&mut printer, DATA, false, &[]);
+    // 1 sheet, 1 line of margin after, the palette maps color 3 to black
+    let (_, status) = send_packet(&mut printer, PRINT, false, &[1, 0x01, 0xE4, 0x40]);
+    assert_eq!(status & PRINTING, PRINTING);
+
+    assert_eq!(printer.printouts().len(), 1);
+    let decoder = png::Decoder::new(File::open(&printer.printouts()[0]).unwrap());
+    let mut reader = decoder.read_info().unwrap();
+    let mut pixels = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut pixels).unwrap();
+    assert_eq!((info.width, info.height), (160, 16));
+    assert_eq!(&pixels[0..9], &[0, 0, 0, 0, 0, 0, 0, 0, 0xFF]);
+    assert!(pixels[WIDTH..].iter().all(|p| *p == 0xFF));
+}
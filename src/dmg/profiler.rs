@@ -0,0 +1,302 @@
+use crate::dmg::rewind::FRAME_CYCLES;
+use crate::dmg::symbols::Symbols;
+use log::error;
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+// The emulator is usually closed rather than exited, so the profile is written about once a second,
+// twice as many cycles apart in double speed
+const SAVE_CYCLES: u64 = FRAME_CYCLES as u64 * 60;
+
+const ROOT: usize = 0;
+
+/// A function is the bank and address it was called at
+type Function = (u16, u16);
+
+// A function as called through a particular chain of calls
+struct Node {
+    function: Function,
+    parent: usize,
+    children: HashMap<Function, usize>,
+    cycles: u64,
+    calls: u64,
+}
+
+impl Node {
+    fn new(function: Function, parent: usize) -> Node {
+        Node {
+            function,
+            parent,
+            children: HashMap::new(),
+            cycles: 0,
+            calls: 0,
+        }
+    }
+}
+
+/// Keeps a shadow call stack from the calls, restarts, interrupts and returns the cpu runs,
+/// and counts the cycles spent in each function under each chain of callers.
+pub struct Profiler {
+    nodes: Vec<Node>,
+    // The node being run by each call, with the stack pointer just after the call
+    stack: Vec<(usize, u16)>,
+    total: u64,
+    saved_at: u64,
+    double_speed: bool,
+    symbols: Symbols,
+    file: Option<PathBuf>,
+}
+
+impl Default for Profiler {
+    fn default() -> Profiler {
+        Profiler {
+            nodes: vec![Node::new((0, 0), ROOT)],
+            stack: vec![],
+            total: 0,
+            saved_at: 0,
+            double_speed: false,
+            symbols: Symbols::default(),
+            file: None,
+        }
+    }
+}
+
+impl Profiler {
+    /// Writes the flat profile to `file` and the collapsed stacks to `file`.folded
+    pub fn create(file: &str) -> Profiler {
+        let mut profiler = Profiler::default();
+        profiler.file = Some(PathBuf::from(file));
+        profiler
+    }
+
+    pub fn set_symbols(&mut self, symbols: Symbols) {
+        self.symbols = symbols;
+    }
+
+    pub fn set_double_speed(&mut self, double_speed: bool) {
+        self.double_speed = double_speed;
+    }
+
+    /// Counts an instruction towards the current function, then follows it if it was a
+    /// call or a return, told apart from untaken ones by how the stack pointer moved
+    pub fn instruction(&mut self, opcode: u8, cycles: u8, old_sp: u16, sp: u16, pc: Function) {
+        self.cycles(cycles);
+        let call = matches!(opcode, 0xC4 | 0xCC | 0xCD | 0xD4 | 0xDC) || opcode & 0xC7 == 0xC7;
+        let ret = matches!(opcode, 0xC0 | 0xC8 | 0xC9 | 0xD0 | 0xD8 | 0xD9);
+        if call && sp == old_sp.wrapping_sub(2) {
+            self.enter(pc, sp);
+        } else if ret && sp == old_sp.wrapping_add(2) {
+            self.leave(sp);
+        }
+    }
+
+    pub fn cycles(&mut self, cycles: u8) {
+        let current = self.current();
+        self.nodes[current].cycles += cycles as u64;
+        self.total += cycles as u64;
+        let save_cycles = if self.double_speed {
+            SAVE_CYCLES * 2
+        } else {
+            SAVE_CYCLES
+        };
+        if self.total - self.saved_at >= save_cycles {
+            self.save();
+        }
+    }
+
+    /// Called once the cpu has jumped to a function, and pushed the return address to `sp`
+    pub fn enter(&mut self, function: Function, sp: u16) {
+        let current = self.current();
+        let node = match self.nodes[current].children.get(&function) {
+            Some(node) => *node,
+            None => {
+                self.nodes.push(Node::new(function, current));
+                let node = self.nodes.len() - 1;
+                self.nodes[current].children.insert(function, node);
+                node
+            }
+        };
+        self.nodes[node].calls += 1;
+        self.stack.push((node, sp));
+    }
+
+    /// Called once the cpu has returned and the stack pointer is back at `sp`.
+    /// Any calls whose return address is now above the stack are over, which
+    /// also catches functions that were left without returning.
+    pub fn leave(&mut self, sp: u16) {
+        while let Some((_, call_sp)) = self.stack.last() {
+            if *call_sp >= sp {
+                break;
+            }
+            self.stack.pop();
+        }
+    }
+
+    fn current(&self) -> usize {
+        self.stack.last().map_or(ROOT, |(node, _)| *node)
+    }
+
+    fn name(&self, node: usize) -> String {
+        if node == ROOT {
+            return "(root)".to_string();
+        }
+        let (bank, address) = self.nodes[node].function;
+        match self.symbols.label(bank, address) {
+            Some(label) => label.to_string(),
+            None => format!("{:02X}:{:04X}", bank, address),
+        }
+    }
+
+    fn path(&self, mut node: usize) -> Vec<usize> {
+        let mut path = vec![node];
+        while node != ROOT {
+            node = self.nodes[node].parent;
+            path.push(node);
+        }
+        path.reverse();
+        path
+    }
+
+    /// The cycles spent in each function, by itself and including what it called
+    pub fn flat_profile(&self) -> String {
+        // Keyed by name, so the root and every call of a function are one line
+        let mut functions: HashMap<String, (u64, u64, u64)> = HashMap::new();
+        for (node, entry) in self.nodes.iter().enumerate() {
+            let name = self.name(node);
+            let function = functions.entry(name).or_default();
+            function.0 += entry.cycles;
+            function.2 += entry.calls;
+            // Recursive functions only count once towards their total
+            let mut callers: Vec<String> = self.path(node).iter().map(|n| self.name(*n)).collect();
+            callers.sort();
+            callers.dedup();
+            for caller in callers {
+                functions.entry(caller).or_default().1 += entry.cycles;
+            }
+        }
+        let mut functions: Vec<(String, (u64, u64, u64))> = functions.into_iter().collect();
+        functions.sort_by(|a, b| b.1 .0.cmp(&a.1 .0).then(a.0.cmp(&b.0)));
+
+        let percent = |cycles: u64| cycles as f64 * 100.0 / self.total.max(1) as f64;
+        let mut lines = vec![format!(
+            "{:>7} {:>14} {:>7} {:>14} {:>10}  function",
+            "self %", "self cycles", "total %", "total cycles", "calls"
+        )];
+        for (name, (cycles, total, calls)) in functions {
+            lines.push(format!(
+                "{:>6.2}% {:>14} {:>6.2}% {:>14} {:>10}  {}",
+                percent(cycles),
+                cycles,
+                percent(total),
+                total,
+                calls,
+                name
+            ));
+        }
+        lines.join("\n")
+    }
+
+    /// One line per chain of calls with the cycles spent at its end,
+    /// the format flamegraph.pl, inferno and speedscope read
+    pub fn collapsed_stacks(&self) -> String {
+        let mut lines: Vec<String> = (0..self.nodes.len())
+            .filter(|node| self.nodes[*node].cycles > 0)
+            .map(|node| {
+                let path: Vec<String> = self.path(node).iter().map(|n| self.name(*n)).collect();
+                format!("{} {}", path.join(";"), self.nodes[node].cycles)
+            })
+            .collect();
+        lines.sort();
+        lines.join("\n")
+    }
+
+    fn save(&mut self) {
+        self.saved_at = self.total;
+        let file = match &self.file {
+            Some(file) => file,
+            None => return,
+        };
+        let mut folded = file.clone().into_os_string();
+        folded.push(".folded");
+        let written = fs::write(file, self.flat_profile() + "\n")
+            .and_then(|_| fs::write(folded, self.collapsed_stacks() + "\n"));
+        if let Err(err) = written {
+            error!("Error writing the profile {}: {}", file.display(), err);
+        }
+    }
+}
+
+impl Drop for Profiler {
+    fn drop(&mut self) {
+        self.save();
+    }
+}
+
+#[test]
+fn follows_calls_and_returns() {
+    use crate::dmg::cpu::CPU;
+
+    let mut cpu = CPU::default();
+    let program: [(u16, &[u8]); 3] = [
+        (0xC000, &[0xCD, 0x10, 0xC0, 0x00]),       // CALL $C010, NOP
+        (0xC010, &[0x00, 0xCD, 0x20, 0xC0, 0xC9]), // NOP, CALL $C020, RET
+        (0xC020, &[0x00, 0xC9]),                   // NOP, RET
+    ];
+    for (address, bytes) in program {
+        for (i, byte) in bytes.iter().enumerate() {
+            cpu.bus.write_byte(address + i as u16, *byte);
+        }
+    }
+    let mut profiler = Profiler::default();
+    profiler.set_symbols(Symbols::parse("00:c020 Inner"));
+    cpu.set_profiler(Some(profiler));
+    cpu.pc = 0xC000;
+    for _ in 0..7 {
+        cpu.step();
+    }
+    let profiler = cpu.profiler().unwrap();
+
+    // The cycles of a call count towards the caller, the return towards the callee
+    assert_eq!(
+        profiler.collapsed_stacks(),
+        "(root) 28\n(root);00:C010 44\n(root);00:C010;Inner 20"
+    );
+    let flat = profiler.flat_profile();
+    let lines: Vec<&str> = flat.lines().collect();
+    assert_eq!(lines.len(), 4);
+    assert!(lines[1].ends_with(" 64          1  00:C010"), "{}", flat);
+    assert!(lines[2].ends_with(" 92          0  (root)"), "{}", flat);
+}
+
+#[test]
+fn unwinds_abandoned_calls() {
+    let mut profiler = Profiler::default();
+    profiler.enter((0, 0x100), 0xDFFC);
+    profiler.enter((0, 0x200), 0xDFFA);
+    // Returning from the first call also ends the second, which never returned
+    profiler.leave(0xDFFE);
+    profiler.cycles(4);
+    assert_eq!(profiler.collapsed_stacks(), "(root) 4");
+}
+
+#[test]
+fn counts_halted_and_interrupt_cycles() {
+    use crate::dmg::cpu::CPU;
+
+    let mut cpu = CPU::default();
+    cpu.bus.write_byte(0xC000, 0x76); // HALT
+    cpu.bus.write_byte(0xFFFF, 0x10); // Enables the joypad interrupt
+    cpu.ime = true;
+    cpu.set_profiler(Some(Profiler::default()));
+    cpu.pc = 0xC000;
+    cpu.step();
+    cpu.step();
+    assert_eq!(cpu.profiler().unwrap().collapsed_stacks(), "(root) 8");
+
+    cpu.bus.write_byte(0xFF0F, 0x10);
+    cpu.step();
+    // Waking up takes 4 cycles and the dispatch 20
+    assert_eq!(cpu.profiler().unwrap().collapsed_stacks(), "(root) 32");
+    assert_eq!(cpu.pc, 0x60);
+}
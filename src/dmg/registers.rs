@@ -1,3 +1,5 @@
+use crate::dmg::save_state::{SaveState, StateReader, StateWriter};
+
 const ZERO_FLAG_BYTE_POSITION: u8 = 7;
 const NEGATIVE_FLAG_BYTE_POSITION: u8 = 6;
 const HALF_CARRY_FLAG_BYTE_POSITION: u8 = 5;
@@ -10,6 +12,7 @@ pub struct FlagsRegister {
     carry: bool,
 }
 
+#[allow(clippy::derivable_impls)]
 impl Default for FlagsRegister {
     fn default() -> FlagsRegister {
         FlagsRegister {
@@ -57,6 +60,7 @@ pub struct Registers {
     pub l: u8,
 }
 
+#[allow(clippy::derivable_impls)]
 impl Default for Registers {
     fn default() -> Registers {
         Registers {
@@ -84,11 +88,11 @@ impl Registers {
 
     pub fn get_af(&self) -> u16 {
         let flags_register = &self.f;
-        (self.b as u16) << 8 | u8::from(flags_register) as u16
+        (self.a as u16) << 8 | u8::from(flags_register) as u16
     }
 
     pub fn set_af(&mut self, value: u16) {
-        self.a = ((value & 0xFF) >> 8) as u8;
+        self.a = ((value & 0xFF00) >> 8) as u8;
         self.f = FlagsRegister::from((value & 0xFF) as u8)
     }
 
@@ -143,3 +147,32 @@ impl Registers {
         self.f.zero = zero;
     }
 }
+
+impl SaveState for Registers {
+    fn save_state(&self, state: &mut StateWriter) {
+        for register in [
+            self.a,
+            self.b,
+            self.c,
+            self.d,
+            self.e,
+            u8::from(&self.f),
+            self.h,
+            self.l,
+        ] {
+            state.u8(register);
+        }
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.a = state.u8()?;
+        self.b = state.u8()?;
+        self.c = state.u8()?;
+        self.d = state.u8()?;
+        self.e = state.u8()?;
+        self.f = FlagsRegister::from(state.u8()?);
+        self.h = state.u8()?;
+        self.l = state.u8()?;
+        Ok(())
+    }
+}
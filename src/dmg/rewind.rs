@@ -0,0 +1,258 @@
+use crate::dmg::cpu::CPU;
+use crate::dmg::save_state;
+use log::error;
+use std::collections::VecDeque;
+
+// The number of cpu cycles it takes the PPU to draw a frame, twice as many in double speed
+pub const FRAME_CYCLES: u32 = 70224;
+
+/// Keeps the recent past as a ring buffer of save states taken every few frames.
+/// Only the newest snapshot is kept whole, every older one is stored as the
+/// difference to the snapshot after it, which for a few frames is mostly zeroes
+/// and compresses well. The oldest snapshots are dropped to stay within the budget.
+pub struct Rewind {
+    interval: u32,
+    budget: usize,
+    cycles: u32,
+    frames: u32,
+    latest: Option<Vec<u8>>,
+    // Each delta turns the snapshot after it back into its own, the newest is at the back
+    deltas: VecDeque<Vec<u8>>,
+    deltas_size: usize,
+}
+
+impl Rewind {
+    /// Takes a snapshot every `interval` frames, using at most `budget` bytes
+    pub fn new(interval: u32, budget: usize) -> Rewind {
+        Rewind {
+            interval: interval.max(1),
+            budget,
+            cycles: 0,
+            frames: 0,
+            latest: None,
+            deltas: VecDeque::new(),
+            deltas_size: 0,
+        }
+    }
+
+    /// Called after every cpu step. At the end of each frame this either records
+    /// the machine or, while `rewinding`, goes back to the previous snapshot.
+    pub fn step(&mut self, cpu: &mut CPU, cycles: u8, rewinding: bool) {
+        let frame_cycles = if cpu.bus.double_speed() {
+            FRAME_CYCLES * 2
+        } else {
+            FRAME_CYCLES
+        };
+        self.cycles += cycles as u32;
+        if self.cycles < frame_cycles {
+            return;
+        }
+        self.cycles -= frame_cycles;
+        if rewinding {
+            self.rewind(cpu);
+        } else {
+            self.record(cpu);
+        }
+    }
+
+    /// Counts a frame, taking a snapshot if it's time to
+    pub fn record(&mut self, cpu: &CPU) {
+        self.frames += 1;
+        if self.frames < self.interval {
+            return;
+        }
+        self.frames = 0;
+
+        let snapshot = save_state::save(cpu);
+        if let Some(previous) = self.latest.replace(snapshot) {
+            let delta = encode(&previous, self.latest.as_ref().unwrap());
+            self.deltas_size += delta.len();
+            self.deltas.push_back(delta);
+        }
+        let latest_size = self.latest.as_ref().map_or(0, Vec::len);
+        while self.deltas_size + latest_size > self.budget {
+            match self.deltas.pop_front() {
+                Some(delta) => self.deltas_size -= delta.len(),
+                None => break,
+            }
+        }
+    }
+
+    /// Loads the newest snapshot and forgets it, so the next call goes further back.
+    /// The oldest snapshot is kept, rewinding past it keeps loading it.
+    /// Returns false if there's nothing to go back to.
+    pub fn rewind(&mut self, cpu: &mut CPU) -> bool {
+        let latest = match self.latest.take() {
+            Some(latest) => latest,
+            None => return false,
+        };
+        if let Err(err) = save_state::load(cpu, &latest) {
+            error!("Error rewinding: {}", err);
+        }
+        self.latest = Some(match self.deltas.pop_back() {
+            Some(delta) => {
+                self.deltas_size -= delta.len();
+                decode(&latest, &delta)
+            }
+            None => latest,
+        });
+        self.frames = 0;
+        true
+    }
+
+    /// The number of snapshots that can be gone back to
+    pub fn len(&self) -> usize {
+        self.deltas.len() + self.latest.is_some() as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.latest.is_none()
+    }
+
+    /// How many bytes the snapshots take up
+    pub fn memory_used(&self) -> usize {
+        self.deltas_size + self.latest.as_ref().map_or(0, Vec::len)
+    }
+}
+
+// The delta is the length of `old` followed by `old` XOR `new` as runs of:
+// the number of zeroes, the number of other bytes, the other bytes
+fn encode(old: &[u8], new: &[u8]) -> Vec<u8> {
+    let length = old.len().max(new.len());
+    let xor = (0..length).map(|i| old.get(i).unwrap_or(&0) ^ new.get(i).unwrap_or(&0));
+    let xor: Vec<u8> = xor.collect();
+
+    let mut delta = Vec::new();
+    write_number(&mut delta, old.len());
+    let mut i = 0;
+    while i < xor.len() {
+        let zeroes = xor[i..].iter().take_while(|byte| **byte == 0).count();
+        i += zeroes;
+        let literals = xor[i..].iter().take_while(|byte| **byte != 0).count();
+        write_number(&mut delta, zeroes);
+        write_number(&mut delta, literals);
+        delta.extend_from_slice(&xor[i..i + literals]);
+        i += literals;
+    }
+    delta
+}
+
+fn decode(new: &[u8], delta: &[u8]) -> Vec<u8> {
+    let mut position = 0;
+    let length = read_number(delta, &mut position);
+    let mut old = new.to_vec();
+    old.resize(length.max(new.len()), 0);
+    let mut i = 0;
+    while position < delta.len() {
+        i += read_number(delta, &mut position);
+        let literals = read_number(delta, &mut position);
+        for byte in &delta[position..position + literals] {
+            old[i] ^= byte;
+            i += 1;
+        }
+        position += literals;
+    }
+    old.truncate(length);
+    old
+}
+
+// 7 bits at a time, the top bit set when more follow
+fn write_number(data: &mut Vec<u8>, mut number: usize) {
+    while number >= 0x80 {
+        data.push(number as u8 | 0x80);
+        number >>= 7;
+    }
+    data.push(number as u8);
+}
+
+fn read_number(data: &[u8], position: &mut usize) -> usize {
+    let mut number = 0;
+    let mut shift = 0;
+    loop {
+        let byte = data[*position];
+        *position += 1;
+        number |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return number;
+        }
+        shift += 7;
+    }
+}
+
+#[test]
+fn delta_round_trip() {
+    let old = vec![1, 2, 3, 0, 0, 0, 7, 8, 9, 10];
+    let mut new = old.clone();
+    new[2] = 0x33;
+    new[8] = 0x99;
+    let delta = encode(&old, &new);
+    assert_eq!(decode(&new, &delta), old);
+    assert!(delta.len() < old.len());
+
+    let longer = vec![5; 300];
+    assert_eq!(decode(&longer, &encode(&old, &longer)), old);
+    assert_eq!(decode(&old, &encode(&longer, &old)), longer);
+}
+
+#[test]
+fn rewinds_to_earlier_frames() {
+    let mut cpu = CPU::default();
+    let mut rewind = Rewind::new(2, usize::MAX);
+    for frame in 0..6 {
+        cpu.bus.write_byte(0xC000, frame);
+        rewind.record(&cpu);
+    }
+    // Snapshots were taken after frames 1, 3 and 5
+    assert_eq!(rewind.len(), 3);
+
+    for expected in [5, 3, 1, 1] {
+        assert!(rewind.rewind(&mut cpu));
+        assert_eq!(cpu.bus.read_byte(0xC000), expected);
+    }
+    assert_eq!(rewind.len(), 1, "The oldest snapshot is kept");
+}
+
+#[test]
+fn stays_within_budget() {
+    let mut cpu = CPU::default();
+    let mut rewind = Rewind::new(1, usize::MAX);
+    rewind.record(&cpu);
+    let budget = rewind.memory_used() + 100;
+
+    let mut rewind = Rewind::new(1, budget);
+    for frame in 0..50u8 {
+        for i in 0..16 {
+            cpu.bus.write_byte(0xC000 + i, frame);
+        }
+        rewind.record(&cpu);
+        assert!(rewind.memory_used() <= budget);
+    }
+    assert!(rewind.len() > 1 && rewind.len() < 50);
+
+    while rewind.len() > 1 {
+        rewind.rewind(&mut cpu);
+    }
+    assert!(
+        cpu.bus.read_byte(0xC000) > 0,
+        "The oldest frames were dropped"
+    );
+}
+
+#[test]
+fn frames_take_twice_as_many_cycles_in_double_speed() {
+    use crate::dmg::model::Model;
+
+    let mut cpu = CPU::default();
+    cpu.bus.set_model(Model::Cgb);
+    cpu.bus.write_byte(0xFF4D, 0x01);
+    assert!(cpu.bus.switch_speed());
+    let mut rewind = Rewind::new(1, usize::MAX);
+    for _ in 0..FRAME_CYCLES / 4 {
+        rewind.step(&mut cpu, 4, false);
+    }
+    assert_eq!(rewind.len(), 0, "Only half a frame has been drawn");
+    for _ in 0..FRAME_CYCLES / 4 {
+        rewind.step(&mut cpu, 4, false);
+    }
+    assert_eq!(rewind.len(), 1);
+}
@@ -0,0 +1,287 @@
+use crate::dmg::cpu::CPU;
+use crate::dmg::ppu::ppu::{HEIGHT, WIDTH};
+use std::path::{Path, PathBuf};
+
+const MAGIC: &[u8; 4] = b"OXBS";
+
+// Bump this whenever the layout of any component's state changes
+const VERSION: u16 = 4;
+
+// The thumbnail is the screen at half size, 3 bytes of RGB per pixel
+pub const THUMBNAIL_WIDTH: usize = WIDTH / 2;
+pub const THUMBNAIL_HEIGHT: usize = HEIGHT / 2;
+
+/// A component that can be written to and restored from a save state.
+/// Only the emulated machine is saved, never things like the window or audio sink.
+pub trait SaveState {
+    fn save_state(&self, state: &mut StateWriter);
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String>;
+}
+
+#[derive(Default)]
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.data.push(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn f32(&mut self, value: f32) {
+        self.u32(value.to_bits());
+    }
+
+    pub fn f64(&mut self, value: f64) {
+        self.u64(value.to_bits());
+    }
+
+    /// A block of bytes whose size is always the same, like vram
+    pub fn bytes(&mut self, value: &[u8]) {
+        self.data.extend_from_slice(value);
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> StateReader<'a> {
+        StateReader { data }
+    }
+
+    /// How many bytes haven't been read yet
+    pub fn remaining(&self) -> usize {
+        self.data.len()
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8], String> {
+        if self.data.len() < length {
+            return Err("The save state is truncated".to_string());
+        }
+        let (value, rest) = self.data.split_at(length);
+        self.data = rest;
+        Ok(value)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, String> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u16(&mut self) -> Result<u16, String> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn f32(&mut self) -> Result<f32, String> {
+        Ok(f32::from_bits(self.u32()?))
+    }
+
+    pub fn f64(&mut self) -> Result<f64, String> {
+        Ok(f64::from_bits(self.u64()?))
+    }
+
+    /// Fills `value` with the next `value.len()` bytes
+    pub fn bytes(&mut self, value: &mut [u8]) -> Result<(), String> {
+        value.copy_from_slice(self.take(value.len())?);
+        Ok(())
+    }
+}
+
+/// What's stored in front of the machine's state
+pub struct Header {
+    pub version: u16,
+    pub rom_checksum: u64,
+    /// `THUMBNAIL_WIDTH` x `THUMBNAIL_HEIGHT` pixels of RGB
+    pub thumbnail: Vec<u8>,
+}
+
+pub fn read_header(state: &mut StateReader) -> Result<Header, String> {
+    let mut magic = [0u8; 4];
+    state
+        .bytes(&mut magic)
+        .map_err(|_| "Not a save state".to_string())?;
+    if &magic != MAGIC {
+        return Err("Not a save state".to_string());
+    }
+    let version = state.u16()?;
+    let rom_checksum = state.u64()?;
+    let mut thumbnail = vec![0u8; THUMBNAIL_WIDTH * THUMBNAIL_HEIGHT * 3];
+    state.bytes(&mut thumbnail)?;
+    Ok(Header {
+        version,
+        rom_checksum,
+        thumbnail,
+    })
+}
+
+/// Snapshots the whole machine
+pub fn save(cpu: &CPU) -> Vec<u8> {
+    let mut state = StateWriter::default();
+    state.bytes(MAGIC);
+    state.u16(VERSION);
+    state.u64(cpu.bus.rom_checksum());
+    state.bytes(&thumbnail(cpu));
+    cpu.save_state(&mut state);
+    state.into_bytes()
+}
+
+/// Restores a snapshot. States from other versions or other ROMs are refused
+/// before anything is changed, and a truncated state leaves the machine as it was.
+pub fn load(cpu: &mut CPU, data: &[u8]) -> Result<(), String> {
+    let mut state = StateReader::new(data);
+    let header = read_header(&mut state)?;
+    if header.version != VERSION {
+        return Err(format!(
+            "The save state is version {}, but only version {} can be loaded",
+            header.version, VERSION
+        ));
+    }
+    if header.rom_checksum != cpu.bus.rom_checksum() {
+        return Err("The save state was made with a different ROM".to_string());
+    }
+    // Truncation is only found partway through loading, so keep a snapshot to go back to
+    let backup = save(cpu);
+    if let Err(e) = cpu.load_state(&mut state) {
+        let mut backup = StateReader::new(&backup);
+        read_header(&mut backup)
+            .and_then(|_| cpu.load_state(&mut backup))
+            .expect("A snapshot that was just taken should load");
+        return Err(e);
+    }
+    Ok(())
+}
+
+pub fn save_to_file(cpu: &CPU, path: &Path) -> Result<(), String> {
+    std::fs::write(path, save(cpu)).map_err(|e| format!("Error writing {}: {}", path.display(), e))
+}
+
+pub fn load_from_file(cpu: &mut CPU, path: &Path) -> Result<(), String> {
+    let data =
+        std::fs::read(path).map_err(|e| format!("Error reading {}: {}", path.display(), e))?;
+    load(cpu, &data)
+}
+
+/// Slots 0 - 9 are stored next to the ROM, e.g. tetris.gb.ss3
+pub fn slot_path(rom: &str, slot: u8) -> PathBuf {
+    PathBuf::from(format!("{}.ss{}", rom, slot))
+}
+
+// Every other pixel of every other line
+fn thumbnail(cpu: &CPU) -> Vec<u8> {
+    let screen = cpu.bus.screen();
+    let mut pixels = Vec::with_capacity(THUMBNAIL_WIDTH * THUMBNAIL_HEIGHT * 3);
+    for line in screen.iter().step_by(2) {
+        for pixel in line.iter().step_by(2) {
+            pixels.extend_from_slice(&pixel.to_be_bytes()[1..]);
+        }
+    }
+    pixels
+}
+
+#[cfg(test)]
+fn test_cpu(rom_byte: u8) -> CPU {
+    use crate::dmg::bus::Bus;
+    use crate::dmg::cartridge::cartridge::{checksum, Cartridge};
+    use crate::dmg::cartridge::rom_only::RomOnly;
+
+    let rom = vec![rom_byte; 0xC000];
+    let cartridge = Cartridge::from_cart(Box::new(RomOnly::new(rom.clone())), checksum(&rom));
+    CPU::with_bus(Bus::with_cartridge(cartridge))
+}
+
+#[test]
+fn round_trip() {
+    let mut cpu = test_cpu(0);
+    cpu.pc = 0x1234;
+    cpu.sp = 0xCFF0;
+    cpu.ime = true;
+    cpu.registers.a = 0x42;
+    cpu.registers.set_hl(0xBEEF);
+    cpu.bus.write_byte(0xC123, 0x55);
+    cpu.bus.write_byte(0x8001, 0x66);
+    cpu.bus.write_byte(0xFF26, 0x80);
+    cpu.bus.write_byte(0xFF12, 0xF3);
+    cpu.bus.write_byte(0xFF06, 0x77);
+    cpu.bus.write_byte(0xFF50, 0x01);
+    let state = save(&cpu);
+
+    let mut loaded = test_cpu(0);
+    load(&mut loaded, &state).unwrap();
+    assert_eq!(loaded.pc, 0x1234);
+    assert_eq!(loaded.sp, 0xCFF0);
+    assert!(loaded.ime);
+    assert_eq!(loaded.registers.a, 0x42);
+    assert_eq!(loaded.registers.get_hl(), 0xBEEF);
+    for address in [0xC123, 0x8001, 0xFF26, 0xFF12, 0xFF06, 0x0000] {
+        assert_eq!(loaded.bus.read_byte(address), cpu.bus.read_byte(address));
+    }
+    assert_eq!(save(&loaded), state, "Saving again gives the same state");
+}
+
+#[test]
+fn refuses_other_roms() {
+    let cpu = test_cpu(0);
+    let mut other = test_cpu(1);
+    let state = save(&cpu);
+    assert_eq!(
+        load(&mut other, &state),
+        Err("The save state was made with a different ROM".to_string())
+    );
+    assert!(load(&mut other, b"not a state").is_err());
+
+    let mut same = test_cpu(0);
+    same.pc = 0x4321;
+    same.bus.write_byte(0xC123, 0x55);
+    let before = save(&same);
+    assert_eq!(
+        load(&mut same, &state[..state.len() - 1]),
+        Err("The save state is truncated".to_string())
+    );
+    assert_eq!(same.pc, 0x4321);
+    assert_eq!(
+        save(&same),
+        before,
+        "A truncated state should leave the machine unchanged"
+    );
+}
+
+#[test]
+fn header_has_thumbnail() {
+    let state = save(&test_cpu(0));
+    let header = read_header(&mut StateReader::new(&state)).unwrap();
+    assert_eq!(header.version, VERSION);
+    assert_eq!(header.thumbnail.len(), 80 * 72 * 3);
+}
@@ -0,0 +1,195 @@
+use crate::dmg::busconnection::BusConnection;
+use crate::dmg::save_state::{SaveState, StateReader, StateWriter};
+use std::io::Write;
+
+// With the internal clock a bit is shifted every 512 cpu cycles (8192 Hz)
+// See: https://gbdev.io/pandocs/Serial_Data_Transfer_(Link_Cable).html
+const CYCLES_PER_BIT: u16 = 512;
+
+/// Whatever is plugged into the other end of the link cable
+pub trait SerialDevice {
+    /// Called when a transfer completes with the byte we sent, returns the byte we received
+    fn transfer(&mut self, byte: u8) -> u8;
+
+    /// Called as time passes. `waiting` holds SB while we wait for the other side to
+    /// clock a transfer, if it does the device returns the byte it sent us
+    fn step(&mut self, _cycles: u8, _waiting: Option<u8>) -> Option<u8> {
+        None
+    }
+
+    /// Everything the device has received, for devices that keep it
+    fn output(&self) -> &[u8] {
+        &[]
+    }
+}
+
+/// Nothing is connected, the received bytes are all 0xFF. Keeps what was sent
+#[derive(Default)]
+pub struct CaptureDevice {
+    output: Vec<u8>,
+}
+
+impl SerialDevice for CaptureDevice {
+    fn transfer(&mut self, byte: u8) -> u8 {
+        self.output.push(byte);
+        0xFF
+    }
+
+    fn output(&self) -> &[u8] {
+        &self.output
+    }
+}
+
+/// Prints what was sent, test roms use this to report their results
+pub struct StdoutDevice;
+
+impl SerialDevice for StdoutDevice {
+    fn transfer(&mut self, byte: u8) -> u8 {
+        let mut stdout = std::io::stdout();
+        stdout.write_all(&[byte]).ok();
+        stdout.flush().ok();
+        0xFF
+    }
+}
+
+pub struct Serial {
+    sb: u8, // 0xFF01
+    sc: u8, // 0xFF02
+
+    bits_left: u8,
+    bit_counter: u16,
+    device: Box<dyn SerialDevice>,
+}
+
+impl Default for Serial {
+    fn default() -> Serial {
+        Serial {
+            sb: 0,
+            sc: 0,
+            bits_left: 0,
+            bit_counter: CYCLES_PER_BIT,
+            device: Box::new(CaptureDevice::default()),
+        }
+    }
+}
+
+impl BusConnection for Serial {
+    fn read_byte(&self, address: u16) -> u8 {
+        match address {
+            0xFF01 => self.sb,
+            0xFF02 => self.sc | 0x7E, // The unused bits read as 1
+            _ => panic!("This should never happen"),
+        }
+    }
+
+    fn write_byte(&mut self, address: u16, value: u8) {
+        match address {
+            0xFF01 => self.sb = value,
+            0xFF02 => {
+                self.sc = value & 0x81;
+                if self.transferring() {
+                    self.bits_left = 8;
+                    self.bit_counter = CYCLES_PER_BIT;
+                }
+            }
+            _ => panic!("This should never happen"),
+        }
+    }
+}
+
+impl Serial {
+    pub fn set_device(&mut self, device: Box<dyn SerialDevice>) {
+        self.device = device;
+    }
+
+    pub fn output(&self) -> &[u8] {
+        self.device.output()
+    }
+
+    // A transfer using the internal clock is in progress
+    fn transferring(&self) -> bool {
+        self.sc & 0x81 == 0x81
+    }
+
+    /// Returns true when a transfer completed and the serial interrupt should be requested
+    pub fn step(&mut self, cycles: u8) -> bool {
+        // A transfer using the external clock only finishes when the other side clocks it
+        let waiting = if self.sc & 0x81 == 0x80 {
+            Some(self.sb)
+        } else {
+            None
+        };
+        if let Some(byte) = self.device.step(cycles, waiting) {
+            if waiting.is_some() {
+                self.sb = byte;
+                self.sc &= 0x7F;
+                return true;
+            }
+        }
+
+        if !self.transferring() {
+            return false;
+        }
+        let mut cycles = cycles as u16;
+        while cycles >= self.bit_counter {
+            cycles -= self.bit_counter;
+            self.bit_counter = CYCLES_PER_BIT;
+            self.bits_left -= 1;
+            if self.bits_left == 0 {
+                self.sb = self.device.transfer(self.sb);
+                self.sc &= 0x7F;
+                return true;
+            }
+        }
+        self.bit_counter -= cycles;
+        false
+    }
+}
+
+// Whatever is on the other end of the cable isn't part of the state
+impl SaveState for Serial {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.sb);
+        state.u8(self.sc);
+        state.u8(self.bits_left);
+        state.u16(self.bit_counter);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.sb = state.u8()?;
+        self.sc = state.u8()?;
+        self.bits_left = state.u8()?;
+        self.bit_counter = state.u16()?;
+        Ok(())
+    }
+}
+
+#[test]
+fn internal_clock_transfer() {
+    let mut serial = Serial::default();
+    serial.write_byte(0xFF01, b'A');
+    serial.write_byte(0xFF02, 0x81);
+    assert_eq!(serial.read_byte(0xFF02), 0xFF);
+    for _ in 0..(8 * CYCLES_PER_BIT / 16 - 1) {
+        assert!(!serial.step(16));
+    }
+    assert!(serial.step(16), "8 bits at 512 cycles each");
+    assert_eq!(
+        serial.read_byte(0xFF02),
+        0x7F,
+        "The transfer flag is cleared"
+    );
+    assert_eq!(serial.read_byte(0xFF01), 0xFF, "Nothing was connected");
+    assert_eq!(serial.output(), b"A");
+}
+
+#[test]
+fn external_clock_waits() {
+    let mut serial = Serial::default();
+    serial.write_byte(0xFF01, b'A');
+    serial.write_byte(0xFF02, 0x80);
+    for _ in 0..1024 {
+        assert!(!serial.step(16));
+    }
+    assert!(serial.output().is_empty());
+}
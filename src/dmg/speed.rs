@@ -0,0 +1,75 @@
+use crate::dmg::busconnection::BusConnection;
+use crate::dmg::save_state::{SaveState, StateReader, StateWriter};
+
+/// KEY1, the CGB's speed switch. Setting bit 0 arms a switch, which happens on the next STOP.
+/// In double speed the cpu, timer, serial port and OAM DMA run twice as fast as the PPU and APU.
+/// See: https://gbdev.io/pandocs/CGB_Registers.html#ff4d--key1-cgb-mode-only-prepare-speed-switch
+#[derive(Default)]
+pub struct Speed {
+    double: bool,
+    armed: bool,
+}
+
+impl BusConnection for Speed {
+    fn read_byte(&self, address: u16) -> u8 {
+        match address {
+            0xFF4D => 0x7E | (self.double as u8) << 7 | self.armed as u8,
+            _ => panic!("The Address: {:#02x}, is not used by KEY1", address),
+        }
+    }
+
+    fn write_byte(&mut self, address: u16, value: u8) {
+        match address {
+            0xFF4D => self.armed = value & 0x01 != 0,
+            _ => panic!("The Address: {:#02x}, is not used by KEY1", address),
+        }
+    }
+}
+
+impl Speed {
+    pub fn double(&self) -> bool {
+        self.double
+    }
+
+    /// Switches speed if a switch is armed, returning whether it did
+    pub fn switch(&mut self) -> bool {
+        let switching = self.armed;
+        if switching {
+            self.double = !self.double;
+            self.armed = false;
+        }
+        switching
+    }
+}
+
+impl SaveState for Speed {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.double);
+        state.bool(self.armed);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.double = state.bool()?;
+        self.armed = state.bool()?;
+        Ok(())
+    }
+}
+
+#[test]
+fn stop_switches_when_armed() {
+    let mut speed = Speed::default();
+    assert_eq!(speed.read_byte(0xFF4D), 0x7E);
+    assert!(!speed.switch(), "Nothing happens unless a switch is armed");
+    speed.write_byte(0xFF4D, 0x01);
+    assert_eq!(speed.read_byte(0xFF4D), 0x7F);
+    assert!(speed.switch());
+    assert!(speed.double());
+    assert_eq!(
+        speed.read_byte(0xFF4D),
+        0xFE,
+        "Bit 7 is the speed, the switch is disarmed"
+    );
+    speed.write_byte(0xFF4D, 0x01);
+    speed.switch();
+    assert!(!speed.double(), "Switching again goes back to normal speed");
+}
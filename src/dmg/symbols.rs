@@ -0,0 +1,128 @@
+use log::warn;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+
+/// Labels read from a symbol file, as written by RGBDS and most other toolchains.
+/// Each line is `bank:address name`, both in hex, and `;` starts a comment.
+#[derive(Clone, Default)]
+pub struct Symbols {
+    // Keyed by (address, bank) so all the banks of an address are next to each other
+    labels: BTreeMap<(u16, u16), String>,
+    addresses: HashMap<String, (u16, u16)>,
+}
+
+impl Symbols {
+    pub fn parse(text: &str) -> Symbols {
+        let mut symbols = Symbols::default();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split(';').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            match parse_line(line) {
+                Some((bank, address, name)) => symbols.add(bank, address, name),
+                None => warn!("Skipping line {} of the symbols: {}", number + 1, line),
+            }
+        }
+        symbols
+    }
+
+    pub fn load(file: &Path) -> Result<Symbols, String> {
+        fs::read_to_string(file)
+            .map(|text| Symbols::parse(&text))
+            .map_err(|e| format!("Error reading symbols {}: {}", file.display(), e))
+    }
+
+    /// Loads the symbols next to a rom, e.g. game.sym for game.gb, if there are any
+    pub fn for_rom(rom: &str) -> Option<Symbols> {
+        let file = Path::new(rom).with_extension("sym");
+        if !file.is_file() {
+            return None;
+        }
+        match Symbols::load(&file) {
+            Ok(symbols) => Some(symbols),
+            Err(err) => {
+                warn!("{}", err);
+                None
+            }
+        }
+    }
+
+    /// The first label for an address keeps it, later ones can still be looked up by name
+    pub fn add(&mut self, bank: u16, address: u16, name: &str) {
+        self.labels
+            .entry((address, bank))
+            .or_insert_with(|| name.to_string());
+        self.addresses.insert(name.to_string(), (bank, address));
+    }
+
+    /// The label at the address while `bank` is switched in. Only the rom and cartridge ram
+    /// banks have to match, other memory is shown the same however the file numbers it.
+    pub fn label(&self, bank: u16, address: u16) -> Option<&str> {
+        if let Some(label) = self.labels.get(&(address, bank)) {
+            return Some(label);
+        }
+        if matches!(address, 0x4000..=0x7FFF | 0xA000..=0xBFFF) {
+            return None;
+        }
+        self.labels
+            .range((address, 0)..=(address, u16::MAX))
+            .next()
+            .map(|(_, label)| label.as_str())
+    }
+
+    /// The bank and address of a label
+    pub fn lookup(&self, name: &str) -> Option<(u16, u16)> {
+        self.addresses.get(name).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.addresses.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.addresses.is_empty()
+    }
+}
+
+fn parse_line(line: &str) -> Option<(u16, u16, &str)> {
+    let (location, name) = line.split_once(char::is_whitespace)?;
+    let (bank, address) = location.split_once(':')?;
+    let bank = u16::from_str_radix(bank, 16).ok()?;
+    let address = u16::from_str_radix(address, 16).ok()?;
+    Some((bank, address, name.trim()))
+}
+
+#[test]
+fn parses_rgbds_symbols() {
+    let symbols = Symbols::parse(
+        "; File generated by rgblink\n\
+         00:0150 Main\n\
+         00:0150 Start\n\
+         00:0158 Main.loop\n\
+         01:4000 Bank1Code ; trailing comment\n\
+         02:4000 Bank2Code\n\
+         01:c100 wPlayerX\n\
+         not a symbol\n",
+    );
+    assert_eq!(symbols.len(), 6);
+    assert_eq!(symbols.label(0, 0x150), Some("Main"));
+    assert_eq!(symbols.label(0, 0x158), Some("Main.loop"));
+    assert_eq!(symbols.lookup("Start"), Some((0, 0x150)));
+    assert_eq!(symbols.lookup("Bank2Code"), Some((2, 0x4000)));
+    assert_eq!(symbols.lookup("Missing"), None);
+}
+
+#[test]
+fn labels_follow_the_bank() {
+    let mut symbols = Symbols::default();
+    symbols.add(1, 0x4000, "Bank1Code");
+    symbols.add(2, 0x4000, "Bank2Code");
+    symbols.add(1, 0xD000, "wBuffer");
+    assert_eq!(symbols.label(1, 0x4000), Some("Bank1Code"));
+    assert_eq!(symbols.label(2, 0x4000), Some("Bank2Code"));
+    assert_eq!(symbols.label(3, 0x4000), None);
+    // Outside the cartridge's banks the bank isn't needed to tell labels apart
+    assert_eq!(symbols.label(0, 0xD000), Some("wBuffer"));
+}
@@ -1,8 +1,13 @@
 use crate::dmg::busconnection::BusConnection;
+use crate::dmg::save_state::{SaveState, StateReader, StateWriter};
 
+/// The timer runs off a 16-bit counter that counts every clock. DIV is its upper byte, and
+/// TIMA counts the falling edges of one of its bits ANDed with the enable bit of TAC.
+/// See: https://gbdev.io/pandocs/Timer_Obscure_Behaviour.html
+#[derive(Default)]
 pub struct Timer {
-    /// The Divider Register
-    div: u8, // 0xFF04
+    /// The system counter, the Divider Register (0xFF04) is the upper byte
+    counter: u16,
 
     /// Timer counter
     tima: u8, // 0xFF05
@@ -12,51 +17,54 @@ pub struct Timer {
 
     /// Timer Control
     tac: u8, // 0xFF07
-    // TODO: May want to make tac it's own struct but it would still need to return the unimplemented bits since
-    // they're still there
     // -> bit 2 = timer enabled
     // -> bit 0..1 = Input clock select
     //    ->  00: CPU Clock / 1024 (DMG, SGB2, CGB Single Speed Mode:   4096 Hz, SGB1:   ~4194 Hz, CGB Double Speed Mode:   8192 Hz)
     //        01: CPU Clock / 16   (DMG, SGB2, CGB Single Speed Mode: 262144 Hz, SGB1: ~268400 Hz, CGB Double Speed Mode: 524288 Hz)
     //        10: CPU Clock / 64   (DMG, SGB2, CGB Single Speed Mode:  65536 Hz, SGB1:  ~67110 Hz, CGB Double Speed Mode: 131072 Hz)
     //        11: CPU Clock / 256  (DMG, SGB2, CGB Single Speed Mode:  16384 Hz, SGB1:  ~16780 Hz, CGB Double Speed Mode:  32768 Hz)
-    timer_counter: u16,
-}
+    /// TIMA overflowed during the last M-cycle and reads 0x00 until it's reloaded in the next one
+    overflowed: bool,
 
-impl Default for Timer {
-    fn default() -> Timer {
-        Timer {
-            div: 0,
-            tima: 0,
-            tma: 0,
-            tac: 0,
-            timer_counter: 1024, // TAC starts at zero so the timer_counter should begin accordingly
-        }
-    }
+    /// TIMA was reloaded from TMA during this M-cycle, so writes to TIMA are ignored
+    /// and writes to TMA also land in TIMA
+    reloading: bool,
 }
 
 impl BusConnection for Timer {
     fn read_byte(&self, address: u16) -> u8 {
         match address {
-            0xFF04 => self.div,
+            0xFF04 => (self.counter >> 8) as u8,
             0xFF05 => self.tima,
             0xFF06 => self.tma,
-            0xFF07 => self.tac,
+            0xFF07 => self.tac | 0xF8,
             _ => panic!("This should never happen"),
         }
     }
 
     fn write_byte(&mut self, address: u16, value: u8) {
         match address {
-            0xFF04 => self.div = 0,
-            0xFF05 => self.tima = value,
-            0xFF06 => self.tma = value,
+            // Resetting the counter can make the selected bit fall, which increments TIMA
+            0xFF04 => self.set_counter(0),
+            0xFF05 => {
+                if !self.reloading {
+                    // Writing during the cycle TIMA reads 0x00 cancels the reload and interrupt
+                    self.tima = value;
+                    self.overflowed = false;
+                }
+            }
+            0xFF06 => {
+                self.tma = value;
+                if self.reloading {
+                    self.tima = value;
+                }
+            }
             0xFF07 => {
-                let curr_freq = self.cpu_cycles_per_tick();
-                self.tac = value;
-                let new_freq = self.cpu_cycles_per_tick();
-                if curr_freq != new_freq {
-                    self.timer_counter = self.cpu_cycles_per_tick();
+                // Disabling the timer or selecting another bit can make the input fall
+                let input = self.input();
+                self.tac = value & 0x07;
+                if input && !self.input() {
+                    self.increment();
                 }
             }
             _ => panic!("This should never happen"),
@@ -65,52 +73,63 @@ impl BusConnection for Timer {
 }
 
 impl Timer {
-    pub fn step(&mut self, cycles: u8) {
-        let (new_div, did_overflow) = self.div.overflowing_add(cycles);
-        if did_overflow {
-            self.div = 0;
-        } else {
-            self.div = new_div;
+    /// Runs the timer for a number of clocks, a multiple of an M-cycle.
+    /// Returns true when TIMA is reloaded after overflowing, requesting an interrupt
+    pub fn step(&mut self, cycles: u8) -> bool {
+        let mut interrupt = false;
+        for _ in 0..cycles / 4 {
+            self.reloading = false;
+            if self.overflowed {
+                self.overflowed = false;
+                self.tima = self.tma;
+                self.reloading = true;
+                interrupt = true;
+            }
+            self.set_counter(self.counter.wrapping_add(4));
+        }
+        interrupt
+    }
+
+    fn set_counter(&mut self, counter: u16) {
+        let input = self.input();
+        self.counter = counter;
+        if input && !self.input() {
+            self.increment();
         }
+    }
 
-        if self.timer_enabled() {
-            let (new_tc, tc_overflow) = self.timer_counter.overflowing_sub(cycles as u16);
-            if tc_overflow {
-                // Reset the timer
-                self.timer_counter = self.cpu_cycles_per_tick();
-
-                // timer about to overflow
-                if self.tima == 255 {
-                    self.tima = self.tma;
-                    // TODO: Request an interrupt
-                } else {
-                    self.tima += 1;
-                }
-            } else {
-                self.timer_counter = new_tc;
-            }
+    fn increment(&mut self) {
+        let (tima, overflowed) = self.tima.overflowing_add(1);
+        self.tima = tima;
+        if overflowed {
+            self.overflowed = true;
         }
     }
 
+    /// The selected bit of the counter ANDed with the enable bit, TIMA counts when it falls
+    fn input(&self) -> bool {
+        self.timer_enabled() && self.counter & (1 << self.selected_bit()) != 0
+    }
+
     fn timer_enabled(&self) -> bool {
         // The second bit of the tac tells is of the timer is enabled
         ((self.tac >> 2) & 0x1) == 1
     }
 
-    // Gets the frequency for the timer from tac and returns the precalculated amount of cylces per cpu tick
-    fn cpu_cycles_per_tick(&self) -> u16 {
+    // The bit of the counter for the frequency in tac, it falls once every 2^(bit + 1) cycles
+    fn selected_bit(&self) -> u8 {
         match self.tac & 3 {
-            0 => 1024, // 00: 4096 Hz, 4194304 cycles per second
-            1 => 16,   // 01: 262144 Hz, 4194304 cycles per second
-            2 => 64,   // 10: 65536 Hz, 4194304 cycles per second
-            3 => 256,  // 11: 16384 Hz, 4194304 cycles per second
+            0 => 9, // 00: 4096 Hz, every 1024 cycles
+            1 => 3, // 01: 262144 Hz, every 16 cycles
+            2 => 5, // 10: 65536 Hz, every 64 cycles
+            3 => 7, // 11: 16384 Hz, every 256 cycles
             _ => panic!("We've defied a law of mathematics!!"),
         }
     }
 
     #[cfg(test)]
     pub fn div(&self) -> u8 {
-        self.div
+        (self.counter >> 8) as u8
     }
 
     #[cfg(test)]
@@ -119,99 +138,156 @@ impl Timer {
     }
 }
 
+impl SaveState for Timer {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u16(self.counter);
+        state.u8(self.tima);
+        state.u8(self.tma);
+        state.u8(self.tac);
+        state.bool(self.overflowed);
+        state.bool(self.reloading);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.counter = state.u16()?;
+        self.tima = state.u8()?;
+        self.tma = state.u8()?;
+        self.tac = state.u8()?;
+        self.overflowed = state.bool()?;
+        self.reloading = state.bool()?;
+        Ok(())
+    }
+}
+
 #[test]
 fn timer_write_to_div() {
     let mut t = Timer::default();
+    t.step(255);
     t.write_byte(0xFF04, 10);
     assert_eq!(
-        t.div, 0,
+        t.div(),
+        0,
         "The div should always be set to zero if we write to it"
     );
 }
 
 #[test]
-fn timer_counter_test_initial() {
-    let t = Timer::default();
-    assert_eq!(
-        t.cpu_cycles_per_tick(),
-        1024,
-        "Timer cpu cylces should start at 1024"
-    );
-    assert_eq!(
-        t.timer_counter, 1024,
-        "The timer counter should begin at 1024"
-    );
+fn div_counts_every_256_cycles() {
+    let mut t = Timer::default();
+    for _ in 0..63 {
+        t.step(4);
+    }
+    assert_eq!(t.div(), 0);
+    t.step(4);
+    assert_eq!(t.div(), 1, "DIV runs at 16384 Hz");
 }
 
 #[test]
-fn timer_counter_partial_step() {
+fn timer_disabled_doesnt_move() {
     let mut t = Timer::default();
-    t.tac = 5;
-    t.timer_counter = t.cpu_cycles_per_tick();
-    assert_eq!(t.timer_enabled(), true);
-    assert_eq!(
-        t.cpu_cycles_per_tick(),
-        16,
-        "Timer cpu cylces should start at 16"
-    );
-    t.step(10);
-    assert_eq!(t.timer_counter, 6, "We didn't make a full clock tick");
+    assert!(!t.timer_enabled(), "Timer begins as disabled");
+    for _ in 0..1024 {
+        t.step(4);
+    }
+    assert_eq!(t.tima(), 0, "Timer should still be at the starting point");
 }
 
 #[test]
-fn timer_should_inc_once() {
+fn timer_should_inc_every_16_cycles() {
     let mut t = Timer::default();
-    t.tac = 5;
-    t.timer_counter = t.cpu_cycles_per_tick();
-    assert_eq!(t.timer_enabled(), true);
-    assert_eq!(
-        t.cpu_cycles_per_tick(),
-        16,
-        "Timer cpu cylces should start at 16"
-    );
-    t.step(20);
-    assert_eq!(
-        t.timer_counter, 16,
-        "Timer should have reset to 16 according to the TAC"
-    );
+    t.write_byte(0xFF07, 0b101);
+    t.step(12);
+    assert_eq!(t.tima(), 0, "We didn't make a full clock tick");
+    t.step(4);
+    assert_eq!(t.tima(), 1);
+    t.step(16);
+    assert_eq!(t.tima(), 2);
 }
 
 #[test]
-fn timer_disabled_doesnt_move() {
+fn changing_frequency_changes_timer() {
     let mut t = Timer::default();
-    assert_eq!(t.timer_enabled(), false, "Timer begins as disables");
-    assert_eq!(t.timer_counter, 1024, "Timer cpu cylces should start at 16");
-    t.step(10);
-    assert_eq!(
-        t.timer_counter, 1024,
-        "Timer should stil be at the starting point"
-    );
+    t.write_byte(0xFF07, 0b100);
+    t.step(16);
+    assert_eq!(t.tima(), 0, "The timer counts every 1024 cycles");
+    t.write_byte(0xFF07, 0b101);
+    t.step(16);
+    assert_eq!(t.tima(), 1, "The timer counts every 16 cycles");
 }
 
 #[test]
-fn changing_frequency_changes_timer() {
+fn writing_div_can_increment_tima() {
     let mut t = Timer::default();
-    assert_eq!(t.timer_counter, 1024);
-    t.write_byte(0xFF07, 0b100);
-    assert_eq!(
-        t.timer_enabled(),
-        true,
-        "TAC at one should enable our timer"
-    );
-    assert_eq!(
-        t.timer_counter, 1024,
-        "TAC has changed but hasn't change the freqency, so this should still be 1024"
-    );
-    t.step(10);
+    t.write_byte(0xFF07, 0b101);
+    t.step(8);
+    assert_eq!(t.tima(), 0);
+    // Bit 3 of the counter is set, resetting it is a falling edge
+    t.write_byte(0xFF04, 0);
+    assert_eq!(t.tima(), 1);
+    t.step(4);
+    t.write_byte(0xFF04, 0);
+    assert_eq!(t.tima(), 1, "Bit 3 wasn't set yet");
+}
+
+#[test]
+fn writing_tac_can_increment_tima() {
+    let mut t = Timer::default();
+    t.write_byte(0xFF07, 0b101);
+    t.step(8);
+    // Disabling the timer while bit 3 is set makes the input fall
+    t.write_byte(0xFF07, 0b001);
+    assert_eq!(t.tima(), 1);
+    t.write_byte(0xFF07, 0b101);
+    t.step(8);
+    // Bit 5 isn't set, so switching to it also makes the input fall
+    t.write_byte(0xFF07, 0b110);
+    assert_eq!(t.tima(), 2);
+}
+
+#[test]
+fn overflow_reloads_a_cycle_later() {
+    let mut t = Timer::default();
+    t.write_byte(0xFF06, 0xAB);
+    t.write_byte(0xFF05, 0xFF);
+    t.write_byte(0xFF07, 0b101);
+    assert!(!t.step(16));
     assert_eq!(
-        t.timer_counter, 1014,
-        "A partial step should result in the timer counter moving"
+        t.tima(),
+        0,
+        "TIMA reads 0x00 for the cycle after overflowing"
     );
+    assert!(t.step(4), "The reload requests the interrupt");
+    assert_eq!(t.tima(), 0xAB);
+}
+
+#[test]
+fn writing_tima_after_overflow_cancels_the_reload() {
+    let mut t = Timer::default();
+    t.write_byte(0xFF06, 0xAB);
+    t.write_byte(0xFF05, 0xFF);
     t.write_byte(0xFF07, 0b101);
+    t.step(16);
+    t.write_byte(0xFF05, 0x12);
+    assert!(!t.step(4), "The interrupt was cancelled");
+    assert_eq!(t.tima(), 0x12);
+}
+
+#[test]
+fn writes_during_the_reload() {
+    let mut t = Timer::default();
+    t.write_byte(0xFF06, 0xAB);
+    t.write_byte(0xFF05, 0xFF);
+    t.write_byte(0xFF07, 0b101);
+    t.step(20);
+    t.write_byte(0xFF05, 0x12);
+    assert_eq!(t.tima(), 0xAB, "Writes to TIMA while reloading are ignored");
+    t.write_byte(0xFF06, 0x34);
     assert_eq!(
-        t.timer_counter, 16,
-        "The timer should be reset to a new frequency"
+        t.tima(),
+        0x34,
+        "Writes to TMA while reloading also go to TIMA"
     );
+    t.step(4);
+    t.write_byte(0xFF05, 0x56);
+    assert_eq!(t.tima(), 0x56);
 }
-
-// TODO: Test for triggering interrupt
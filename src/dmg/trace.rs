@@ -0,0 +1,166 @@
+use crate::dmg::busconnection::MemoryBus;
+use crate::dmg::cpu::CPU;
+use crate::dmg::symbols::Symbols;
+use log::error;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+// Lines are written in batches, but not so rarely that a killed emulator loses much
+const FLUSH_LINES: u32 = 4096;
+
+/// Logs the cpu state before every instruction in the format Gameboy Doctor compares.
+/// See: https://github.com/robert/gameboy-doctor
+/// With symbols, lines at a label end with it, e.g. `PCMEM:00,C3,13,02 ; Main`.
+pub struct Trace {
+    writer: Box<dyn Write>,
+    lines: u32,
+    symbols: Symbols,
+}
+
+impl Trace {
+    pub fn create(file: &str) -> io::Result<Trace> {
+        Ok(Trace::new(Box::new(BufWriter::new(File::create(file)?))))
+    }
+
+    pub fn new(writer: Box<dyn Write>) -> Trace {
+        Trace {
+            writer,
+            lines: 0,
+            symbols: Symbols::default(),
+        }
+    }
+
+    pub fn set_symbols(&mut self, symbols: Symbols) {
+        self.symbols = symbols;
+    }
+
+    /// Logs the cpu as it is before running its next instruction
+    pub fn log_cpu<B: MemoryBus>(&mut self, cpu: &CPU<B>) {
+        let mut line = doctor_line(cpu);
+        if let Some(label) = self.symbols.label(cpu.bus.bank(cpu.pc), cpu.pc) {
+            line.push_str(" ; ");
+            line.push_str(label);
+        }
+        self.log(&line);
+    }
+
+    pub fn log(&mut self, line: &str) {
+        if let Err(err) = writeln!(self.writer, "{}", line) {
+            error!("Error writing the trace: {}", err);
+        }
+        self.lines += 1;
+        if self.lines.is_multiple_of(FLUSH_LINES) {
+            let _ = self.writer.flush();
+        }
+    }
+}
+
+impl Drop for Trace {
+    fn drop(&mut self) {
+        let _ = self.writer.flush();
+    }
+}
+
+/// e.g. A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02
+pub fn doctor_line<B: MemoryBus>(cpu: &CPU<B>) -> String {
+    let r = &cpu.registers;
+    let pc_mem: Vec<String> = (0..4)
+        .map(|i| format!("{:02X}", cpu.bus.read_byte(cpu.pc.wrapping_add(i))))
+        .collect();
+    format!(
+        "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{}",
+        r.a,
+        u8::from(&r.f),
+        r.b,
+        r.c,
+        r.d,
+        r.e,
+        r.h,
+        r.l,
+        cpu.sp,
+        cpu.pc,
+        pc_mem.join(",")
+    )
+}
+
+#[cfg(test)]
+#[derive(Clone, Default)]
+struct SharedBuffer(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+
+#[cfg(test)]
+impl Write for SharedBuffer {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn doctor_format() {
+    let mut cpu = CPU::default();
+    cpu.skip_boot_rom();
+    let buffer = SharedBuffer::default();
+    cpu.set_trace(Some(Trace::new(Box::new(buffer.clone()))));
+    for (i, byte) in [0x00, 0xF0, 0x44, 0x00].iter().enumerate() {
+        cpu.bus.write_byte(0xC000 + i as u16, *byte);
+    }
+    cpu.pc = 0xC000;
+    cpu.step(); // NOP
+    cpu.step(); // LDH A, (LY)
+    cpu.step();
+    cpu.set_trace(None);
+
+    let trace = String::from_utf8(buffer.0.borrow().clone()).unwrap();
+    let lines: Vec<&str> = trace.lines().collect();
+    assert_eq!(
+        lines,
+        [
+            "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:C000 PCMEM:00,F0,44,00",
+            "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:C001 PCMEM:F0,44,00,00",
+            "A:90 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:C003 PCMEM:00,00,00,00",
+        ]
+    );
+}
+
+#[test]
+fn nothing_is_logged_while_halted() {
+    let mut cpu = CPU::default();
+    let buffer = SharedBuffer::default();
+    cpu.set_trace(Some(Trace::new(Box::new(buffer.clone()))));
+    cpu.bus.write_byte(0xC000, 0x76); // HALT
+    cpu.pc = 0xC000;
+    for _ in 0..10 {
+        cpu.step();
+    }
+    cpu.set_trace(None);
+
+    assert!(cpu.is_halted);
+    let trace = String::from_utf8(buffer.0.borrow().clone()).unwrap();
+    assert_eq!(trace.lines().count(), 1);
+}
+
+#[test]
+fn labels_from_symbols() {
+    let mut cpu = CPU::default();
+    let buffer = SharedBuffer::default();
+    let mut trace = Trace::new(Box::new(buffer.clone()));
+    trace.set_symbols(Symbols::parse("00:c001 Loop"));
+    cpu.set_trace(Some(trace));
+    cpu.pc = 0xC000;
+    cpu.step();
+    cpu.step();
+    cpu.set_trace(None);
+
+    let trace = String::from_utf8(buffer.0.borrow().clone()).unwrap();
+    let lines: Vec<&str> = trace.lines().collect();
+    assert!(lines[0].ends_with("PCMEM:00,00,00,00"), "{}", lines[0]);
+    assert!(
+        lines[1].ends_with("PCMEM:00,00,00,00 ; Loop"),
+        "{}",
+        lines[1]
+    );
+}
@@ -1,71 +1,589 @@
 pub mod dmg;
 
-use log::info;
+use log::{error, info, warn};
+use minifb::Key;
+use oxide_boy::dmg::audio::sink::{AudioSink, NullSink};
+use oxide_boy::dmg::audio::wav_sink::WavSink;
+use oxide_boy::dmg::coverage::Coverage;
+use oxide_boy::dmg::debugger::Debugger;
+use oxide_boy::dmg::disasm::{listing, parse_hex};
+use oxide_boy::dmg::gbs::{Gbs, GbsPlayer};
+use oxide_boy::dmg::gdb;
+use oxide_boy::dmg::link::LinkCable;
+use oxide_boy::dmg::model::Model;
+use oxide_boy::dmg::printer::Printer;
+use oxide_boy::dmg::profiler::Profiler;
+use oxide_boy::dmg::rewind::Rewind;
+use oxide_boy::dmg::save_state;
+use oxide_boy::dmg::serial::StdoutDevice;
+use oxide_boy::dmg::symbols::Symbols;
+use oxide_boy::dmg::trace::Trace;
 use oxide_boy::CPU;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
 use structopt::StructOpt;
 
-use env_logger;
+// The DMG's master clock, 4194304 Hz
+const CPU_CLOCK: u64 = 4194304;
+
+const DEFAULT_ROM: &str = "src/dmg/rom/DEFAULT_ROM.bin";
 
 #[derive(StructOpt)]
 #[structopt(rename_all = "kebab-case")]
 pub enum Command {
     #[structopt(about = "Runs the specified ROM", help = "USEAGE: run myRomFile.rom")]
-    Run { file: String },
+    Run {
+        file: String,
+        #[structopt(flatten)]
+        options: Options,
+    },
     #[structopt(
         about = "Runs the specified ROM as the BOOT ROM, use this to run custom boot roms or test roms",
         help = "USEAGE: custom myBootRom.rom myRomFile.rom"
     )]
-    Custom { boot_file: String, file: String },
+    Custom {
+        boot_file: String,
+        file: String,
+        #[structopt(flatten)]
+        options: Options,
+    },
     #[structopt(
         about = "Runs the specified ROM, but skips the boot sequence",
         help = "USEAGE: skip myRomFile.rom"
     )]
-    Skip { file: String },
+    Skip {
+        file: String,
+        #[structopt(flatten)]
+        options: Options,
+    },
     #[structopt(
         about = "For development purposes: Runs the default rom at src/dmg/rom/DEFAULT_ROM.bin",
         help = "USEAGE: default"
     )]
-    Default,
+    Default {
+        #[structopt(flatten)]
+        options: Options,
+    },
+    #[structopt(
+        about = "Plays a GBS music file",
+        help = "USEAGE: gbs myMusicFile.gbs --track 2"
+    )]
+    Gbs {
+        file: String,
+        #[structopt(long, help = "The song to play, counting from 1")]
+        track: Option<u8>,
+        #[structopt(long, help = "Stops after this many seconds")]
+        seconds: Option<u32>,
+        #[structopt(flatten)]
+        options: Options,
+    },
+    #[structopt(
+        about = "Runs the specified ROM in a command line debugger",
+        help = "USEAGE: debug myRomFile.rom"
+    )]
+    Debug {
+        file: String,
+        #[structopt(long, help = "Starts at 0x100 with the boot rom disabled")]
+        skip_boot: bool,
+        #[structopt(flatten)]
+        options: Options,
+    },
+    #[structopt(
+        about = "Disassembles a bank of the specified ROM into RGBDS syntax",
+        help = "USEAGE: disasm myRomFile.rom --bank 1 --start 0x4000 --end 0x4100"
+    )]
+    Disasm {
+        file: String,
+        #[structopt(
+            long,
+            default_value = "0",
+            help = "The rom bank, 0 is at 0x0000 and the rest at 0x4000"
+        )]
+        bank: usize,
+        #[structopt(long, parse(try_from_str = parse_hex), help = "The first address, in hex")]
+        start: Option<u16>,
+        #[structopt(long, parse(try_from_str = parse_hex), help = "The last address, in hex")]
+        end: Option<u16>,
+        #[structopt(
+            long,
+            help = "Reads labels from the given symbol file instead of the one next to the ROM"
+        )]
+        symbols: Option<String>,
+    },
+    #[structopt(
+        about = "Shows how much of each ROM bank coverage files saw used, merging them",
+        help = "USEAGE: coverage run1.cov run2.cov --output all.cov"
+    )]
+    Coverage {
+        #[structopt(required = true)]
+        files: Vec<String>,
+        #[structopt(
+            long,
+            help = "Writes the merged coverage to the given file, and a code/data log to <file>.cdl"
+        )]
+        output: Option<String>,
+    },
+}
+
+/// Options shared by every way of running a ROM
+#[derive(StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+pub struct Options {
+    #[structopt(
+        long,
+        help = "Writes the audio to the given WAV file instead of the sound device"
+    )]
+    record_audio: Option<String>,
+    #[structopt(long, help = "Discards all audio")]
+    no_audio: bool,
+    #[structopt(
+        long,
+        help = "Also writes each sound channel to <prefix>-ch1.wav through <prefix>-ch4.wav"
+    )]
+    record_channels: Option<String>,
+    #[structopt(
+        long,
+        conflicts_with_all = &["link-listen", "link-connect", "printer"],
+        help = "Prints the bytes sent over the link cable"
+    )]
+    serial_stdout: bool,
+    #[structopt(
+        long,
+        conflicts_with_all = &["link-connect", "printer"],
+        help = "Waits for another emulator to connect a link cable, e.g. 127.0.0.1:8765 or unix:/tmp/link"
+    )]
+    link_listen: Option<String>,
+    #[structopt(
+        long,
+        conflicts_with = "printer",
+        help = "Connects a link cable to another emulator"
+    )]
+    link_connect: Option<String>,
+    #[structopt(
+        long,
+        help = "Connects a Game Boy Printer that writes PNG files to the given directory"
+    )]
+    printer: Option<String>,
+    #[structopt(
+        long,
+        help = "Starts from a save state, either a file or a slot number from 0 to 9"
+    )]
+    load_state: Option<String>,
+    #[structopt(
+        long,
+        default_value = "4",
+        help = "How many frames apart the snapshots kept for rewinding are"
+    )]
+    rewind_interval: u32,
+    #[structopt(
+        long,
+        default_value = "64",
+        help = "How many megabytes of snapshots to keep for rewinding"
+    )]
+    rewind_memory: usize,
+    #[structopt(
+        long,
+        help = "Waits for GDB to connect on the given port and runs under its control"
+    )]
+    gdb: Option<u16>,
+    #[structopt(
+        long,
+        help = "Logs every instruction to the given file in the Gameboy Doctor format"
+    )]
+    trace: Option<String>,
+    #[structopt(
+        long,
+        help = "Reads labels from the given symbol file instead of the one next to the ROM"
+    )]
+    symbols: Option<String>,
+    #[structopt(long, help = "Doesn't read a symbol file")]
+    no_symbols: bool,
+    #[structopt(
+        long,
+        help = "Writes the cycles spent in each function to the given file, and collapsed stacks for flame graphs to <file>.folded"
+    )]
+    profile: Option<String>,
+    #[structopt(
+        long,
+        help = "Records which ROM bytes are run or read into the given file, adding to what it holds, and a code/data log to <file>.cdl"
+    )]
+    coverage: Option<String>,
+    #[structopt(
+        long,
+        help = "Runs as a DMG or CGB, instead of the model the ROM's header asks for"
+    )]
+    model: Option<Model>,
 }
 
 fn main() {
     env_logger::init();
     let args = Command::from_args();
     match args {
-        Command::Run { file } => run(file),
-        Command::Default => default(),
-        Command::Custom { boot_file, file } => custom(boot_file, file),
-        Command::Skip { file } => skip(file),
+        Command::Run { file, options } => run(file, options),
+        Command::Default { options } => default(options),
+        Command::Custom {
+            boot_file,
+            file,
+            options,
+        } => custom(boot_file, file, options),
+        Command::Skip { file, options } => skip(file, options),
+        Command::Gbs {
+            file,
+            track,
+            seconds,
+            options,
+        } => gbs(file, track, seconds, options),
+        Command::Debug {
+            file,
+            skip_boot,
+            options,
+        } => debug(file, skip_boot, options),
+        Command::Disasm {
+            file,
+            bank,
+            start,
+            end,
+            symbols,
+        } => disasm(file, bank, start, end, symbols),
+        Command::Coverage { files, output } => coverage(files, output),
     }
 }
 
-fn default() {
+fn default(options: Options) {
     info!("Starting emulator!");
     let mut cpu = CPU::default();
-    loop {
-        cpu.step();
-    }
+    setup(&mut cpu, DEFAULT_ROM, &options);
+    run_loop(&mut cpu, DEFAULT_ROM, &options);
 }
 
-fn run(file: String) {
+fn run(file: String, options: Options) {
     let mut cpu = CPU::new(&file);
-    loop {
-        cpu.step();
-    }
+    setup(&mut cpu, &file, &options);
+    run_loop(&mut cpu, &file, &options);
 }
 
-fn custom(boot_file: String, file: String) {
+fn custom(boot_file: String, file: String, options: Options) {
     let mut cpu = CPU::custom_boot_rom(&boot_file, &file);
-    loop {
-        cpu.step();
+    setup(&mut cpu, &file, &options);
+    run_loop(&mut cpu, &file, &options);
+}
+
+fn skip(file: String, options: Options) {
+    let mut cpu = CPU::new(&file);
+    setup(&mut cpu, &file, &options);
+    cpu.skip_boot_rom();
+    run_loop(&mut cpu, &file, &options);
+}
+
+fn gbs(file: String, track: Option<u8>, seconds: Option<u32>, options: Options) {
+    let gbs = Gbs::open(&file).unwrap_or_else(|e| panic!("{}", e));
+    let track = track.unwrap_or(gbs.first_song);
+    if !(1..=gbs.songs).contains(&track) {
+        panic!(
+            "There's no song {}, {} has songs 1 to {}",
+            track, file, gbs.songs
+        );
     }
+    println!(
+        "{} - {} ({}), song {} of {}",
+        gbs.title, gbs.author, gbs.copyright, track, gbs.songs
+    );
+
+    let mut player = GbsPlayer::new(&gbs, track);
+    setup(&mut player.cpu, &file, &options);
+    let max_cycles = seconds.map(|s| s as u64 * CPU_CLOCK);
+    let mut cycles = 0;
+    while max_cycles.is_none_or(|max| cycles < max) {
+        cycles += player.play() as u64;
+        // Without a window to pace us, wait for the sound device to catch up
+        while player.cpu.bus.apu().sink_fill_level().unwrap_or(0.0) > 0.5 {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+    player.cpu.bus.apu_mut().flush();
 }
 
-fn skip(file: String) {
+fn debug(file: String, skip_boot: bool, options: Options) {
     let mut cpu = CPU::new(&file);
-    cpu.bus.write_byte(0xFF50, 0x1); // Disables the boot rom
-    cpu.pc = 0x100;
+    setup(&mut cpu, &file, &options);
+    if skip_boot {
+        cpu.skip_boot_rom();
+    }
+    load_state(&mut cpu, &file, &options);
+    let mut debugger = Debugger::new(cpu);
+    debugger.set_symbols(symbols(&file, &options));
+    debugger.repl();
+}
+
+fn disasm(
+    file: String,
+    bank: usize,
+    start: Option<u16>,
+    end: Option<u16>,
+    symbols: Option<String>,
+) {
+    let rom = std::fs::read(&file).unwrap_or_else(|e| panic!("Error reading {}: {}", file, e));
+    // Bank 0 is always at 0x0000 - 0x3FFF, the others are switched in at 0x4000 - 0x7FFF
+    let (first, last) = if bank == 0 {
+        (0x0000, 0x3FFF)
+    } else {
+        (0x4000, 0x7FFF)
+    };
+    println!(
+        "{}",
+        listing(
+            &rom,
+            bank,
+            start.unwrap_or(first),
+            end.unwrap_or(last),
+            &read_symbols(&file, symbols.as_deref())
+        )
+    );
+}
+
+fn coverage(files: Vec<String>, output: Option<String>) {
+    let mut merged = Coverage::load(Path::new(&files[0])).unwrap_or_else(|e| panic!("{}", e));
+    for file in &files[1..] {
+        let coverage = Coverage::load(Path::new(file)).unwrap_or_else(|e| panic!("{}", e));
+        merged
+            .merge(&coverage)
+            .unwrap_or_else(|e| panic!("{}: {}", file, e));
+    }
+    println!("{}", merged.summary());
+    if let Some(file) = output {
+        merged
+            .save_to_file(Path::new(&file))
+            .unwrap_or_else(|e| panic!("{}", e));
+    }
+}
+
+/// Applies the command line options to a freshly created cpu
+fn setup(cpu: &mut CPU, rom: &str, options: &Options) {
+    let model = model(cpu, options);
+    cpu.bus.set_model(model);
+    // Only the DMG boot rom is built in, so CGB games start where the CGB's would have left them.
+    // A custom boot rom, which may well be the CGB's, runs as it is
+    if model == Model::Cgb && cpu.bus.boot_rom_enabled() && !cpu.bus.has_custom_boot_rom() {
+        cpu.skip_boot_rom();
+    }
+    let sink = audio_sink(options);
+    let sample_rate = sink.sample_rate();
+    cpu.bus.set_audio_sink(sink);
+    if let Some(prefix) = &options.record_channels {
+        for channel in 1..=4 {
+            let file = format!("{}-ch{}.wav", prefix, channel);
+            let sink = WavSink::with_sample_rate(&file, sample_rate)
+                .unwrap_or_else(|e| panic!("Error creating audio file {}: {}", file, e));
+            cpu.bus
+                .apu_mut()
+                .set_channel_sink(channel, Some(Box::new(sink)));
+        }
+    }
+    if let Some(file) = &options.trace {
+        let mut trace = Trace::create(file)
+            .unwrap_or_else(|e| panic!("Error creating trace file {}: {}", file, e));
+        trace.set_symbols(symbols(rom, options));
+        cpu.set_trace(Some(trace));
+    }
+    if let Some(file) = &options.profile {
+        let mut profiler = Profiler::create(file);
+        profiler.set_symbols(symbols(rom, options));
+        cpu.set_profiler(Some(profiler));
+    }
+    if let Some(file) = &options.coverage {
+        let rom_size = std::fs::metadata(rom).map_or(0, |m| m.len() as usize);
+        let coverage = Coverage::create(file, cpu.bus.rom_checksum(), rom_size)
+            .unwrap_or_else(|e| panic!("{}", e));
+        cpu.bus.set_coverage(Some(coverage));
+    }
+    if options.serial_stdout {
+        cpu.bus.set_serial_device(Box::new(StdoutDevice));
+    }
+    if let Some(directory) = &options.printer {
+        cpu.bus.set_serial_device(Box::new(Printer::new(directory)));
+    }
+    if let Some(address) = &options.link_listen {
+        let cable = LinkCable::listen(address)
+            .unwrap_or_else(|e| panic!("Error listening on {}: {}", address, e));
+        cpu.bus.set_serial_device(Box::new(cable));
+    } else if let Some(address) = &options.link_connect {
+        let cable = LinkCable::connect(address)
+            .unwrap_or_else(|e| panic!("Error connecting to {}: {}", address, e));
+        cpu.bus.set_serial_device(Box::new(cable));
+    }
+}
+
+/// The model picked with `--model`, or otherwise the one the ROM's header asks for.
+/// A CGB runs DMG games in a compatibility mode that isn't emulated, so they always run as a DMG
+fn model(cpu: &CPU, options: &Options) -> Model {
+    let cartridge = cpu.bus.cartridge_model();
+    match options.model {
+        Some(Model::Cgb) if cartridge == Model::Dmg => {
+            warn!("The ROM doesn't support the CGB, running it as a DMG");
+            Model::Dmg
+        }
+        Some(model) => model,
+        None => cartridge,
+    }
+}
+
+fn symbols(rom: &str, options: &Options) -> Symbols {
+    if options.no_symbols {
+        return Symbols::default();
+    }
+    read_symbols(rom, options.symbols.as_deref())
+}
+
+/// The given symbol file, or otherwise the one next to the rom if there is one
+fn read_symbols(rom: &str, file: Option<&str>) -> Symbols {
+    match file {
+        Some(file) => Symbols::load(Path::new(file)).unwrap_or_else(|e| panic!("{}", e)),
+        None => Symbols::for_rom(rom).unwrap_or_default(),
+    }
+}
+
+fn load_state(cpu: &mut CPU, rom: &str, options: &Options) {
+    if let Some(state) = &options.load_state {
+        let path = match state.parse::<u8>() {
+            Ok(slot) if slot <= 9 => save_state::slot_path(rom, slot),
+            _ => PathBuf::from(state),
+        };
+        save_state::load_from_file(cpu, &path).unwrap_or_else(|e| panic!("{}", e));
+    }
+}
+
+fn run_loop(cpu: &mut CPU, rom: &str, options: &Options) -> ! {
+    load_state(cpu, rom, options);
+    if let Some(port) = options.gdb {
+        gdb::serve(&format!("127.0.0.1:{}", port), cpu);
+        std::process::exit(0);
+    }
+    let mut rewind = Rewind::new(options.rewind_interval, options.rewind_memory << 20);
+    let mut slot = 0;
     loop {
-        cpu.step();
+        let cycles = cpu.step();
+        // Holding backspace goes back one snapshot every frame
+        let rewinding = cpu.bus.is_key_down(Key::Backspace);
+        rewind.step(cpu, cycles, rewinding);
+        for key in cpu.bus.take_pressed_keys() {
+            hotkey(cpu, key, rom, &mut slot);
+        }
+    }
+}
+
+/// F1 - F4 toggle muting sound channels 1 - 4, F5 - F8 solo them.
+/// 0 - 9 pick the save state slot, F9 saves to it and F10 loads it.
+fn hotkey(cpu: &mut CPU, key: Key, rom: &str, slot: &mut u8) {
+    match key {
+        Key::F1 | Key::F2 | Key::F3 | Key::F4 => {
+            let apu = cpu.bus.apu_mut();
+            let channel = key as usize - Key::F1 as usize + 1;
+            apu.set_channel_muted(channel, !apu.channel_muted(channel));
+        }
+        Key::F5 | Key::F6 | Key::F7 | Key::F8 => {
+            cpu.bus
+                .apu_mut()
+                .solo_channel(key as usize - Key::F5 as usize + 1);
+        }
+        Key::Key0
+        | Key::Key1
+        | Key::Key2
+        | Key::Key3
+        | Key::Key4
+        | Key::Key5
+        | Key::Key6
+        | Key::Key7
+        | Key::Key8
+        | Key::Key9 => {
+            *slot = key as u8 - Key::Key0 as u8;
+            info!("Save state slot {}", slot);
+        }
+        Key::F9 => {
+            let path = save_state::slot_path(rom, *slot);
+            match save_state::save_to_file(cpu, &path) {
+                Ok(()) => info!("Saved state to {}", path.display()),
+                Err(err) => error!("{}", err),
+            }
+        }
+        Key::F10 => {
+            let path = save_state::slot_path(rom, *slot);
+            match save_state::load_from_file(cpu, &path) {
+                Ok(()) => info!("Loaded state from {}", path.display()),
+                Err(err) => error!("{}", err),
+            }
+        }
+        _ => {}
+    }
+}
+
+fn audio_sink(options: &Options) -> Box<dyn AudioSink> {
+    if let Some(file) = &options.record_audio {
+        let sink = WavSink::create(file)
+            .unwrap_or_else(|e| panic!("Error creating audio file {}: {}", file, e));
+        return Box::new(sink);
     }
+    if options.no_audio {
+        return Box::new(NullSink::default());
+    }
+    host_sink()
+}
+
+#[cfg(feature = "host-audio")]
+fn host_sink() -> Box<dyn AudioSink> {
+    use oxide_boy::dmg::audio::host_sink::HostSink;
+
+    match HostSink::new() {
+        Ok(sink) => Box::new(sink),
+        Err(err) => {
+            error!("Error opening the sound device: {}, audio is disabled", err);
+            Box::new(NullSink::default())
+        }
+    }
+}
+
+#[cfg(not(feature = "host-audio"))]
+fn host_sink() -> Box<dyn AudioSink> {
+    Box::new(NullSink::default())
+}
+
+#[test]
+fn cgb_games_start_after_the_boot_rom() {
+    use oxide_boy::dmg::boot_rom::BootRom;
+    use oxide_boy::dmg::bus::Bus;
+    use oxide_boy::dmg::cartridge::cartridge::{checksum, Cartridge};
+    use oxide_boy::dmg::cartridge::rom_only::RomOnly;
+
+    let cpu = |cgb_flag: u8| {
+        let mut rom = vec![0u8; 0x8000];
+        rom[0x143] = cgb_flag;
+        let cartridge = Cartridge::from_cart(Box::new(RomOnly::new(rom.clone())), checksum(&rom));
+        CPU::with_bus(Bus::with_cartridge(cartridge))
+    };
+    let options = |args: &[&str]| Options::from_iter([&["run", "--no-audio"], args].concat());
+
+    // Set up the way `run` does, which would otherwise start the DMG boot rom
+    let mut cgb = cpu(0x80);
+    setup(&mut cgb, "game.gbc", &options(&[]));
+    assert_eq!(cgb.bus.model(), Model::Cgb);
+    assert_eq!(cgb.pc, 0x100);
+    assert_eq!(cgb.registers.a, 0x11);
+
+    let mut dmg = cpu(0x00);
+    setup(&mut dmg, "game.gb", &options(&["--model", "cgb"]));
+    assert_eq!(dmg.bus.model(), Model::Dmg, "DMG games can't run as a CGB");
+    assert_eq!(dmg.pc, 0x0000, "The boot rom runs");
+
+    // Set up the way `custom` does
+    let boot_file = std::env::temp_dir().join(format!("oxide_boy_boot_{}", std::process::id()));
+    std::fs::write(&boot_file, [0x18, 0xFE]).unwrap();
+    let mut custom = cpu(0x80);
+    custom
+        .bus
+        .set_boot_rom(BootRom::custom(boot_file.to_str().unwrap()));
+    std::fs::remove_file(&boot_file).unwrap();
+    setup(&mut custom, "game.gbc", &options(&[]));
+    assert_eq!(custom.bus.model(), Model::Cgb);
+    assert_eq!(custom.pc, 0x0000, "The custom boot rom runs");
+    assert_eq!(custom.bus.read_byte(0x0000), 0x18);
 }
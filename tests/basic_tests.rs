@@ -1,3 +1,6 @@
+use oxide_boy::dmg::bus::Bus;
+use oxide_boy::dmg::cartridge::cartridge::{checksum, Cartridge};
+use oxide_boy::dmg::cartridge::rom_only::RomOnly;
 use oxide_boy::{
     ArithmeticTarget, Instruction, JumpCond, LoadByteSource, LoadByteTarget, LoadType,
     LoadWordSource, LoadWordTarget, Registers, RestartAddr, SixteenBitArithmeticTarget,
@@ -372,6 +375,7 @@ fn cp_test_zero() {
 }
 
 #[test]
+#[allow(clippy::bool_assert_comparison)]
 fn halt_test() {
     let mut cpu = setup();
     cpu.execute(Instruction::HALT(4));
@@ -600,8 +604,8 @@ fn load_test_a_from_hldec() {
 #[test]
 fn load_a_from_c_plus_0xff00() {
     let mut cpu = setup();
-    cpu.bus.write_byte(0xff11, 0x10);
-    cpu.registers.c = 0x11;
+    cpu.bus.write_byte(0xff80, 0x10);
+    cpu.registers.c = 0x80;
     cpu.execute(Instruction::LDAC(8));
     assert_eq!(cpu.registers.a, 0x10);
     assert_flags_znhc(cpu.registers, false, false, false, false);
@@ -611,9 +615,9 @@ fn load_a_from_c_plus_0xff00() {
 fn load_c_plus_0xff00_from_a() {
     let mut cpu = setup();
     cpu.registers.a = 0x10;
-    cpu.registers.c = 0x11;
+    cpu.registers.c = 0x80;
     cpu.execute(Instruction::LDCA(8));
-    assert_eq!(cpu.bus.read_byte(0xFF11), 0x10);
+    assert_eq!(cpu.bus.read_byte(0xFF80), 0x10);
     assert_flags_znhc(cpu.registers, false, false, false, false);
 }
 
@@ -707,6 +711,45 @@ fn cpl_test() {
 }
 
 #[test]
+fn daa_after_add() {
+    let mut cpu = setup();
+    cpu.registers.a = 0x15;
+    cpu.registers.b = 0x27;
+    cpu.execute(Instruction::ADD(ArithmeticTarget::B, 4));
+    cpu.execute(Instruction::DAA(4));
+    assert_eq!(cpu.registers.a, 0x42, "15 + 27 = 42");
+    assert_flags_znhc(cpu.registers, false, false, false, false);
+
+    let mut cpu = setup();
+    cpu.registers.a = 0x99;
+    cpu.registers.b = 0x01;
+    cpu.execute(Instruction::ADD(ArithmeticTarget::B, 4));
+    cpu.execute(Instruction::DAA(4));
+    assert_eq!(cpu.registers.a, 0x00, "99 + 1 = 100");
+    assert_flags_znhc(cpu.registers, true, false, false, true);
+}
+
+#[test]
+fn daa_after_sub() {
+    let mut cpu = setup();
+    cpu.registers.a = 0x42;
+    cpu.registers.b = 0x15;
+    cpu.execute(Instruction::SUB(ArithmeticTarget::B, 4));
+    cpu.execute(Instruction::DAA(4));
+    assert_eq!(cpu.registers.a, 0x27, "42 - 15 = 27");
+    assert_flags_znhc(cpu.registers, false, true, false, false);
+
+    let mut cpu = setup();
+    cpu.registers.a = 0x10;
+    cpu.registers.b = 0x20;
+    cpu.execute(Instruction::SUB(ArithmeticTarget::B, 4));
+    cpu.execute(Instruction::DAA(4));
+    assert_eq!(cpu.registers.a, 0x90, "10 - 20 borrows to 90");
+    assert_flags_znhc(cpu.registers, false, true, false, true);
+}
+
+#[test]
+#[allow(clippy::bool_assert_comparison)]
 fn bit_test() {
     let mut cpu = setup();
     cpu.registers.a = 0b1000_0000;
@@ -960,6 +1003,18 @@ fn test_jump_relative_negative() {
     assert_eq!(cpu.pc, res);
 }
 
+#[test]
+fn test_jump_relative_furthest_back() {
+    let mut cpu = setup();
+    cpu.bus.write_byte(0xC20A, 0x80);
+    cpu.pc = 0xC209;
+    cpu.execute(Instruction::JR(JumpCond::Always, 8, 12));
+    assert_eq!(
+        cpu.pc, 0xC18B,
+        "Should jump back 128 spaces from the next instruction"
+    );
+}
+
 #[test]
 fn test_jump_relative_zero() {
     let mut cpu = setup();
@@ -998,11 +1053,11 @@ fn test_jump_to_hl() {
 #[test]
 fn test_ldha() {
     let mut cpu = setup();
-    cpu.bus.write_byte(0xCAAB, 0x11);
+    cpu.bus.write_byte(0xCAAB, 0x81);
     cpu.pc = 0xCAAA;
     cpu.registers.a = 0x12;
     cpu.execute(Instruction::LDHA(12));
-    assert_eq!(cpu.bus.read_byte(0xFF11), cpu.registers.a);
+    assert_eq!(cpu.bus.read_byte(0xFF81), cpu.registers.a);
 }
 
 #[test]
@@ -1029,13 +1084,14 @@ fn test_ldha_turn_on_lcdc() {
 fn test_ld8a() {
     let mut cpu = setup();
     cpu.pc = 0xC000;
-    cpu.bus.write_byte(0xC001, 0x11);
-    cpu.bus.write_byte(0xFF11, 0x10);
+    cpu.bus.write_byte(0xC001, 0x81);
+    cpu.bus.write_byte(0xFF81, 0x10);
     cpu.execute(Instruction::LDHA8(12));
     assert_eq!(cpu.registers.a, 0x10);
 }
 
 #[test]
+#[allow(clippy::bool_assert_comparison)]
 fn test_disable_interrupts() {
     let mut cpu = setup();
     cpu.execute(Instruction::DI(4));
@@ -1043,6 +1099,7 @@ fn test_disable_interrupts() {
 }
 
 #[test]
+#[allow(clippy::bool_assert_comparison)]
 fn test_enable_interrupts() {
     let mut cpu = setup();
     cpu.execute(Instruction::EI(4));
@@ -1057,6 +1114,7 @@ fn test_reti() {
     cpu.bus.write_word(0xC000, 0x0101);
     cpu.execute(Instruction::RETI(16));
     assert_eq!(cpu.pc, 0x0101);
+    assert!(cpu.ime);
 }
 
 #[test]
@@ -1390,6 +1448,7 @@ fn vram_logo_check() {
             break;
         }
     }
+    #[rustfmt::skip]
     let expected_vram = vec![
     /*8000*/ 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
     /*8010*/ 0xF0, 0x00, 0xF0, 0x00, 0xFC, 0x00, 0xFC, 0x00, 0xFC, 0x00, 0xFC, 0x00, 0xF3, 0x00, 0xF3, 0x00,
@@ -1418,6 +1477,7 @@ fn vram_logo_check() {
     /*8180*/ 0x0F, 0x00, 0x0F, 0x00, 0x0F, 0x00, 0x0F, 0x00, 0x0F, 0x00, 0x0F, 0x00, 0xFC, 0x00, 0xFC, 0x00,
     /*8190*/ 0x3C, 0x00, 0x42, 0x00, 0xB9, 0x00, 0xA5, 0x00, 0xB9, 0x00, 0xA5, 0x00, 0x42, 0x00, 0x3C, 0x00];
 
+    #[rustfmt::skip]
     let exp_vram_2 = vec![
     /*9900*/ 0x00, 0x00, 0x00, 0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x0B, 0x0C,
     /*9910*/ 0x19, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
@@ -1438,3 +1498,42 @@ fn vram_logo_check() {
         assert_eq!(cpu.bus.read_byte(i), 0);
     }
 }
+
+#[test]
+fn serial_transfer_interrupt() {
+    // The serial handler is just a RETI
+    let mut rom = vec![0u8; 0x8000];
+    rom[0x58] = 0xD9;
+    let cartridge = Cartridge::from_cart(Box::new(RomOnly::new(rom.clone())), checksum(&rom));
+    let mut cpu = CPU::with_bus(Bus::with_cartridge(cartridge));
+    cpu.skip_boot_rom();
+    cpu.pc = 0xC000; // Work RAM is full of NOPs
+    cpu.ime = true;
+    cpu.bus.write_byte(0xFFFF, 0x08);
+    cpu.bus.write_byte(0xFF01, b'!');
+    cpu.bus.write_byte(0xFF02, 0x81);
+    while cpu.pc != 0x58 {
+        assert!(
+            cpu.pc < 0xC000 + 1024,
+            "The transfer should take 4096 cycles"
+        );
+        cpu.step();
+    }
+    assert!(!cpu.ime);
+    assert_eq!(cpu.bus.read_byte(0xFF0F) & 0x08, 0);
+    assert_eq!(cpu.bus.serial_output(), b"!");
+
+    cpu.step();
+    assert!(cpu.ime, "RETI should enable interrupts again");
+    let resumed = cpu.pc;
+    cpu.bus.write_byte(0xFF01, b'?');
+    cpu.bus.write_byte(0xFF02, 0x81);
+    while cpu.pc != 0x58 {
+        assert!(
+            cpu.pc < resumed + 1024,
+            "The second transfer's interrupt should be taken too"
+        );
+        cpu.step();
+    }
+    assert_eq!(cpu.bus.serial_output(), b"!?");
+}
@@ -0,0 +1,189 @@
+mod common;
+
+use common::{headless, headless_cartridge, test_rom, CPU_CLOCK, FRAME_CYCLES};
+use oxide_boy::dmg::cartridge::cartridge::{checksum, Cartridge};
+use oxide_boy::dmg::cartridge::rom_only::RomOnly;
+use oxide_boy::CPU;
+
+#[derive(Debug, PartialEq)]
+enum Outcome {
+    Passed,
+    Failed(String),
+    TimedOut(String),
+}
+
+/// Runs one of Blargg's test roms until it reports a result or `seconds` of emulated time pass.
+/// The results are printed over the serial port, drawn on screen with tiles numbered
+/// as their ASCII characters and, by the newer roms, also written to cartridge ram.
+/// See: https://github.com/retrio/gb-test-roms
+fn run_blargg(mut cpu: CPU, seconds: u64) -> Outcome {
+    let mut cycles = 0;
+    let mut checked_at = 0;
+    while cycles < seconds * CPU_CLOCK {
+        cycles += cpu.step() as u64;
+        // Looking for the result takes a while, so only once a frame
+        if cycles - checked_at < FRAME_CYCLES {
+            continue;
+        }
+        checked_at = cycles;
+        if let Some(outcome) = result(&cpu) {
+            return outcome;
+        }
+    }
+    Outcome::TimedOut(output(&cpu))
+}
+
+fn result(cpu: &CPU) -> Option<Outcome> {
+    // $A001 - $A003 are DE B0 61 once the rest is valid, $A000 is 0x80 while running
+    let signature: Vec<u8> = (0xA001..=0xA003).map(|a| cpu.bus.read_byte(a)).collect();
+    if signature == [0xDE, 0xB0, 0x61] && cpu.bus.read_byte(0xA000) != 0x80 {
+        let passed = cpu.bus.read_byte(0xA000) == 0;
+        return Some(outcome(passed, memory_text(cpu)));
+    }
+    for text in [serial_text(cpu), screen_text(cpu)] {
+        if text.contains("Passed") {
+            return Some(Outcome::Passed);
+        }
+        if text.contains("Failed") {
+            return Some(Outcome::Failed(output(cpu)));
+        }
+    }
+    None
+}
+
+fn outcome(passed: bool, text: String) -> Outcome {
+    if passed {
+        Outcome::Passed
+    } else {
+        Outcome::Failed(text)
+    }
+}
+
+fn serial_text(cpu: &CPU) -> String {
+    String::from_utf8_lossy(cpu.bus.serial_output()).to_string()
+}
+
+// Zero terminated, from $A004
+fn memory_text(cpu: &CPU) -> String {
+    let bytes: Vec<u8> = (0xA004..0xC000)
+        .map(|a| cpu.bus.read_byte(a))
+        .take_while(|b| *b != 0)
+        .collect();
+    String::from_utf8_lossy(&bytes).to_string()
+}
+
+// The visible 20x18 tiles of the background map at $9800
+fn screen_text(cpu: &CPU) -> String {
+    let rows: Vec<String> = (0..18)
+        .map(|row| {
+            (0..20)
+                .map(|column| cpu.bus.read_byte(0x9800 + row * 32 + column))
+                .map(|tile| {
+                    if tile.is_ascii_graphic() {
+                        tile as char
+                    } else {
+                        ' '
+                    }
+                })
+                .collect::<String>()
+                .trim_end()
+                .to_string()
+        })
+        .collect();
+    rows.join("\n").trim().to_string()
+}
+
+// Whichever output the rom has used, to show why it failed
+fn output(cpu: &CPU) -> String {
+    let serial = serial_text(cpu);
+    if !serial.is_empty() {
+        return serial;
+    }
+    screen_text(cpu)
+}
+
+fn blargg(path: &str, seconds: u64) {
+    if let Some(rom) = test_rom(path) {
+        assert_eq!(
+            run_blargg(headless(&rom), seconds),
+            Outcome::Passed,
+            "{}",
+            path
+        );
+    }
+}
+
+#[test]
+fn cpu_instrs() {
+    blargg("blargg/cpu_instrs/cpu_instrs.gb", 60);
+}
+
+#[test]
+fn cpu_instrs_individual() {
+    let tests = [
+        "01-special",
+        "02-interrupts",
+        "03-op sp,hl",
+        "04-op r,imm",
+        "05-op rp",
+        "06-ld r,r",
+        "07-jr,jp,call,ret,rst",
+        "08-misc instrs",
+        "09-op r,r",
+        "10-bit ops",
+        "11-op a,(hl)",
+    ];
+    let mut failures = vec![];
+    for test in tests {
+        let path = format!("blargg/cpu_instrs/individual/{}.gb", test);
+        if let Some(rom) = test_rom(&path) {
+            let outcome = run_blargg(headless(&rom), 20);
+            if outcome != Outcome::Passed {
+                failures.push(format!("{}: {:?}", test, outcome));
+            }
+        }
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
+
+#[test]
+fn instr_timing() {
+    blargg("blargg/instr_timing/instr_timing.gb", 10);
+}
+
+#[test]
+fn mem_timing() {
+    blargg("blargg/mem_timing/mem_timing.gb", 10);
+}
+
+#[test]
+fn halt_bug() {
+    blargg("blargg/halt_bug.gb", 10);
+}
+
+// A rom that prints "Passed" over the serial port the way Blargg's do, to check the harness
+#[test]
+fn detects_serial_output() {
+    let mut rom = vec![0u8; 0x8000];
+    let program = [
+        0x21, 0x50, 0x01, // LD HL, $0150
+        0x2A, //             LD A, [HL+]
+        0xB7, //             OR A, A
+        0x28, 0x0E, //       JR Z, done
+        0xE0, 0x01, //       LDH [SB], A
+        0x3E, 0x81, //       LD A, $81
+        0xE0, 0x02, //       LDH [SC], A
+        0xF0, 0x02, //       LDH A, [SC]
+        0xE6, 0x80, //       AND A, $80
+        0x20, 0xFA, //       JR NZ, -6
+        0x18, 0xEE, //       JR -18
+        0x18, 0xFE, // done: JR -2
+    ];
+    rom[0x100..0x100 + program.len()].copy_from_slice(&program);
+    rom[0x150..0x157].copy_from_slice(b"Passed\n");
+    let cartridge = Cartridge::from_cart(Box::new(RomOnly::new(rom.clone())), checksum(&rom));
+    assert_eq!(
+        run_blargg(headless_cartridge(cartridge), 1),
+        Outcome::Passed
+    );
+}
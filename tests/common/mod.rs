@@ -0,0 +1,51 @@
+// Each test file only uses some of these
+#![allow(dead_code)]
+
+pub mod screenshot;
+
+use oxide_boy::dmg::bus::Bus;
+use oxide_boy::dmg::cartridge::cartridge::Cartridge;
+use oxide_boy::CPU;
+use std::env;
+use std::path::{Path, PathBuf};
+
+// The DMG's master clock, 4194304 Hz
+pub const CPU_CLOCK: u64 = 4194304;
+
+// The number of cpu cycles it takes the PPU to draw a frame
+pub const FRAME_CYCLES: u64 = 70224;
+
+/// Where the test roms are, laid out like https://github.com/c-sp/gameboy-test-roms.
+/// Set OXIDE_BOY_TEST_ROMS to use another directory.
+pub fn test_roms() -> PathBuf {
+    env::var_os("OXIDE_BOY_TEST_ROMS")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("tests/roms"))
+}
+
+/// The path to a test rom, or None if it isn't there and the test should be skipped
+pub fn test_rom(path: &str) -> Option<PathBuf> {
+    let rom = test_roms().join(path);
+    if rom.is_file() {
+        Some(rom)
+    } else {
+        eprintln!("Skipping, {} isn't there", rom.display());
+        None
+    }
+}
+
+/// A cpu without a window, starting where the boot rom would have left off
+pub fn headless(rom: &Path) -> CPU {
+    headless_cartridge(Cartridge::new(&rom.to_string_lossy()))
+}
+
+pub fn headless_cartridge(cartridge: Cartridge) -> CPU {
+    let mut cpu = booting(cartridge);
+    cpu.skip_boot_rom();
+    cpu
+}
+
+/// A cpu without a window that starts by running the boot rom
+pub fn booting(cartridge: Cartridge) -> CPU {
+    CPU::with_bus(Bus::with_cartridge(cartridge))
+}
@@ -0,0 +1,120 @@
+use super::FRAME_CYCLES;
+use oxide_boy::dmg::ppu::color::Color;
+use oxide_boy::dmg::ppu::ppu::{HEIGHT, WIDTH};
+use oxide_boy::CPU;
+use std::env;
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+// How the shades are stored in reference images, 0 is the lightest
+const GREYS: [u8; 4] = [0xFF, 0xAA, 0x55, 0x00];
+
+/// Runs for a number of frames and returns the screen
+pub fn run_frames(cpu: &mut CPU, frames: u64) -> Vec<u8> {
+    let mut cycles = 0;
+    while cycles < frames * FRAME_CYCLES {
+        cycles += cpu.step() as u64;
+    }
+    screen_shades(cpu)
+}
+
+/// The screen as shades from 0 to 3, before the palette in `Color::rgb` colours them
+pub fn screen_shades(cpu: &CPU) -> Vec<u8> {
+    let colors: Vec<u32> = (0..4).map(|shade| Color::from(shade).rgb()).collect();
+    cpu.bus
+        .screen()
+        .iter()
+        .flatten()
+        .map(|pixel| {
+            let shade = colors.iter().position(|color| color == pixel);
+            shade.expect("The screen has a colour that isn't a DMG shade") as u8
+        })
+        .collect()
+}
+
+/// Compares the screen with a reference image. On a mismatch the actual screen and
+/// an image with the differences in red are written to cargo's temporary directory.
+/// With OXIDE_BOY_BLESS set the screen becomes the new reference instead.
+pub fn assert_screenshot(reference: &Path, shades: &[u8]) {
+    if env::var_os("OXIDE_BOY_BLESS").is_some() {
+        write_png(reference, &grey(shades), png::ColorType::Grayscale);
+        eprintln!("Blessed {}", reference.display());
+        return;
+    }
+    let expected = match read_png(reference) {
+        Ok(expected) => expected,
+        Err(err) => panic!(
+            "Error reading the reference {}: {}, run with OXIDE_BOY_BLESS=1 to create it",
+            reference.display(),
+            err
+        ),
+    };
+    let differences = shades.iter().zip(&expected).filter(|(a, e)| a != e).count();
+    if differences == 0 {
+        return;
+    }
+
+    let name = reference.file_stem().unwrap().to_string_lossy();
+    let output = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("screenshots");
+    let actual = output.join(format!("{}-actual.png", name));
+    let diff = output.join(format!("{}-diff.png", name));
+    write_png(&actual, &grey(shades), png::ColorType::Grayscale);
+    // The reference faded, with the pixels that differ in red
+    let diff_pixels: Vec<u8> = shades
+        .iter()
+        .zip(&expected)
+        .flat_map(|(actual, expected)| {
+            if actual == expected {
+                let faded = GREYS[*expected as usize] / 2 + 0x40;
+                [faded, faded, faded]
+            } else {
+                [0xFF, 0x00, 0x00]
+            }
+        })
+        .collect();
+    write_png(&diff, &diff_pixels, png::ColorType::Rgb);
+    panic!(
+        "{} pixels differ from {}, see {} and {}",
+        differences,
+        reference.display(),
+        actual.display(),
+        diff.display()
+    );
+}
+
+fn grey(shades: &[u8]) -> Vec<u8> {
+    shades.iter().map(|shade| GREYS[*shade as usize]).collect()
+}
+
+// Any png of the screen's size, its pixels turned into the nearest of the four shades
+fn read_png(path: &Path) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut decoder = png::Decoder::new(File::open(path)?);
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let mut reader = decoder.read_info()?;
+    let mut data = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut data)?;
+    if (info.width as usize, info.height as usize) != (WIDTH, HEIGHT) {
+        return Err(format!("the image is {}x{}", info.width, info.height).into());
+    }
+    let channels = info.color_type.samples();
+    let shades = data[..info.buffer_size()]
+        .chunks(channels)
+        .map(|pixel| {
+            let colors = if channels >= 3 { 3 } else { 1 };
+            let level = pixel[..colors].iter().map(|c| *c as u32).sum::<u32>() / colors as u32;
+            3 - ((level + 42) / 85).min(3) as u8
+        })
+        .collect();
+    Ok(shades)
+}
+
+fn write_png(path: &Path, pixels: &[u8], color: png::ColorType) {
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    let file = BufWriter::new(File::create(path).unwrap());
+    let mut encoder = png::Encoder::new(file, WIDTH as u32, HEIGHT as u32);
+    encoder.set_color(color);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().unwrap();
+    writer.write_image_data(pixels).unwrap();
+}
@@ -231,6 +231,7 @@ fn from_byte_cp() {
 }
 
 #[test]
+#[allow(clippy::useless_vec)]
 fn from_byte_load() {
     for byte in 0x40..=0x47 {
         let op = Instruction::from_byte(byte, false).unwrap();
@@ -646,3 +647,15 @@ fn undefined_function_tests() {
         assert_eq!(op, None);
     }
 }
+
+#[test]
+fn push_and_pop_af() {
+    let mut cpu = oxide_boy::CPU::default();
+    cpu.sp = 0xD000;
+    cpu.registers.set_af(0x12F0);
+    cpu.execute(Instruction::PUSH(StackTarget::AF, 16));
+    cpu.registers.set_af(0x0000);
+    cpu.execute(Instruction::POP(StackTarget::AF, 12));
+    assert_eq!(cpu.registers.a, 0x12);
+    assert_eq!(cpu.registers.get_af(), 0x12F0);
+}
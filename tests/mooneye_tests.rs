@@ -0,0 +1,199 @@
+mod common;
+
+use common::{headless, headless_cartridge, test_roms, CPU_CLOCK};
+use oxide_boy::dmg::cartridge::cartridge::{checksum, Cartridge};
+use oxide_boy::dmg::cartridge::rom_only::RomOnly;
+use oxide_boy::CPU;
+use std::collections::BTreeMap;
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+
+// The acceptance tests all finish within a few frames
+const TIMEOUT_SECONDS: u64 = 2;
+
+// Tests that pass, so they can't start failing again. Add to this as the emulator improves
+const PASSING: &[&str] = &[
+    "acceptance/oam_dma/basic",
+    "acceptance/oam_dma/reg_read",
+    "acceptance/timer/div_write",
+    "acceptance/timer/rapid_toggle",
+    "acceptance/timer/tim00",
+    "acceptance/timer/tim00_div_trigger",
+    "acceptance/timer/tim01",
+    "acceptance/timer/tim01_div_trigger",
+    "acceptance/timer/tim10",
+    "acceptance/timer/tim10_div_trigger",
+    "acceptance/timer/tim11",
+    "acceptance/timer/tim11_div_trigger",
+    "acceptance/timer/tima_reload",
+    "acceptance/timer/tima_write_reloading",
+    "acceptance/timer/tma_write_reloading",
+];
+
+#[derive(Debug, PartialEq)]
+enum Outcome {
+    Passed,
+    Failed,
+    TimedOut,
+    Panicked,
+}
+
+/// Runs a Mooneye test rom until it runs `LD B, B`. It passes if B, C, D, E, H and L
+/// then hold the start of the Fibonacci sequence, and fails if they're all 0x42.
+/// See: https://github.com/Gekkio/mooneye-test-suite#passfail-reporting
+fn run_mooneye(mut cpu: CPU) -> Outcome {
+    let mut cycles = 0;
+    while cycles < TIMEOUT_SECONDS * CPU_CLOCK {
+        cycles += cpu.step() as u64;
+        if cpu.software_breakpoint {
+            let r = &cpu.registers;
+            return match [r.b, r.c, r.d, r.e, r.h, r.l] {
+                [3, 5, 8, 13, 21, 34] => Outcome::Passed,
+                _ => Outcome::Failed,
+            };
+        }
+    }
+    Outcome::TimedOut
+}
+
+// Unfinished parts of the emulator panic, that's a result like any other
+fn run_rom(rom: &Path) -> Outcome {
+    let result = panic::catch_unwind(AssertUnwindSafe(|| run_mooneye(headless(rom))));
+    result.unwrap_or(Outcome::Panicked)
+}
+
+/// Whether the test is meant for the DMG. The models a test is for follow the last `-`,
+/// either as revisions like `dmgABCmgb` or as groups where G is the DMG and MGB.
+fn runs_on_dmg(name: &str) -> bool {
+    let models = match name.rsplit_once('-') {
+        Some((_, models)) => models,
+        None => return true,
+    };
+    if models.contains("dmgABC") {
+        return true;
+    }
+    if models.chars().all(|c| c.is_ascii_uppercase()) {
+        return models.contains('G');
+    }
+    // Other revisions, like dmg0 or cgb
+    false
+}
+
+fn find_roms(directory: &Path, roms: &mut Vec<PathBuf>) {
+    let entries = match fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            find_roms(&path, roms);
+        } else if path.extension().is_some_and(|e| e == "gb") {
+            let name = path.file_stem().unwrap().to_string_lossy().to_string();
+            if runs_on_dmg(&name) {
+                roms.push(path);
+            }
+        }
+    }
+}
+
+/// A markdown table per directory of tests, e.g. acceptance/timer
+fn status_table(results: &BTreeMap<String, Vec<(String, Outcome)>>) -> String {
+    let mut lines = vec!["# Mooneye test suite status".to_string()];
+    for (directory, tests) in results {
+        let passed = tests.iter().filter(|(_, o)| *o == Outcome::Passed).count();
+        lines.push(String::new());
+        lines.push(format!("## {} ({}/{})", directory, passed, tests.len()));
+        lines.push(String::new());
+        lines.push("| Test | Result |".to_string());
+        lines.push("| --- | --- |".to_string());
+        for (test, outcome) in tests {
+            let result = match outcome {
+                Outcome::Passed => "Passed",
+                Outcome::Failed => "Failed",
+                Outcome::TimedOut => "Timed out",
+                Outcome::Panicked => "Panicked",
+            };
+            lines.push(format!("| {} | {} |", test, result));
+        }
+    }
+    lines.join("\n") + "\n"
+}
+
+/// Runs every DMG test of the acceptance and emulator-only suites and writes their results
+/// to mooneye-status.md, in `MOONEYE_STATUS` or otherwise cargo's temporary directory
+#[test]
+fn mooneye_suite() {
+    let suite = test_roms().join("mooneye-test-suite");
+    let mut roms = vec![];
+    find_roms(&suite.join("acceptance"), &mut roms);
+    find_roms(&suite.join("emulator-only"), &mut roms);
+    if roms.is_empty() {
+        eprintln!("Skipping, there are no roms in {}", suite.display());
+        return;
+    }
+    roms.sort();
+
+    let mut results: BTreeMap<String, Vec<(String, Outcome)>> = BTreeMap::new();
+    let mut regressions = vec![];
+    for rom in &roms {
+        let relative = rom.strip_prefix(&suite).unwrap();
+        let directory = relative.parent().unwrap().to_string_lossy().to_string();
+        let test = relative.with_extension("").to_string_lossy().to_string();
+        let outcome = run_rom(rom);
+        if PASSING.contains(&test.as_str()) && outcome != Outcome::Passed {
+            regressions.push(format!("{}: {:?}", test, outcome));
+        }
+        let name = rom.file_stem().unwrap().to_string_lossy().to_string();
+        results.entry(directory).or_default().push((name, outcome));
+    }
+
+    let status = std::env::var_os("MOONEYE_STATUS")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| Path::new(env!("CARGO_TARGET_TMPDIR")).to_path_buf());
+    fs::create_dir_all(&status).unwrap();
+    let status = status.join("mooneye-status.md");
+    fs::write(&status, status_table(&results)).unwrap();
+    eprintln!("Wrote {}", status.display());
+
+    assert!(regressions.is_empty(), "{}", regressions.join("\n"));
+}
+
+#[test]
+fn dmg_models() {
+    assert!(runs_on_dmg("add_sp_e_timing"));
+    assert!(runs_on_dmg("boot_regs-dmgABC"));
+    assert!(runs_on_dmg("boot_hwio-dmgABCmgb"));
+    assert!(runs_on_dmg("di_timing-GS"));
+    assert!(!runs_on_dmg("boot_div-dmg0"));
+    assert!(!runs_on_dmg("boot_regs-sgb"));
+    assert!(!runs_on_dmg("boot_hwio-S"));
+}
+
+// A rom that reports a pass the way the Mooneye tests do, to check the harness
+#[test]
+fn detects_fibonacci_registers() {
+    let program = [
+        0x06, 0x03, //       LD B, 3
+        0x0E, 0x05, //       LD C, 5
+        0x16, 0x08, //       LD D, 8
+        0x1E, 0x0D, //       LD E, 13
+        0x26, 0x15, //       LD H, 21
+        0x2E, 0x22, //       LD L, 34
+        0x40, //             LD B, B
+        0x18, 0xFE, //       JR -2
+    ];
+    let cpu = |program: &[u8]| {
+        let mut rom = vec![0u8; 0x8000];
+        rom[0x100..0x100 + program.len()].copy_from_slice(program);
+        let cartridge = Cartridge::from_cart(Box::new(RomOnly::new(rom.clone())), checksum(&rom));
+        headless_cartridge(cartridge)
+    };
+    assert_eq!(run_mooneye(cpu(&program)), Outcome::Passed);
+
+    let mut failing = program;
+    failing[1] = 0x42;
+    assert_eq!(run_mooneye(cpu(&failing)), Outcome::Failed);
+    assert_eq!(run_mooneye(cpu(&[0x18, 0xFE])), Outcome::TimedOut);
+}
@@ -0,0 +1,30 @@
+mod common;
+
+use common::booting;
+use common::screenshot::{assert_screenshot, run_frames};
+use oxide_boy::dmg::cartridge::cartridge::{checksum, Cartridge};
+use oxide_boy::dmg::cartridge::rom_only::RomOnly;
+use std::path::Path;
+
+// The references are kept here, create or update them with OXIDE_BOY_BLESS=1
+const SCREENSHOTS: &str = "tests/screenshots";
+
+// The boot rom has scrolled the logo into place and handed over to the cartridge by then
+const BOOT_FRAMES: u64 = 400;
+
+/// The logo the boot rom leaves on screen for a cartridge that does nothing but loop
+#[test]
+fn boot_logo() {
+    let boot_rom = std::fs::read("src/dmg/rom/DMG_ROM.bin").unwrap();
+    let mut rom = vec![0u8; 0x8000];
+    // JR -2 once the boot rom hands over
+    rom[0x100..0x102].copy_from_slice(&[0x18, 0xFE]);
+    // The boot rom keeps its own copy of the logo to check the cartridge's against
+    rom[0x104..0x134].copy_from_slice(&boot_rom[0xA8..0xD8]);
+    // The header checksum of an empty header, the boot rom locks up if it's wrong
+    rom[0x14D] = 0xE7;
+    let cartridge = Cartridge::from_cart(Box::new(RomOnly::new(rom.clone())), checksum(&rom));
+    let mut cpu = booting(cartridge);
+    let shades = run_frames(&mut cpu, BOOT_FRAMES);
+    assert_screenshot(&Path::new(SCREENSHOTS).join("boot_logo.png"), &shades);
+}
@@ -0,0 +1,237 @@
+mod common;
+
+use common::test_roms;
+use oxide_boy::dmg::busconnection::BusConnection;
+use oxide_boy::dmg::flat_bus::FlatBus;
+use oxide_boy::dmg::registers::FlagsRegister;
+use oxide_boy::{Instruction, CPU};
+use serde_json::Value;
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+
+// Each opcode fails with its first few mismatches rather than all of its 1000 cases
+const REPORTED_CASES: usize = 3;
+
+/// The tests are in `OXIDE_BOY_SINGLE_STEP_TESTS`, or otherwise sm83/v1 of the test roms,
+/// laid out like https://github.com/SingleStepTests/sm83 with a file per opcode, e.g. `cb 4e.json`
+fn single_step_tests() -> PathBuf {
+    match std::env::var_os("OXIDE_BOY_SINGLE_STEP_TESTS") {
+        Some(directory) => PathBuf::from(directory),
+        None => test_roms().join("sm83").join("v1"),
+    }
+}
+
+fn number(state: &Value, name: &str) -> u16 {
+    state[name]
+        .as_u64()
+        .unwrap_or_else(|| panic!("The state has no {}", name)) as u16
+}
+
+fn ram(state: &Value) -> Vec<(u16, u8)> {
+    let ram = state["ram"].as_array().expect("The state has no ram");
+    ram.iter()
+        .map(|entry| {
+            let entry = entry.as_array().unwrap();
+            (
+                entry[0].as_u64().unwrap() as u16,
+                entry[1].as_u64().unwrap() as u8,
+            )
+        })
+        .collect()
+}
+
+fn load(cpu: &mut CPU<FlatBus>, state: &Value) {
+    let r = &mut cpu.registers;
+    r.a = number(state, "a") as u8;
+    r.b = number(state, "b") as u8;
+    r.c = number(state, "c") as u8;
+    r.d = number(state, "d") as u8;
+    r.e = number(state, "e") as u8;
+    r.f = FlagsRegister::from(number(state, "f") as u8);
+    r.h = number(state, "h") as u8;
+    r.l = number(state, "l") as u8;
+    cpu.pc = number(state, "pc");
+    cpu.sp = number(state, "sp");
+    cpu.ime = number(state, "ime") != 0;
+    if state.get("ie").is_some() {
+        cpu.bus.write_byte(0xFFFF, number(state, "ie") as u8);
+    }
+    for (address, value) in ram(state) {
+        cpu.bus.write_byte(address, value);
+    }
+}
+
+/// The differences between the cpu and the state it should be in, e.g. `a: 12 != 34`
+fn compare(cpu: &CPU<FlatBus>, state: &Value) -> Vec<String> {
+    let r = &cpu.registers;
+    let mut actual = vec![
+        ("a", r.a as u16),
+        ("b", r.b as u16),
+        ("c", r.c as u16),
+        ("d", r.d as u16),
+        ("e", r.e as u16),
+        ("f", u8::from(&r.f) as u16),
+        ("h", r.h as u16),
+        ("l", r.l as u16),
+        ("pc", cpu.pc),
+        ("sp", cpu.sp),
+        ("ime", cpu.ime as u16),
+    ];
+    if state.get("ie").is_some() {
+        actual.push(("ie", cpu.bus.read_byte(0xFFFF) as u16));
+    }
+    let mut differences: Vec<String> = actual
+        .into_iter()
+        .filter(|(name, value)| *value != number(state, name))
+        .map(|(name, value)| format!("{}: {:02X} != {:02X}", name, value, number(state, name)))
+        .collect();
+    for (address, value) in ram(state) {
+        let actual = cpu.bus.read_byte(address);
+        if actual != value {
+            differences.push(format!(
+                "[{:04X}]: {:02X} != {:02X}",
+                address, actual, value
+            ));
+        }
+    }
+    differences
+}
+
+/// Runs one instruction from the initial state and checks it ends in the final one,
+/// having taken as many M-cycles as the case lists bus activity for
+fn run_case(case: &Value) -> Result<(), String> {
+    let mut cpu = CPU::with_bus(FlatBus::default());
+    load(&mut cpu, &case["initial"]);
+    let stepped = panic::catch_unwind(AssertUnwindSafe(|| {
+        cpu.step();
+    }));
+    if stepped.is_err() {
+        return Err("panicked".to_string());
+    }
+    let mut differences = compare(&cpu, &case["final"]);
+    let cycles = case["cycles"].as_array().map_or(0, |cycles| cycles.len()) as u64;
+    if cpu.bus.ticks() != cycles {
+        differences.push(format!("M-cycles: {} != {}", cpu.bus.ticks(), cycles));
+    }
+    if differences.is_empty() {
+        Ok(())
+    } else {
+        Err(differences.join(", "))
+    }
+}
+
+/// The cases of an opcode that fail, with what was wrong
+fn run_file(file: &Path) -> Result<Vec<String>, String> {
+    let text =
+        fs::read_to_string(file).map_err(|e| format!("Error reading {}: {}", file.display(), e))?;
+    let cases: Vec<Value> =
+        serde_json::from_str(&text).map_err(|e| format!("{}: {}", file.display(), e))?;
+    let failures = cases
+        .iter()
+        .filter_map(|case| {
+            let name = case["name"].as_str().unwrap_or("?");
+            run_case(case).err().map(|err| format!("{}: {}", name, err))
+        })
+        .collect();
+    Ok(failures)
+}
+
+/// The file names of the opcodes the suite has cases for, e.g. `4e` and `cb 4e`
+fn defined_opcodes() -> Vec<String> {
+    // STOP and HALT depend on more than the cpu, so the suite may leave them out
+    let unprefixed = (0..=0xFFu8)
+        .filter(|opcode| !matches!(opcode, 0x10 | 0x76 | 0xCB))
+        .filter(|opcode| Instruction::from_byte(*opcode, false).is_some())
+        .map(|opcode| format!("{:02x}", opcode));
+    let prefixed = (0..=0xFFu8).map(|opcode| format!("cb {:02x}", opcode));
+    unprefixed.chain(prefixed).collect()
+}
+
+// A partly copied suite would otherwise pass with the opcodes it's missing untested
+fn missing_opcodes(files: &[PathBuf]) -> Vec<String> {
+    let names: Vec<String> = files
+        .iter()
+        .map(|file| file.file_stem().unwrap().to_string_lossy().to_lowercase())
+        .collect();
+    defined_opcodes()
+        .into_iter()
+        .filter(|opcode| !names.contains(opcode))
+        .collect()
+}
+
+/// Checks the cpu ends each case of every opcode, including the CB prefixed ones,
+/// with the registers and memory the test expects
+#[test]
+fn single_step_suite() {
+    let directory = single_step_tests();
+    let mut files: Vec<PathBuf> = match fs::read_dir(&directory) {
+        Ok(entries) => entries
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|e| e == "json"))
+            .collect(),
+        Err(_) => vec![],
+    };
+    if files.is_empty() {
+        eprintln!("Skipping, there are no tests in {}", directory.display());
+        return;
+    }
+    files.sort();
+
+    let mut failing = vec![];
+    let missing = missing_opcodes(&files);
+    if !missing.is_empty() {
+        failing.push(format!("No tests for {}", missing.join(", ")));
+    }
+    for file in &files {
+        let opcode = file.file_stem().unwrap().to_string_lossy().to_string();
+        match run_file(file) {
+            Ok(failures) if failures.is_empty() => {}
+            Ok(failures) => {
+                failing.push(format!("{}: {} cases failed", opcode, failures.len()));
+                for failure in failures.iter().take(REPORTED_CASES) {
+                    failing.push(format!("  {}", failure));
+                }
+            }
+            Err(err) => failing.push(err),
+        }
+    }
+    eprintln!("Ran {} opcodes", files.len());
+    assert!(failing.is_empty(), "{}", failing.join("\n"));
+}
+
+// Cases in the suite's format, to check the runner without it
+#[test]
+fn runs_a_case() {
+    let case = |final_b: u8| -> Value {
+        let state = |pc: u16, b: u8, c: u8| {
+            serde_json::json!({
+                "pc": pc, "sp": 0xFFFE, "a": 0, "b": b, "c": c, "d": 0, "e": 0,
+                "f": 0xB0, "h": 0, "l": 0, "ime": 0, "ie": 0,
+                // LD B, C
+                "ram": [[0xC000, 0x41]]
+            })
+        };
+        serde_json::json!({
+            "name": "41 0000",
+            "initial": state(0xC000, 0x12, 0x34),
+            "final": state(0xC001, final_b, 0x34),
+            "cycles": [[0xC000, 0x41, "r-m"]]
+        })
+    };
+    assert_eq!(run_case(&case(0x34)), Ok(()));
+    assert_eq!(run_case(&case(0x12)), Err("b: 34 != 12".to_string()));
+}
+
+#[test]
+fn reports_missing_opcodes() {
+    let mut files: Vec<PathBuf> = defined_opcodes()
+        .iter()
+        .map(|opcode| PathBuf::from(format!("{}.json", opcode)))
+        .collect();
+    assert_eq!(defined_opcodes().len(), 242 + 256);
+    assert!(missing_opcodes(&files).is_empty());
+    files.retain(|file| file != Path::new("cb 4e.json") && file != Path::new("e8.json"));
+    assert_eq!(missing_opcodes(&files), ["e8", "cb 4e"]);
+}